@@ -1,49 +1,24 @@
-use criterion::{
-    black_box, criterion_group, criterion_main, AxisScale, Criterion, PlotConfiguration,
-};
+use criterion::{criterion_group, criterion_main, Criterion};
 use oak::dataset::{OakIndexOptions, SimilaritySearchable, TopKSearchResultBatch};
-use oak::fvecs::{FlattenedVecs, FvecsDataset};
+use oak::fvecs::{AttributeCsv, FlattenedVecs, FvecsDataset};
 use oak::predicate::PredicateQuery;
 use oak::stubs::generate_random_vector;
-use std::sync::Once;
+use std::sync::OnceLock;
 
-use std::fs::File;
-use std::io::Write;
+/// The search-time `efsearch` that every benchmark is run with.
+const EFSEARCH: i64 = 16;
 
-static INIT: Once = Once::new();
-static mut DATASET_GAMMA_1: Option<FvecsDataset> = None;
-static mut DATASET_GAMMA_Y: Option<FvecsDataset> = None;
+static DATASET_GAMMA_1: OnceLock<FvecsDataset> = OnceLock::new();
 
 fn load_dataset_gamma_1() -> &'static FvecsDataset {
     // Only create the index once when benchmarking.
-    INIT.call_once(|| {
-        let mut dataset = FvecsDataset::new("data/sift_base".to_string(), true).unwrap();
+    DATASET_GAMMA_1.get_or_init(|| {
+        let mut dataset =
+            FvecsDataset::new("data/sift_base".to_string(), AttributeCsv::NoHeader).unwrap();
         let options = OakIndexOptions::default();
         let _ = dataset.initialize(&options);
-        unsafe {
-            DATASET_GAMMA_1 = Some(dataset);
-        }
-    });
-    let ds = unsafe { DATASET_GAMMA_1.as_ref().unwrap() };
-    ds
-}
-
-fn load_dataset_gamma_y() -> &'static FvecsDataset {
-    // Only create the index once when benchmarking.
-    INIT.call_once(|| {
-        let mut dataset = FvecsDataset::new("data/sift_base".to_string(), true).unwrap();
-        let options = OakIndexOptions {
-            gamma: 12,
-            m: 32,
-            m_beta: 64,
-        };
-        let _ = dataset.initialize(&options);
-        unsafe {
-            DATASET_GAMMA_1 = Some(dataset);
-        }
-    });
-    let ds = unsafe { DATASET_GAMMA_1.as_ref().unwrap() };
-    ds
+        dataset
+    })
 }
 
 fn run_query(
@@ -51,13 +26,13 @@ fn run_query(
     query: &FlattenedVecs,
     predicate: &Option<PredicateQuery>,
 ) -> TopKSearchResultBatch {
-    ds.search(query, predicate, 1).unwrap()
+    ds.search(query, predicate, 1, EFSEARCH).unwrap()
 }
 
 fn single_query_no_predicate_gamma_1(c: &mut Criterion) {
     let dataset = load_dataset_gamma_1();
 
-    let queries = FvecsDataset::new("data/sift_query".to_string(), false).unwrap();
+    let queries = FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::Skip).unwrap();
     // let predicates = Vec::<PredicateQuery>::from(&queries);
     // NOTE: passing all 10000 queries at the same time with a non-None query throughs a segfault,
     // presumably as the bitmask generation is too memory intensive.
@@ -69,7 +44,7 @@ fn single_query_no_predicate_gamma_1(c: &mut Criterion) {
     // debug!("Predicate is: {first_predicate}");
 
     c.bench_function("single_query_no_predicate_gamma_1", |b| {
-        b.iter(|| run_query(&dataset, &queries, &first_predicate))
+        b.iter(|| run_query(dataset, &queries, &first_predicate))
     });
 }
 
@@ -86,7 +61,7 @@ fn single_query_equals_predicate_gamma_1(c: &mut Criterion) {
     };
 
     c.bench_function("single_query_equals_predicate", |b| {
-        b.iter(|| run_query(&dataset, &query, &predicate))
+        b.iter(|| run_query(dataset, &query, &predicate))
     });
 }
 
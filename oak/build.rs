@@ -1,6 +1,12 @@
 use std::env;
 
 fn main() {
+    // The vendored FAISS/ACORN tree is only needed by the `hnsw_faiss` backend; `hnsw_rust` builds
+    // without a C++ toolchain or BLAS.
+    if env::var_os("CARGO_FEATURE_HNSW_FAISS").is_none() {
+        return;
+    }
+
     println!("cargo:rustc-link-lib=dylib=gomp");
     println!("cargo:rustc-link-lib=openblas");
 
@@ -78,6 +84,7 @@ fn main() {
         "third_party/ACORN/faiss/invlists/DirectMap.cpp",
         "third_party/ACORN/faiss/invlists/InvertedLists.cpp",
         "third_party/ACORN/faiss/invlists/InvertedListsIOHook.cpp",
+        "third_party/ACORN/faiss/invlists/OnDiskInvertedLists.cpp",
         "third_party/ACORN/faiss/utils/Heap.cpp",
         "third_party/ACORN/faiss/utils/WorkerThread.cpp",
         "third_party/ACORN/faiss/utils/distances.cpp",
@@ -1,75 +1,226 @@
+use crate::bitmask::Bitmask;
 use crate::dataset::{
-    ConstructionError, OakIndexOptions, SearchableError, SimilaritySearchable, TopKSearchResult,
+    check_batch_size, check_index_input, check_query_dimensionality, ConstructionError,
+    HybridSearchMetadata, MemoryUsage, OakIndexOptions, SearchableError, TopKSearchResult,
 };
 use crate::ffi;
 use crate::fvecs::FlattenedVecs;
 
-use core::ffi::c_char;
 use slog_scope::debug;
+use std::ffi::c_char;
+use std::path::Path;
 
+/// The largest filter map, in bytes, that is passed to ACORN in one search. Larger batches of
+/// queries are split across several searches.
+const MAX_FILTER_MAP_BYTES: usize = 1 << 28;
+
+// SAFETY: an index is only ever reached through the `UniquePtr` owned by its `AcornHnswIndex`, and
+// FAISS does not tie an index to the thread that created it, so it can be moved between threads.
+unsafe impl Send for ffi::IndexACORNFlat {}
+
+// SAFETY: the only functions that take a shared reference to an index are `search_index` and those
+// that read its size or write it to disk, none of which modify it. In particular, `efsearch` is
+// passed with each search rather than set on the index, and the filter map is only read. Indexes
+// are only modified while they are built, through a unique reference.
+unsafe impl Sync for ffi::IndexACORNFlat {}
+
+/// An ACORN index, built with FAISS. It can be searched from several threads at once.
 #[allow(dead_code)]
 pub struct AcornHnswIndex {
     index: cxx::UniquePtr<ffi::IndexACORNFlat>,
     count: usize,
+    dimensionality: usize,
+    /// The graph is not modified once the index is built, so its size is read once up front.
+    graph_bytes: usize,
 }
 
-#[cfg(feature = "hnsw_faiss")]
 impl AcornHnswIndex {
-    pub fn new<D: SimilaritySearchable>(
-        dataset: &D,
+    /// Builds an index over the vectors in `flattened`, whose attributes are in `metadata`.
+    pub fn new(
         flattened: &FlattenedVecs,
+        metadata: &HybridSearchMetadata,
         options: &OakIndexOptions,
     ) -> Result<Self, ConstructionError> {
-        let dimensionality = i32::try_from(dataset.get_dimensionality())
+        check_index_input(flattened, metadata)?;
+        let dimensionality = i32::try_from(flattened.dimensionality)
             .expect("dimensionality should not be greater than 2,147,483,647");
 
-        let metadata = dataset.get_metadata();
         let mut index = ffi::new_index_acorn(
             dimensionality,
             options.m,
             options.gamma,
             options.m_beta,
-            metadata.as_ref(),
-        );
+            &metadata.acorn_attrs()?,
+        )?;
         debug!(
             "Constructed index with dimensionality: {dimensionality}, m: {}, gamma: {}, m_beta: {}",
             options.m, options.gamma, options.m_beta
         );
 
         // NOTE: this brings the data into memory.
-        let num_fvecs = dataset.len();
+        let num_fvecs = flattened.len();
         debug!("Adding {num_fvecs} vectors to the index...");
 
-        // SAFETY: this is unsafe because we pass a raw ptr to the fvecs data; but we are SURE that
-        // we have constructed it appropriately.
-        // TODO: is there a way to 'catch' segmentation faults in these unsafe functions?
+        // SAFETY: `flattened.data` holds `num_fvecs` vectors of the dimensionality that the index
+        // was constructed with, and ACORN only reads them while they are added.
         unsafe {
-            ffi::add_to_index(&mut index, num_fvecs as i64, flattened.data.as_ptr());
+            ffi::add_to_index(&mut index, num_fvecs as i64, flattened.data.as_ptr())?;
         }
         debug!("Added {num_fvecs} vectors to the index.");
 
+        Self::from_ffi(index)
+    }
+
+    /// Wraps an index that has been built or read over FFI, reading the sizes that are cached.
+    fn from_ffi(index: cxx::UniquePtr<ffi::IndexACORNFlat>) -> Result<Self, ConstructionError> {
         Ok(Self {
+            count: ffi::index_count(&index)? as usize,
+            dimensionality: ffi::index_dimensionality(&index)? as usize,
+            graph_bytes: ffi::index_graph_bytes(&index)?,
             index,
-            count: num_fvecs as usize,
         })
     }
 
+    /// Writes the index to `path`, overwriting any file that is already there.
+    pub fn save_index(&self, path: &Path) -> Result<(), ConstructionError> {
+        let fname = path_to_str(path)?;
+        ffi::write_index_to_file(&self.index, fname).map_err(|e| {
+            ConstructionError::IndexFileError {
+                path: path.to_path_buf(),
+                reason: e.to_string(),
+            }
+        })?;
+        debug!("Wrote index with {} vectors to {fname}.", self.count);
+        Ok(())
+    }
+
+    /// Reads an index that was previously written with `save_index`. FAISS does not persist the
+    /// metadata that ACORN is constructed with, so the loaded index can be searched but not added
+    /// to.
+    pub fn load_index(path: &Path) -> Result<Self, ConstructionError> {
+        let fname = path_to_str(path)?;
+        let index =
+            ffi::read_index_from_file(fname).map_err(|e| ConstructionError::IndexFileError {
+                path: path.to_path_buf(),
+                reason: e.to_string(),
+            })?;
+        let index = Self::from_ffi(index)?;
+        debug!("Read index with {} vectors from {fname}.", index.count);
+
+        Ok(index)
+    }
+
+    /// The number of vectors that have been added to the index.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// The dimensionality of the vectors in the index.
+    pub fn get_dimensionality(&self) -> usize {
+        self.dimensionality
+    }
+
+    /// The memory used by the index, which keeps its own copy of the vectors alongside its graph.
+    /// The single integer attribute per vector that ACORN keeps is small by comparison, so it is
+    /// not counted.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            vectors: self.count * self.dimensionality * std::mem::size_of::<f32>(),
+            graph: self.graph_bytes,
+            metadata: 0,
+        }
+    }
+
+    /// `mask` must have one bit for each vector in the index, and is applied to every query. If
+    /// there is no mask, every vector passes the filter.
     pub fn search(
         &self,
         query_vectors: &FlattenedVecs,
-        filter_id_map: &mut Vec<c_char>,
+        mask: Option<&Bitmask>,
+        k: usize,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        self.search_batch(query_vectors, &vec![mask; query_vectors.len()], k, efsearch)
+    }
+
+    /// Searches a batch of queries, each filtered by its own mask in `masks`, which must have one
+    /// entry for each query vector. Each mask must have one bit for each vector in the index; a
+    /// query without a mask is not filtered.
+    pub fn search_batch(
+        &self,
+        query_vectors: &FlattenedVecs,
+        masks: &[Option<&Bitmask>],
         k: usize,
         efsearch: i64,
     ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        check_query_dimensionality(query_vectors, self.get_dimensionality())?;
         let number_of_query_vectors: usize = query_vectors.len();
         debug!("Searching queries: {number_of_query_vectors} in batch.");
-        let length_of_results = k * number_of_query_vectors;
+        check_batch_size(query_vectors, masks)?;
+
+        // ACORN would read past the end of a filter map that is too short.
+        if let Some(mask) = masks
+            .iter()
+            .flatten()
+            .find(|mask| mask.capacity() != self.len())
+        {
+            return Err(SearchableError::MaskSizeMismatch {
+                vectors: self.len(),
+                mask: mask.capacity(),
+            });
+        }
+
+        // ACORN reads a separate filter map of N values for each query, so the batch is split so
+        // that the filter map for each search stays within a bounded size.
+        let queries_per_search = (MAX_FILTER_MAP_BYTES / self.len().max(1)).max(1);
+        let mut results = Vec::with_capacity(number_of_query_vectors);
+        for (queries, masks) in query_vectors
+            .data
+            .chunks(queries_per_search * query_vectors.dimensionality)
+            .zip(masks.chunks(queries_per_search))
+        {
+            results.extend(self.search_filtered(queries, masks, k, efsearch)?);
+        }
+
+        debug!("Search complete");
+        Ok(results)
+    }
+
+    /// Runs a single search over ACORN, where `queries` holds one query vector for each mask.
+    fn search_filtered(
+        &self,
+        queries: &[f32],
+        masks: &[Option<&Bitmask>],
+        k: usize,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        let number_of_query_vectors = masks.len();
+        let length_of_results =
+            k.checked_mul(number_of_query_vectors)
+                .ok_or(SearchableError::TooManyResults {
+                    k,
+                    queries: number_of_query_vectors,
+                })?;
         debug!("Length of results arrays: {length_of_results}.");
 
         // These two arrays are where the outputs from the cpp methods will be stored
         let mut distances: Vec<f32> = vec![0 as f32; length_of_results];
         let mut labels: Vec<i64> = vec![0; length_of_results];
 
+        // The masks are expanded into one row of the filter map per query, stacked in the order of
+        // the queries. ACORN always filters, so no mask means that every vector passes.
+        let mut filter_id_map: Vec<c_char> =
+            Vec::with_capacity(number_of_query_vectors * self.len());
+        for mask in masks {
+            match mask {
+                Some(mask) => filter_id_map.extend(mask.to_filter_map()),
+                None => filter_id_map.resize(filter_id_map.len() + self.len(), 1),
+            }
+        }
         let filter_id_map_length = filter_id_map.len();
         debug!("Length of bitmap representing predicate: {filter_id_map_length}.");
 
@@ -77,22 +228,194 @@ impl AcornHnswIndex {
             ffi::search_index(
                 &self.index,
                 number_of_query_vectors as i64,
-                query_vectors.data.as_ptr(),
+                queries.as_ptr(),
                 k as i64,
                 distances.as_mut_ptr(),
                 labels.as_mut_ptr(),
-                filter_id_map.as_mut_ptr(),
+                filter_id_map.as_ptr(),
                 efsearch,
             )?
         }
 
-        debug!("Search complete");
+        // ACORN pads the results for a query with a label of -1 when fewer than `k` vectors pass
+        // its filter, so those are dropped rather than returned as ids.
+        Ok(labels
+            .chunks(k)
+            .zip(distances.chunks(k))
+            .map(|(labels, distances)| {
+                labels
+                    .iter()
+                    .zip(distances)
+                    .filter(|(&label, _)| label >= 0)
+                    .map(|(&label, &distance)| (label as usize, distance))
+                    .collect()
+            })
+            .collect())
+    }
+}
+
+fn path_to_str(path: &Path) -> Result<&str, ConstructionError> {
+    path.to_str()
+        .ok_or_else(|| ConstructionError::IndexFileError {
+            path: path.to_path_buf(),
+            reason: "path is not valid UTF-8".to_string(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::SimilaritySearchable;
+    use crate::fvecs::{AttributeCsv, FvecsDataset};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_cpp_errors_are_returned() {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::Skip).unwrap();
+        let index = AcornHnswIndex::new(
+            &dataset.vectors().to_flattened(),
+            &dataset.metadata,
+            &OakIndexOptions::default(),
+        )
+        .unwrap();
+        let dimensionality = dataset.get_dimensionality();
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.vectors().get(0).to_vec(),
+        };
+
+        // FAISS rejects searches for no neighbours with an exception.
+        let result = index.search(&query_vector, None, 0, 16);
+        assert!(matches!(result, Err(SearchableError::CppError(_))));
+
+        // Indexes read from disk have no attributes for ACORN to read, so cannot be added to.
+        let path = std::env::temp_dir().join("oak_test_cpp_errors.index");
+        index.save_index(&path).unwrap();
+        let mut loaded = AcornHnswIndex::load_index(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // SAFETY: the query vector has the dimensionality of the index.
+        let result = unsafe { ffi::add_to_index(&mut loaded.index, 1, query_vector.data.as_ptr()) };
+        assert!(result.is_err());
+        assert_eq!(loaded.len(), dataset.len());
+
+        let result = AcornHnswIndex::new(
+            &dataset.vectors().to_flattened(),
+            &dataset.metadata,
+            &OakIndexOptions {
+                m: 0,
+                ..Default::default()
+            },
+        );
+        assert!(matches!(result, Err(ConstructionError::CppError(_))));
+    }
 
-        let combined: Vec<(usize, f32)> = labels
+    #[test]
+    fn test_search_batch_rejects_mismatched_input() {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::Skip).unwrap();
+        let index = AcornHnswIndex::new(
+            &dataset.vectors().to_flattened(),
+            &dataset.metadata,
+            &OakIndexOptions::default(),
+        )
+        .unwrap();
+        let dimensionality = dataset.get_dimensionality();
+
+        let query_vectors = FlattenedVecs {
+            dimensionality: dimensionality - 1,
+            data: dataset.vectors().get(0)[1..].to_vec(),
+        };
+        assert_eq!(
+            index.search_batch(&query_vectors, &[None], 10, 16),
+            Err(SearchableError::DimensionalityMismatch {
+                expected: dimensionality,
+                found: dimensionality - 1
+            })
+        );
+
+        // The second query is cut short.
+        let query_vectors = FlattenedVecs {
+            dimensionality,
+            data: dataset.vectors().to_flattened_range(0..2).data[..dimensionality + 3].to_vec(),
+        };
+        assert_eq!(
+            index.search_batch(&query_vectors, &[None], 10, 16),
+            Err(SearchableError::DimensionalityMismatch {
+                expected: dimensionality,
+                found: 3
+            })
+        );
+
+        // Each mask must have a bit for every vector in the index.
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.vectors().get(0).to_vec(),
+        };
+        let mask = Bitmask::full(dataset.len() - 1);
+        assert_eq!(
+            index.search_batch(&query_vector, &[Some(&mask)], 10, 16),
+            Err(SearchableError::MaskSizeMismatch {
+                vectors: dataset.len(),
+                mask: dataset.len() - 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_concurrent_search() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<AcornHnswIndex>();
+
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::Skip).unwrap();
+        let index = Arc::new(
+            AcornHnswIndex::new(
+                &dataset.vectors().to_flattened(),
+                &dataset.metadata,
+                &OakIndexOptions::default(),
+            )
+            .unwrap(),
+        );
+        let dimensionality = dataset.get_dimensionality();
+        let query_vectors = Arc::new(FlattenedVecs {
+            dimensionality,
+            data: dataset.vectors().to_flattened_range(0..8).data,
+        });
+        let mask = Arc::new((0..dataset.len()).map(|i| i % 3 != 0).collect::<Bitmask>());
+
+        // Searches with different values of efsearch must not affect each other, so running them
+        // concurrently gives the same results as running them one after another.
+        let efsearches = [1, 4, 16, 64];
+        let expected: Vec<_> = efsearches
+            .iter()
+            .map(|&efsearch| {
+                index
+                    .search(&query_vectors, Some(&mask), 10, efsearch)
+                    .unwrap()
+            })
+            .collect();
+
+        let searchers: Vec<_> = efsearches
             .into_iter()
-            .map(|i| i as usize)
-            .zip(distances)
+            .map(|efsearch| {
+                let index = Arc::clone(&index);
+                let query_vectors = Arc::clone(&query_vectors);
+                let mask = Arc::clone(&mask);
+                thread::spawn(move || {
+                    (0..50)
+                        .map(|_| {
+                            index
+                                .search(&query_vectors, Some(&mask), 10, efsearch)
+                                .unwrap()
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
             .collect();
-        Ok(combined.chunks(k).map(|chunk| chunk.to_vec()).collect())
+
+        for (searcher, expected) in searchers.into_iter().zip(expected) {
+            for result in searcher.join().unwrap() {
+                assert_eq!(result, expected);
+            }
+        }
     }
 }
@@ -1,14 +1,16 @@
 use anyhow::Result;
 use clap::Parser;
-use csv::{ReaderBuilder, Writer};
+use csv::Writer;
 use dropshot::ConfigLogging;
 use dropshot::ConfigLoggingLevel;
 use oak::bitmask::Bitmask;
-use oak::dataset::{OakIndexOptions, SimilaritySearchable, TopKSearchResultBatch};
-use oak::fvecs::{FlattenedVecs, FvecsDataset};
+use oak::dataset::{OakIndexOptions, QueryFilter, SimilaritySearchable, TopKSearchResultBatch};
+use oak::fvecs::{read_ivecs, AttributeCsv, FlattenedVecs, FvecsDataset};
 use oak::predicate::PredicateQuery;
-use oak::router::Router;
+use oak::router::{Router, RoutingPolicyKind};
 use slog_scope::info;
+use std::path::Path;
+use std::sync::Arc;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -26,8 +28,31 @@ struct Args {
     dataset: String,
     #[arg(short, long, required(true))]
     query: String,
+    /// The .ivecs file holding the ids of the nearest neighbours of each query, of which only the
+    /// first is used.
     #[arg(short, long, required(true))]
     groundtruth: String,
+    /// If set, the index is loaded from this file when it exists, and otherwise built and saved
+    /// to it.
+    #[arg(short, long)]
+    index: Option<String>,
+    /// The predicate that queries are filtered with, e.g. `attr0 = 5 AND attr1 < 10`.
+    #[arg(short, long, default_value = "attr0 = 1")]
+    predicate: PredicateQuery,
+    /// How queries are routed between the base index and the partition: one of `heuristic`,
+    /// `always-base`, `best-coverage` or `cost-based`, optionally followed by a colon and the
+    /// policy's threshold, e.g. `heuristic:20`.
+    #[arg(short, long, default_value = "heuristic")]
+    routing_policy: RoutingPolicyKind,
+    /// If set, all queries are searched in a single batch, each filtered by the predicate on the
+    /// first attribute of its own metadata, which is read from the CSV alongside the query file.
+    /// The groundtruth must then be for each query's own predicate.
+    #[arg(short, long)]
+    batch: bool,
+    /// If set, the first row of each CSV of attributes is a header naming the columns. Otherwise,
+    /// every row holds the attributes of a vector, and the columns are named `attr0`, `attr1`, ...
+    #[arg(long)]
+    csv_header: bool,
 }
 
 struct QueryStats {
@@ -48,37 +73,77 @@ struct ExperimentResults {
 fn query_loop(
     dataset: &FvecsDataset,
     router: &Router,
-    queries: &Vec<FlattenedVecs>,
+    queries: &[FlattenedVecs],
     bitmask: &Bitmask,
     k: usize,
-    gt: &Vec<usize>,
+    gt: &[Option<usize>],
     efsearch: i64,
 ) -> Result<Vec<QueryStats>> {
     let mut results = vec![];
     info! {"We have {} queries and {} gt elts", queries.len(), gt.len()};
     for (i, q) in queries.iter().enumerate() {
         let now = tokio::time::Instant::now();
-        let result = dataset.search_with_bitmask(&q, &bitmask, k, efsearch)?;
+        let result = dataset.search_with_bitmask(q, bitmask, k, efsearch)?;
         let end = now.elapsed();
         let acorn_latency = end.as_micros();
         let acorn_recall = calculate_recall_1(gt[i], result)?;
 
         info!("Now searching oak");
         let oak_now = tokio::time::Instant::now();
-        let oak_result = router.search_with_bitmask(&q, &bitmask, k, efsearch)?;
+        let oak_result = router.search_with_bitmask(q, bitmask, k, efsearch)?;
         let oak_end = oak_now.elapsed();
         let oak_latency = oak_end.as_micros();
         let oak_recall = calculate_recall_1(gt[i], oak_result);
         results.push(QueryStats {
-            acorn_latency: acorn_latency,
+            acorn_latency,
             acorn_recall_10: acorn_recall,
-            oak_latency: oak_latency,
+            oak_latency,
             oak_recall_10: oak_recall?,
         });
     }
     Ok(results)
 }
 
+/// Searches all `queries` in one batch, each with its own filter, against both the base dataset
+/// and the router, timing each batch as a whole.
+fn batch_loop(
+    dataset: &FvecsDataset,
+    router: &Router,
+    queries: &FlattenedVecs,
+    filters: &[QueryFilter],
+    k: usize,
+    gt: &[Option<usize>],
+    efsearch: i64,
+) -> Result<ExperimentResults> {
+    let count = queries.len() as f64;
+    let recall = |results: Vec<_>| -> Result<f64> {
+        let mut hits = 0;
+        for (i, result) in results.into_iter().enumerate() {
+            if calculate_recall_1(gt[i], vec![result])? {
+                hits += 1;
+            }
+        }
+        Ok(hits as f64 / count)
+    };
+
+    let now = tokio::time::Instant::now();
+    let acorn_results = dataset.search_batch(queries, filters, k, efsearch)?;
+    let acorn_latency = now.elapsed().as_micros() as f64;
+
+    let now = tokio::time::Instant::now();
+    let oak_results = router.search_batch(queries, filters, k, efsearch)?;
+    let oak_latency = now.elapsed().as_micros() as f64;
+
+    Ok(ExperimentResults {
+        acorn_latency,
+        oak_latency,
+        acorn_qps: acorn_latency / count,
+        oak_qps: oak_latency / count,
+        acorn_recall: recall(acorn_results)?,
+        oak_recall: recall(oak_results)?,
+    })
+}
+
 fn averages(queries: Vec<QueryStats>) -> Result<ExperimentResults> {
     let acorn_latencies: f64 = queries.iter().map(|qs| qs.acorn_latency).sum::<u128>() as f64;
     let acorn_r10s: f64 = queries.iter().filter(|qs| qs.acorn_recall_10).count() as f64;
@@ -95,7 +160,12 @@ fn averages(queries: Vec<QueryStats>) -> Result<ExperimentResults> {
     })
 }
 
-fn calculate_recall_1(gt: usize, acorn_result: TopKSearchResultBatch) -> Result<bool> {
+/// Whether the nearest neighbour `gt` is among the first 10 results. A query without a nearest
+/// neighbour, as no vector matches it, is only answered correctly by returning no results.
+fn calculate_recall_1(gt: Option<usize>, acorn_result: TopKSearchResultBatch) -> Result<bool> {
+    let Some(gt) = gt else {
+        return Ok(acorn_result[0].is_empty());
+    };
     let mut n_10: bool = false;
     for (i, j) in acorn_result[0].iter().enumerate() {
         if j.0 == gt {
@@ -108,20 +178,6 @@ fn calculate_recall_1(gt: usize, acorn_result: TopKSearchResultBatch) -> Result<
     Ok(n_10)
 }
 
-fn read_csv(file_path: String) -> Result<Vec<usize>> {
-    let mut rdr = ReaderBuilder::new()
-        .has_headers(false)
-        .from_path(file_path)?;
-    let mut values = Vec::new();
-
-    for result in rdr.records() {
-        let record = result?;
-        let value: usize = record[0].parse::<usize>()?; // why is this needed?
-        values.push(value);
-    }
-    Ok(values)
-}
-
 fn main() -> Result<()> {
     let log = ConfigLogging::StderrTerminal {
         level: ConfigLoggingLevel::Debug,
@@ -133,7 +189,7 @@ fn main() -> Result<()> {
 
     let args = Args::parse();
 
-    let mut dataset = FvecsDataset::new(args.dataset, true)?;
+    let mut dataset = FvecsDataset::new(args.dataset, AttributeCsv::with_header(args.csv_header))?;
     info!("Dataset loaded from disk.");
 
     let opts = OakIndexOptions {
@@ -142,50 +198,85 @@ fn main() -> Result<()> {
         m_beta: 64,
     };
 
-    let _ = dataset.initialize(&opts);
+    match &args.index {
+        Some(path) => dataset.initialize_cached(&opts, Path::new(path))?,
+        None => dataset.initialize(&opts)?,
+    }
     info!("Seed index constructed.");
+    let dataset = Arc::new(dataset);
 
-    let query = PredicateQuery::new(1);
+    let query = args.predicate;
 
-    let mut subdataset = dataset.view(&query);
-    let _ = subdataset.initialize(&opts);
+    let mut subdataset = dataset.view(&query)?;
+    subdataset.initialize(&opts)?;
     info!("Subindex as view constructed.");
 
-    let dimensionality = dataset.get_dimensionality() as usize;
-    assert_eq!(dimensionality, subdataset.get_dimensionality() as usize);
+    let dimensionality = dataset.get_dimensionality();
+    assert_eq!(dimensionality, subdataset.get_dimensionality());
 
-    let query_set = FvecsDataset::new(args.query, false)?;
+    let query_csv = if args.batch {
+        AttributeCsv::with_header(args.csv_header)
+    } else {
+        AttributeCsv::Skip
+    };
+    let query_set = FvecsDataset::new(args.query, query_csv)?;
     let batched_queries = FlattenedVecs::from(&query_set);
     info!("Query set loaded from disk.");
 
     let topk = 10;
     let num_queries = batched_queries.len();
     info!("Total {num_queries} queries loaded");
-    let queries = batched_queries.to_vec();
+    let queries = FlattenedVecs::from(&query_set).to_vec();
     info!("Converted into {}", queries.len());
 
-    let mask_main = Bitmask::new(&query, &dataset);
+    let mask_main = Bitmask::new(&query, dataset.as_ref())?;
     let _mask_sub = Bitmask::new_full(&subdataset);
 
     info!("GT loading...");
-    // let variable_gt_path = "./outdir/sift_groundtruth.csv";
-    let gt = read_csv(args.groundtruth)?;
+    // Only the nearest neighbour of each query is needed to compute recall@10. A query that
+    // matches no vectors has its neighbours padded with -1, and so has no nearest neighbour.
+    let gt: Vec<Option<usize>> = read_ivecs(Path::new(&args.groundtruth))?
+        .iter()
+        .map(|neighbours| neighbours.first().and_then(|&id| usize::try_from(id).ok()))
+        .collect();
     info!("{} gt queries found", gt.len());
 
-    info!("Searching full dataset for {topk} similar vectors for {num_queries} random query , where attr is equal to 1...");
+    info!("Searching full dataset for {topk} similar vectors for {num_queries} random query , where {query}...");
     let efsearch = vec![1, 4, 8, 16, 32];
     // To test ACORN, we simply call search_with_bitmask which routes to the
     // base index for ACORN
 
     // To test OAK, we use the router which decides whether the query should
     // be redirected to an OI
-    let router = Router::new(&dataset, vec![(&mask_main, &subdataset)]);
+    let router = Router::new(
+        dataset.clone(),
+        vec![(mask_main.clone(), Box::new(subdataset))],
+        args.routing_policy.into_policy(),
+    );
 
     let mut results: Vec<ExperimentResults> = vec![];
 
+    let filters: Vec<QueryFilter> = Vec::<PredicateQuery>::from(&query_set)
+        .into_iter()
+        .map(QueryFilter::from)
+        .collect();
+
     for efs in efsearch {
-        let qs = query_loop(&dataset, &router, &queries, &mask_main, topk, &gt, efs)?;
-        match averages(qs) {
+        let exp_result = if args.batch {
+            Ok(batch_loop(
+                &dataset,
+                &router,
+                &batched_queries,
+                &filters,
+                topk,
+                &gt,
+                efs,
+            )?)
+        } else {
+            let qs = query_loop(&dataset, &router, &queries, &mask_main, topk, &gt, efs)?;
+            averages(qs)
+        };
+        match exp_result {
             Ok(exp_result) => {
                 info!(
                     "ACORN: QPS was {} microseconds with total latency
@@ -213,7 +304,7 @@ fn main() -> Result<()> {
     let mut wtr = Writer::from_path("experiments.csv")?;
 
     // Write the header
-    wtr.write_record(&[
+    wtr.write_record([
         "ACORN Latency",
         "ACORN QPS",
         "ACORN Recall@10",
@@ -224,7 +315,7 @@ fn main() -> Result<()> {
 
     // Write the data
     for exp in results.iter() {
-        wtr.write_record(&[
+        wtr.write_record([
             exp.acorn_latency.to_string(),
             exp.acorn_qps.to_string(),
             exp.acorn_recall.to_string(),
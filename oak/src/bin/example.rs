@@ -3,10 +3,11 @@ use clap::Parser;
 use dropshot::ConfigLogging;
 use dropshot::ConfigLoggingLevel;
 use slog_scope::info;
+use std::path::Path;
 use thiserror::Error;
 
 use oak::dataset::{OakIndexOptions, SimilaritySearchable};
-use oak::fvecs::{FlattenedVecs, FvecsDataset};
+use oak::fvecs::{AttributeCsv, FlattenedVecs, FvecsDataset};
 use oak::predicate::PredicateQuery;
 use oak::stubs::generate_random_vector;
 
@@ -23,6 +24,17 @@ pub enum ExampleError {
 struct Args {
     #[arg(short, long, required(true))]
     dataset: String,
+    /// If set, the index is loaded from this file when it exists, and otherwise built and saved
+    /// to it.
+    #[arg(short, long)]
+    index: Option<String>,
+    /// The predicate that queries are filtered with, e.g. `attr0 = 5 AND attr1 < 10`.
+    #[arg(short, long, default_value = "attr0 = 5")]
+    predicate: PredicateQuery,
+    /// If set, the first row of each CSV of attributes is a header naming the columns. Otherwise,
+    /// every row holds the attributes of a vector, and the columns are named `attr0`, `attr1`, ...
+    #[arg(long)]
+    csv_header: bool,
 }
 
 fn main() -> Result<()> {
@@ -36,7 +48,7 @@ fn main() -> Result<()> {
 
     let args = Args::parse();
 
-    let mut dataset = FvecsDataset::new(args.dataset, true)?;
+    let mut dataset = FvecsDataset::new(args.dataset, AttributeCsv::with_header(args.csv_header))?;
     info!("Dataset loaded from disk.");
 
     let opts = OakIndexOptions {
@@ -45,10 +57,13 @@ fn main() -> Result<()> {
         m_beta: 64,
     };
 
-    let _ = dataset.initialize(&opts);
+    match &args.index {
+        Some(path) => dataset.initialize_cached(&opts, Path::new(path))?,
+        None => dataset.initialize(&opts)?,
+    }
     info!("Seed index constructed.");
 
-    let dimensionality = dataset.get_dimensionality() as usize;
+    let dimensionality = dataset.get_dimensionality();
     info!("Constructing random vector to query with {dimensionality} dimensions");
     let query_vector = FlattenedVecs {
         dimensionality,
@@ -56,9 +71,12 @@ fn main() -> Result<()> {
     };
     let topk = 10;
     let num_queries = query_vector.len();
-    let query = Some(PredicateQuery::new(5));
+    let query = Some(args.predicate);
 
-    info!("Searching for {topk} similar vectors for {num_queries} random query, where attr is equal to 5...");
+    info!(
+        "Searching for {topk} similar vectors for {num_queries} random query, where {}...",
+        query.as_ref().unwrap()
+    );
 
     let result = dataset.search(&query_vector, &query, topk, 16);
 
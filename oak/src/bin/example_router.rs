@@ -4,14 +4,16 @@ use dropshot::ConfigLogging;
 use dropshot::ConfigLoggingLevel;
 use slog_scope::{debug, info};
 
+use std::path::Path;
+use std::sync::Arc;
 use std::time::Instant;
 use thiserror::Error;
 
 use oak::bitmask::Bitmask;
 use oak::dataset::{OakIndexOptions, SimilaritySearchable};
-use oak::fvecs::{FlattenedVecs, FvecsDataset};
+use oak::fvecs::{AttributeCsv, FlattenedVecs, FvecsDataset};
 use oak::predicate::PredicateQuery;
-use oak::router::Router;
+use oak::router::{Router, RoutingPolicyKind};
 use oak::stubs::generate_random_vector;
 
 #[derive(Error, Debug)]
@@ -27,6 +29,22 @@ pub enum ExampleError {
 struct Args {
     #[arg(short, long, required(true))]
     dataset: String,
+    /// If set, the index is loaded from this file when it exists, and otherwise built and saved
+    /// to it.
+    #[arg(short, long)]
+    index: Option<String>,
+    /// The predicate that queries are filtered with, e.g. `attr0 = 5 AND attr1 < 10`.
+    #[arg(short, long, default_value = "attr0 = 5")]
+    predicate: PredicateQuery,
+    /// How queries are routed between the base index and the partition: one of `heuristic`,
+    /// `always-base`, `best-coverage` or `cost-based`, optionally followed by a colon and the
+    /// policy's threshold, e.g. `heuristic:20`.
+    #[arg(short, long, default_value = "heuristic")]
+    routing_policy: RoutingPolicyKind,
+    /// If set, the first row of each CSV of attributes is a header naming the columns. Otherwise,
+    /// every row holds the attributes of a vector, and the columns are named `attr0`, `attr1`, ...
+    #[arg(long)]
+    csv_header: bool,
 }
 
 fn main() -> Result<()> {
@@ -40,7 +58,7 @@ fn main() -> Result<()> {
 
     let args = Args::parse();
 
-    let mut dataset = FvecsDataset::new(args.dataset, true)?;
+    let mut dataset = FvecsDataset::new(args.dataset, AttributeCsv::with_header(args.csv_header))?;
     info!("Dataset loaded from disk.");
 
     let opts = OakIndexOptions {
@@ -49,17 +67,21 @@ fn main() -> Result<()> {
         m_beta: 64,
     };
 
-    let _ = dataset.initialize(&opts);
+    match &args.index {
+        Some(path) => dataset.initialize_cached(&opts, Path::new(path))?,
+        None => dataset.initialize(&opts)?,
+    }
     info!("Seed index constructed.");
+    let dataset = Arc::new(dataset);
 
-    let query = PredicateQuery::new(5);
+    let query = args.predicate;
 
-    let mut subdataset = dataset.view(&query);
-    let _ = subdataset.initialize(&opts);
+    let mut subdataset = dataset.view(&query)?;
+    subdataset.initialize(&opts)?;
     info!("Subindex as view constructed.");
 
-    let dimensionality = dataset.get_dimensionality() as usize;
-    assert_eq!(dimensionality, subdataset.get_dimensionality() as usize);
+    let dimensionality = dataset.get_dimensionality();
+    assert_eq!(dimensionality, subdataset.get_dimensionality());
 
     // Experiments
     // --------=--
@@ -72,21 +94,15 @@ fn main() -> Result<()> {
     let topk = 10;
     let num_queries = query_vector.len();
 
-    let mask_main = Bitmask::new(&query, &dataset);
-    let mask_sub = Bitmask::new_full(&subdataset);
+    let mask_main = Bitmask::new(&query, dataset.as_ref())?;
 
     debug!(
         "Mask main filled: {} / {}",
         mask_main.bitcount(),
         mask_main.capacity()
     );
-    debug!(
-        "Mask sub filled: {} / {}",
-        mask_sub.bitcount(),
-        mask_sub.capacity()
-    );
 
-    info!("Searching full dataset for {topk} similar vectors for {num_queries} random query , where attr is equal to 5...");
+    info!("Searching full dataset for {topk} similar vectors for {num_queries} random query , where {query}...");
 
     let big_start = Instant::now();
     let big_result = dataset.search_with_bitmask(&query_vector, &mask_main, topk, 16);
@@ -95,7 +111,7 @@ fn main() -> Result<()> {
     info!("Searching dataset partition for {topk} similar vectors for {num_queries} random query, with no predicate as we know all vectors match...");
 
     let small_start = Instant::now();
-    let small_result = subdataset.search_with_bitmask(&query_vector, &mask_sub, topk, 16);
+    let small_result = subdataset.search_with_bitmask(&query_vector, &mask_main, topk, 16);
     let small_end = small_start.elapsed();
 
     let big_mean_distance = big_result.unwrap()[0]
@@ -118,7 +134,11 @@ fn main() -> Result<()> {
 
     // Using router
     // ----------------------------
-    let router = Router::new(&dataset, vec![(&mask_main, &subdataset)]);
+    let router = Router::new(
+        dataset.clone(),
+        vec![(mask_main.clone(), Box::new(subdataset))],
+        args.routing_policy.into_policy(),
+    );
 
     let routed_start = Instant::now();
     let routed_result = router.search_with_bitmask(&query_vector, &mask_main, topk, 16);
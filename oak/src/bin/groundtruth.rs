@@ -0,0 +1,116 @@
+use anyhow::Result;
+use clap::Parser;
+use dropshot::ConfigLogging;
+use dropshot::ConfigLoggingLevel;
+use slog_scope::info;
+use std::path::Path;
+use std::thread;
+use std::time::Instant;
+use thiserror::Error;
+
+use oak::dataset::{QueryFilter, SimilaritySearchable};
+use oak::fvecs::{write_ivecs, AttributeCsv, FlattenedVecs, FvecsDataset};
+use oak::predicate::PredicateQuery;
+
+#[derive(Error, Debug)]
+pub enum GroundtruthError {
+    #[error("Failed to start logger: {0}")]
+    LoggerStartError(String),
+}
+
+/// Computes the exact filtered nearest neighbours of each query by brute force, and writes their
+/// ids to a .ivecs file that `bench` reads as the groundtruth. A query with fewer matching vectors
+/// than `topk` has its remaining neighbours written as -1, as FAISS does.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[arg(short, long, required(true))]
+    dataset: String,
+    #[arg(short, long, required(true))]
+    query: String,
+    /// The .ivecs file to write the groundtruth to.
+    #[arg(short, long, required(true))]
+    output: String,
+    /// The number of nearest neighbours to find for each query.
+    #[arg(short = 'k', long, default_value_t = 100)]
+    topk: usize,
+    /// The predicate that every query is filtered with, e.g. `attr0 = 5 AND attr1 < 10`. If not
+    /// set, each query is filtered by the predicate on the first attribute of its own metadata,
+    /// which is read from the CSV alongside the query file, as in `bench --batch`.
+    #[arg(short, long)]
+    predicate: Option<PredicateQuery>,
+    /// The number of threads to search with, which defaults to the number of CPUs.
+    #[arg(short, long)]
+    threads: Option<usize>,
+    /// If set, the first row of each CSV of attributes is a header naming the columns. Otherwise,
+    /// every row holds the attributes of a vector, and the columns are named `attr0`, `attr1`, ...
+    #[arg(long)]
+    csv_header: bool,
+}
+
+fn main() -> Result<()> {
+    let log = ConfigLogging::StderrTerminal {
+        level: ConfigLoggingLevel::Info,
+    }
+    .to_logger("oak-logger")
+    .map_err(|e| GroundtruthError::LoggerStartError(e.to_string()))?;
+
+    let _guard = slog_scope::set_global_logger(log.clone());
+
+    let args = Args::parse();
+    let topk = args.topk;
+
+    let csv = AttributeCsv::with_header(args.csv_header);
+    let dataset = FvecsDataset::new(args.dataset, csv)?;
+    info!("Dataset of {} vectors loaded from disk.", dataset.len());
+
+    let query_csv = match args.predicate {
+        Some(_) => AttributeCsv::Skip,
+        None => csv,
+    };
+    let query_set = FvecsDataset::new(args.query, query_csv)?;
+    let query_vectors = FlattenedVecs::from(&query_set);
+    info!("{} queries loaded from disk.", query_vectors.len());
+
+    let filters: Vec<QueryFilter> = match args.predicate {
+        Some(pq) => vec![QueryFilter::Predicate(pq); query_vectors.len()],
+        None => Vec::<PredicateQuery>::from(&query_set)
+            .into_iter()
+            .map(QueryFilter::from)
+            .collect(),
+    };
+
+    let threads = match args.threads {
+        Some(threads) => threads,
+        None => thread::available_parallelism()?.get(),
+    };
+    let start = Instant::now();
+    let results = dataset.exact_search_batch(&query_vectors, &filters, topk, threads)?;
+    info!(
+        "Found the {} nearest neighbours of {} queries on {threads} threads in {:?}.",
+        topk,
+        results.len(),
+        start.elapsed()
+    );
+
+    let short = results.iter().filter(|result| result.len() < topk).count();
+    if short > 0 {
+        info!(
+            "{short} queries match fewer than {} vectors; their results are padded with -1.",
+            topk
+        );
+    }
+    let ids: Vec<Vec<i32>> = results
+        .iter()
+        .map(|result| {
+            let mut ids: Vec<i32> = result.iter().map(|&(id, _)| id as i32).collect();
+            ids.resize(topk, -1);
+            ids
+        })
+        .collect();
+    let output = Path::new(&args.output);
+    write_ivecs(output, ids.iter().map(Vec::as_slice))?;
+    info!("Groundtruth written to {}.", output.display());
+
+    Ok(())
+}
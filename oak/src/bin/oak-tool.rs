@@ -0,0 +1,255 @@
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use dropshot::ConfigLogging;
+use dropshot::ConfigLoggingLevel;
+use rand::distributions::WeightedIndex;
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use slog_scope::info;
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+use oak::bitmask::Bitmask;
+use oak::dataset::{AttributeColumn, HybridSearchMetadata, SimilaritySearchable};
+use oak::fvecs::{write_csv_from_metadata, write_fvecs, AttributeCsv, FvecsDataset};
+use oak::predicate::PredicateQuery;
+
+#[derive(Error, Debug)]
+pub enum ToolError {
+    #[error("Failed to start logger: {0}")]
+    LoggerStartError(String),
+    #[error("A synthetic attribute is written as `name=uniform:low:high` or `name=zipf:n:exponent`, not `{0}`")]
+    InvalidAttribute(String),
+    #[error("Cannot take {requested} vectors from a dataset of {available}")]
+    TooFewVectors { requested: usize, available: usize },
+    #[error("Cannot split a dataset into 0 partitions")]
+    NoPartitions,
+}
+
+/// Slices datasets into smaller ones and attaches synthetic attributes to them. The vectors are
+/// read from "{input}.fvecs", or any other format that `FvecsDataset` reads, along with the
+/// attributes in "{input}.csv" if it exists. The vectors that are kept are written to
+/// "{output}.fvecs", and their attributes to "{output}.csv", which has a header naming the
+/// columns.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[arg(short, long, required(true))]
+    input: String,
+    #[arg(short, long, required(true))]
+    output: String,
+    /// A synthetic attribute to attach to every vector before any are chosen, written as
+    /// `name=uniform:low:high` for integers drawn uniformly from `low` to `high` inclusive, or
+    /// `name=zipf:n:exponent` for integers from 1 to `n` drawn from a Zipf distribution, in which
+    /// 1 is the most common. Can be given more than once.
+    #[arg(short, long)]
+    attribute: Vec<SyntheticAttribute>,
+    /// The seed for choosing vectors and drawing attributes, so that datasets can be recreated.
+    /// If not set, a different seed is used on every run.
+    #[arg(short, long)]
+    seed: Option<u64>,
+    /// If set, the first row of "{input}.csv" is a header naming the columns. Otherwise,
+    /// every row holds the attributes of a vector, and the columns are named `attr0`, `attr1`, ...
+    #[arg(long)]
+    csv_header: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Keeps the first `count` vectors.
+    Head {
+        #[arg(short = 'n', long)]
+        count: usize,
+    },
+    /// Keeps `count` vectors chosen at random, in the order they appear in the input.
+    Sample {
+        #[arg(short = 'n', long)]
+        count: usize,
+    },
+    /// Splits the vectors at random into `count` partitions that differ in size by at most one
+    /// vector, written to "{output}_part0", "{output}_part1", and so on.
+    Partition {
+        #[arg(short = 'k', long)]
+        count: usize,
+    },
+    /// Keeps the vectors that match a predicate, e.g. `attr0 = 5 AND attr1 < 10`.
+    Filter {
+        #[arg(short, long)]
+        predicate: PredicateQuery,
+    },
+    /// Keeps every vector, for example to only attach synthetic attributes, or to convert
+    /// vectors in another format to .fvecs.
+    Copy,
+}
+
+#[derive(Clone, Debug)]
+enum AttributeDistribution {
+    Uniform { low: i64, high: i64 },
+    Zipf { n: usize, exponent: f64 },
+}
+
+#[derive(Clone, Debug)]
+struct SyntheticAttribute {
+    name: String,
+    distribution: AttributeDistribution,
+}
+
+impl FromStr for SyntheticAttribute {
+    type Err = ToolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ToolError::InvalidAttribute(s.to_string());
+        let (name, distribution) = s.split_once('=').ok_or_else(invalid)?;
+        let parts: Vec<&str> = distribution.split(':').map(str::trim).collect();
+        let distribution = match parts.as_slice() {
+            ["uniform", low, high] => {
+                let low = low.parse().map_err(|_| invalid())?;
+                let high = high.parse().map_err(|_| invalid())?;
+                if low > high {
+                    return Err(invalid());
+                }
+                AttributeDistribution::Uniform { low, high }
+            }
+            ["zipf", n, exponent] => {
+                let n = n.parse().map_err(|_| invalid())?;
+                let exponent: f64 = exponent.parse().map_err(|_| invalid())?;
+                if n == 0 || !exponent.is_finite() || exponent < 0.0 {
+                    return Err(invalid());
+                }
+                AttributeDistribution::Zipf { n, exponent }
+            }
+            _ => return Err(invalid()),
+        };
+
+        Ok(Self {
+            name: name.trim().to_string(),
+            distribution,
+        })
+    }
+}
+
+impl SyntheticAttribute {
+    fn sample_column(&self, len: usize, rng: &mut StdRng) -> AttributeColumn {
+        let values = match self.distribution {
+            AttributeDistribution::Uniform { low, high } => {
+                (0..len).map(|_| rng.gen_range(low..=high)).collect()
+            }
+            AttributeDistribution::Zipf { n, exponent } => {
+                // The value k is drawn with a probability proportional to 1 / k^exponent.
+                let weights = (1..=n).map(|k| (k as f64).powf(-exponent));
+                let zipf = WeightedIndex::new(weights).unwrap();
+                (0..len).map(|_| zipf.sample(rng) as i64 + 1).collect()
+            }
+        };
+        AttributeColumn::Int(values)
+    }
+}
+
+/// Writes the vectors of `dataset` that are set in `mask` to "{output}.fvecs", and their
+/// attributes to "{output}.csv" if the dataset has any.
+fn write_subset(dataset: &FvecsDataset, mask: &Bitmask, output: &str) -> Result<()> {
+    let vectors = dataset.vectors();
+    write_fvecs(
+        Path::new(&format!("{output}.fvecs")),
+        mask.iter_ones().map(|i| vectors.get(i)),
+    )?;
+    if !dataset.metadata.names().is_empty() {
+        let metadata = HybridSearchMetadata::new_from_bitmask(&dataset.metadata, mask);
+        write_csv_from_metadata(Path::new(&format!("{output}.csv")), &metadata)?;
+    }
+    info!("Wrote {} vectors to {output}.", mask.bitcount());
+
+    Ok(())
+}
+
+/// The mask of the first `count` of `len` vectors, or of `count` chosen at random if `rng` is
+/// given.
+fn choose(count: usize, len: usize, rng: Option<&mut StdRng>) -> Result<Bitmask, ToolError> {
+    if count > len {
+        return Err(ToolError::TooFewVectors {
+            requested: count,
+            available: len,
+        });
+    }
+    let mut mask = Bitmask::empty(len);
+    match rng {
+        Some(rng) => rand::seq::index::sample(rng, len, count)
+            .into_iter()
+            .for_each(|i| mask.set(i, true)),
+        None => (0..count).for_each(|i| mask.set(i, true)),
+    }
+    Ok(mask)
+}
+
+fn main() -> Result<()> {
+    let log = ConfigLogging::StderrTerminal {
+        level: ConfigLoggingLevel::Info,
+    }
+    .to_logger("oak-logger")
+    .map_err(|e| ToolError::LoggerStartError(e.to_string()))?;
+
+    let _guard = slog_scope::set_global_logger(log.clone());
+
+    let args = Args::parse();
+
+    let csv = if Path::new(&format!("{}.csv", args.input)).exists() {
+        AttributeCsv::with_header(args.csv_header)
+    } else {
+        AttributeCsv::Skip
+    };
+    let mut dataset = FvecsDataset::new(args.input, csv)?;
+    let len = dataset.len();
+    info!(
+        "Dataset of {len} vectors with {} attributes loaded from disk.",
+        dataset.metadata.names().len()
+    );
+
+    let mut rng = match args.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    for attribute in &args.attribute {
+        let column = attribute.sample_column(len, &mut rng);
+        dataset
+            .metadata
+            .push_column(attribute.name.clone(), column)?;
+        info!("Attached attribute {}.", attribute.name);
+    }
+
+    match args.command {
+        Command::Head { count } => {
+            write_subset(&dataset, &choose(count, len, None)?, &args.output)?;
+        }
+        Command::Sample { count } => {
+            write_subset(&dataset, &choose(count, len, Some(&mut rng))?, &args.output)?;
+        }
+        Command::Partition { count } => {
+            if count == 0 {
+                return Err(ToolError::NoPartitions.into());
+            }
+            // Dealing the shuffled vectors out in turn keeps the partitions balanced.
+            let mut ids: Vec<usize> = (0..len).collect();
+            ids.shuffle(&mut rng);
+            let mut masks = vec![Bitmask::empty(len); count];
+            for (position, &id) in ids.iter().enumerate() {
+                masks[position % count].set(id, true);
+            }
+            for (i, mask) in masks.iter().enumerate() {
+                write_subset(&dataset, mask, &format!("{}_part{i}", args.output))?;
+            }
+        }
+        Command::Filter { predicate } => {
+            let mask = Bitmask::new(&predicate, &dataset)?;
+            write_subset(&dataset, &mask, &args.output)?;
+        }
+        Command::Copy => {
+            write_subset(&dataset, &Bitmask::full(len), &args.output)?;
+        }
+    }
+
+    Ok(())
+}
@@ -6,22 +6,22 @@ use dropshot::HttpError;
 use dropshot::HttpResponseOk;
 use dropshot::RequestContext;
 use dropshot::ServerBuilder;
+use dropshot::TypedBody;
 use schemars::JsonSchema;
-use semver;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use slog_scope::info;
 use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
 use thiserror::Error;
 
-use oak::dataset::{OakIndexOptions, SimilaritySearchable};
-use oak::fvecs::FvecsDataset;
-// use oak::stubs::generate_random_vector;
-
-// Ensure that only one of FAISS or hnsw_rs is used.
-#[cfg(all(feature = "hnsw_faiss", feature = "hnsw_rust"))]
-compile_error!(
-    "Features `hnsw_faiss` and `hnsw_rust` cannot be enabled at the same time. Please enable only one."
-);
+use oak::dataset::{OakIndexOptions, SearchableError, SimilaritySearchable, TopKSearchResult};
+use oak::fvecs::{AttributeCsv, FlattenedVecs, FvecsDataset};
+use oak::predicate::PredicateQuery;
+use oak::router::{Router, RoutingPolicyKind};
+use oak::workload::{AutoPartitionOptions, AutoPartitioner};
 
 #[derive(Error, Debug)]
 pub enum ServerError {
@@ -36,8 +36,29 @@ pub enum ServerError {
 struct Args {
     #[arg(short, long, required(true))]
     dataset: String,
+    /// If set, the index is loaded from this file when it exists, and otherwise built and saved
+    /// to it.
+    #[arg(short, long)]
+    index: Option<String>,
+    /// How queries are routed between the base index and the opportunistic partitions: one of
+    /// `heuristic`, `always-base`, `best-coverage` or `cost-based`, optionally followed by a colon
+    /// and the policy's threshold, e.g. `heuristic:20`.
+    #[arg(short, long, default_value = "heuristic")]
+    routing_policy: RoutingPolicyKind,
+    /// If set, partitions are built in the background for predicates that are queried often, and
+    /// queries are routed to them once they are built.
+    #[arg(short, long)]
+    auto_partition: bool,
+    /// If set, the first row of each CSV of attributes is a header naming the columns. Otherwise,
+    /// every row holds the attributes of a vector, and the columns are named `attr0`, `attr1`, ...
+    #[arg(long)]
+    csv_header: bool,
 }
 
+/// How often the background partitioner checks for predicates that are worth a partition, and
+/// for partitions that have finished building.
+const AUTO_PARTITION_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Information about the database.
 #[derive(Serialize, JsonSchema)]
 struct OakInfo {
@@ -45,9 +66,83 @@ struct OakInfo {
     dimensionality: usize,
 }
 
+/// A request for the top-k nearest neighbours of one or more query vectors.
+#[derive(Deserialize, JsonSchema)]
+struct OakAnnsQuery {
+    /// The query vectors, each of which must have the dimensionality of the dataset.
+    queries: Vec<Vec<f32>>,
+    /// The number of neighbours to return for each query vector.
+    k: usize,
+    /// The search-time parameter that trades off recall against performance.
+    efsearch: i64,
+    /// If present, only vectors that match this predicate are returned, e.g. `attr0 = 5 AND
+    /// (attr1 < 10 OR attr2 IN (1, 2, 3))`.
+    predicate: Option<String>,
+}
+
+/// The neighbours found for a single query vector, closest first.
+#[derive(Serialize, JsonSchema)]
+struct OakQueryResult {
+    /// The ids of the neighbours in the dataset.
+    ids: Vec<usize>,
+    /// The distance of each neighbour in `ids` from the query vector.
+    distances: Vec<f32>,
+}
+
+impl From<TopKSearchResult> for OakQueryResult {
+    fn from(result: TopKSearchResult) -> Self {
+        let (ids, distances) = result.into_iter().unzip();
+        Self { ids, distances }
+    }
+}
+
+/// The results of an ANNS search, with one entry per query vector in the order they were sent.
 #[derive(Serialize, JsonSchema)]
 struct OakAnnsResult {
-    // TODO:
+    results: Vec<OakQueryResult>,
+}
+
+/// State shared between all requests to the server.
+struct OakContext {
+    /// Routes queries between the dataset, which must be indexed before the server starts, and
+    /// any partitions of it. Requests only read it, so can be served concurrently, and it is only
+    /// locked for writing while partitions are registered or evicted.
+    router: Arc<RwLock<Router>>,
+}
+
+impl OakAnnsQuery {
+    /// Checks that the query can be run against a dataset of `len` vectors of the given
+    /// dimensionality, returning a message describing the first problem found otherwise.
+    fn validate(&self, dimensionality: usize, len: usize) -> Result<(), String> {
+        if self.queries.is_empty() {
+            return Err("at least one query vector must be provided".to_string());
+        }
+        if self.k == 0 {
+            return Err("`k` must be greater than 0".to_string());
+        }
+        if self.k > len {
+            return Err(format!(
+                "`k` must be at most {len}, the number of vectors in the dataset"
+            ));
+        }
+        if self.efsearch <= 0 {
+            return Err("`efsearch` must be greater than 0".to_string());
+        }
+        for (i, query) in self.queries.iter().enumerate() {
+            if query.len() != dimensionality {
+                return Err(format!(
+                    "query vector {i} has dimensionality {}, but the dataset has dimensionality {dimensionality}",
+                    query.len()
+                ));
+            }
+            if query.iter().any(|x| !x.is_finite()) {
+                return Err(format!(
+                    "query vector {i} contains a value that is not finite"
+                ));
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Defines the trait that captures all the methods.
@@ -72,38 +167,85 @@ trait OakApi {
     }]
     async fn oak_anns_query(
         rqctx: RequestContext<Self::Context>,
+        body: TypedBody<OakAnnsQuery>,
     ) -> Result<HttpResponseOk<OakAnnsResult>, HttpError>;
 }
 
 enum ServerImpl {}
 impl OakApi for ServerImpl {
-    type Context = OakInfo;
+    type Context = OakContext;
 
     async fn oak_get_info(
         rqctx: RequestContext<Self::Context>,
     ) -> Result<HttpResponseOk<OakInfo>, HttpError> {
-        let info = rqctx.context();
+        let router = rqctx
+            .context()
+            .router
+            .read()
+            .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
         Ok(HttpResponseOk(OakInfo {
-            dimensionality: info.dimensionality,
+            dimensionality: router.get_dimensionality(),
         }))
     }
 
     async fn oak_anns_query(
-        _rqctx: RequestContext<Self::Context>,
+        rqctx: RequestContext<Self::Context>,
+        body: TypedBody<OakAnnsQuery>,
     ) -> Result<HttpResponseOk<OakAnnsResult>, HttpError> {
-        unimplemented!();
-        // info!("Constructing random vector to query with {dimensionality} dimensions");
-        // let query_vector = FlattenedVecs {
-        //     dimensionality,
-        //     data: generate_random_vector(dimensionality),
-        // };
-        // let topk = 10;
-        // let num_queries = query_vector.len();
-        // info!("Searching {topk} similar vectors for {num_queries} queries...");
-
-        // let query: Option<PredicateQuery> = None;
-
-        // let result = dataset.search(query_vector, query, topk);
+        let query = body.into_inner();
+        let router = Arc::clone(&rqctx.context().router);
+        let (dimensionality, len) = {
+            let router = router
+                .read()
+                .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
+            (router.get_dimensionality(), router.len())
+        };
+        query
+            .validate(dimensionality, len)
+            .map_err(|msg| HttpError::for_bad_request(None, msg))?;
+
+        // The query vectors are moved into a flattened batch, so the other fields are taken out
+        // first.
+        let OakAnnsQuery {
+            queries,
+            k,
+            efsearch,
+            predicate,
+        } = query;
+        let num_queries = queries.len();
+        let query_vectors = FlattenedVecs {
+            dimensionality,
+            data: queries.into_iter().flatten().collect(),
+        };
+        let predicate = predicate
+            .as_deref()
+            .map(str::parse::<PredicateQuery>)
+            .transpose()
+            .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+        info!("Searching {k} similar vectors for {num_queries} queries...");
+
+        // Searching blocks until it is done, so it is run off the async runtime, which would
+        // otherwise be unable to serve other requests in the meantime.
+        let results = tokio::task::spawn_blocking(move || {
+            let router = router
+                .read()
+                .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
+            router
+                .search(&query_vectors, &predicate, k, efsearch)
+                .map_err(|e| match e {
+                    SearchableError::UnknownAttribute(_)
+                    | SearchableError::AttributeTypeMismatch(_) => {
+                        HttpError::for_bad_request(None, e.to_string())
+                    }
+                    _ => HttpError::for_internal_error(e.to_string()),
+                })
+        })
+        .await
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))??;
+
+        Ok(HttpResponseOk(OakAnnsResult {
+            results: results.into_iter().map(OakQueryResult::from).collect(),
+        }))
     }
 }
 
@@ -119,7 +261,7 @@ async fn main() -> Result<()> {
 
     let args = Args::parse();
 
-    let mut dataset = FvecsDataset::new(args.dataset, true)?;
+    let mut dataset = FvecsDataset::new(args.dataset, AttributeCsv::with_header(args.csv_header))?;
     info!("Dataset loaded from disk.");
 
     let opts = OakIndexOptions {
@@ -128,10 +270,26 @@ async fn main() -> Result<()> {
         m_beta: 64,
     };
 
-    let _ = dataset.initialize(&opts);
+    match &args.index {
+        Some(path) => dataset.initialize_cached(&opts, Path::new(path))?,
+        None => dataset.initialize(&opts)?,
+    }
     info!("Seed index constructed.");
+    let dataset = Arc::new(dataset);
 
-    let dimensionality = dataset.get_dimensionality() as usize;
+    let mut router = Router::new(dataset.clone(), vec![], args.routing_policy.into_policy());
+    let partitioner = args.auto_partition.then(|| {
+        let partitioner = AutoPartitioner::new(
+            dataset,
+            AutoPartitionOptions {
+                index_options: opts,
+                ..Default::default()
+            },
+        );
+        router.track_workload(partitioner.workload());
+        partitioner
+    });
+    let router = Arc::new(RwLock::new(router));
 
     let mut f = OpenOptions::new()
         .create(true)
@@ -146,10 +304,23 @@ async fn main() -> Result<()> {
 
     info!("OpenAPI spec written to file.");
 
-    let info = OakInfo { dimensionality };
+    if let Some(mut partitioner) = partitioner {
+        let router = Arc::clone(&router);
+        thread::spawn(move || loop {
+            thread::sleep(AUTO_PARTITION_INTERVAL);
+            if router.is_poisoned() {
+                return;
+            }
+            if let Err(e) = partitioner.poll(&router) {
+                info!("Failed to build a partition: {e}");
+            }
+        });
+    }
+
+    let context = OakContext { router };
 
     // Start the server.
-    let server = ServerBuilder::new(api, info, log)
+    let server = ServerBuilder::new(api, context, log)
         .start()
         .map_err(|error| ServerError::ServerStartError(format!("{error}")))?;
 
@@ -1,123 +1,599 @@
-use crate::dataset::SimilaritySearchable;
-use crate::predicate::{PredicateOp, PredicateQuery};
+use crate::dataset::{HybridSearchMetadata, SearchableError, SimilaritySearchable};
+use crate::predicate::PredicateQuery;
 use core::ffi::c_char;
-use std::collections::HashSet;
+use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Not};
 
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// Evaluates a predicate against the attributes of every vector, returning a mask with a bit set
+/// for each vector that matches.
+fn evaluate(
+    pq: &PredicateQuery,
+    metadata: &HybridSearchMetadata,
+) -> Result<Bitmask, SearchableError> {
+    Ok(evaluate_known(pq, metadata)?.0)
+}
+
+/// Evaluates a predicate with SQL's three-valued logic, returning the mask of vectors for which it
+/// is true, and the mask of those for which it is either true or false. The rest compare a value
+/// that cannot be compared, such as NaN, and so match neither the predicate nor its negation.
+fn evaluate_known(
+    pq: &PredicateQuery,
+    metadata: &HybridSearchMetadata,
+) -> Result<(Bitmask, Bitmask), SearchableError> {
+    match pq {
+        PredicateQuery::Attribute { lhs, op } => {
+            let column = metadata
+                .column(lhs)
+                .ok_or_else(|| SearchableError::UnknownAttribute(lhs.clone()))?;
+            if !op.operands().iter().all(|rhs| column.is_comparable(rhs)) {
+                return Err(SearchableError::AttributeTypeMismatch(lhs.clone()));
+            }
+            let matches = (0..metadata.len()).map(|i| op.matches(column, i)).collect();
+            let known = (0..metadata.len())
+                .map(|i| op.is_known(column, i))
+                .collect();
+            Ok((matches, known))
+        }
+        PredicateQuery::And(children) => {
+            // A conjunction is false if any child is false, and true if every child is true.
+            let mut matches = Bitmask::full(metadata.len());
+            let mut refuted = Bitmask::empty(metadata.len());
+            for child in children {
+                let (child_matches, child_known) = evaluate_known(child, metadata)?;
+                refuted |= &(&child_known & &!&child_matches);
+                matches &= &child_matches;
+            }
+            let known = &matches | &refuted;
+            Ok((matches, known))
+        }
+        PredicateQuery::Or(children) => {
+            // A disjunction is true if any child is true, and false if every child is false.
+            let mut matches = Bitmask::empty(metadata.len());
+            let mut refuted = Bitmask::full(metadata.len());
+            for child in children {
+                let (child_matches, child_known) = evaluate_known(child, metadata)?;
+                refuted &= &(&child_known & &!&child_matches);
+                matches |= &child_matches;
+            }
+            let known = &matches | &refuted;
+            Ok((matches, known))
+        }
+        PredicateQuery::Not(child) => {
+            let (matches, known) = evaluate_known(child, metadata)?;
+            Ok((&known & &!&matches, known))
+        }
+    }
+}
+
+/// A set of vectors in a dataset, stored as one bit per vector packed into `u64` words. Bit `i`
+/// is set if the vector at index `i` is in the set. Bits past the end of the mask in the last word
+/// are always kept clear, so that whole words can be counted and compared directly.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Bitmask {
-    pub map: Vec<i8>,
-    pub bitcount: usize,
+    words: Vec<u64>,
+    len: usize,
 }
 
 impl Bitmask {
-    /// 'Serializes' a query as a filter map, which will allow it to be passed across FFI to the
-    /// `search` function. A filter map is specific to a dataset, as it is of length (nq * N),
-    /// where nq is the number of queries in the map, and N is the number of vectors in the
-    /// dataset. A value of 1 in the bitmap represents that the search query matches with the
-    /// vector at that index in the dataset, and a value of 0 that it doesn't.
+    /// Evaluates a query over a dataset, producing a mask with a bit set for each vector that the
+    /// query matches. A mask is specific to a dataset, as it has one bit for each of the N vectors
+    /// in the dataset.
     ///
-    /// If an attribute matching the `lhs` of the query does not exist in the dataset, then an
-    /// error will be raised.
-    pub fn new<D: SimilaritySearchable>(pq: &PredicateQuery, dataset: &D) -> Self {
-        let ds_len = dataset.len();
-        let mut map: Vec<c_char> = vec![0; ds_len];
-
-        assert_eq!(dataset.get_metadata().len(), map.len());
-
-        let rhs: i32 = i32::from(&pq.rhs);
-        let on_bit: c_char = 1;
-        let mut bitcount: usize = 0;
-
-        for (i, xq) in dataset.get_metadata().as_ref().iter().enumerate() {
-            let bit = match pq.op {
-                PredicateOp::Equals => (xq == &rhs) as c_char,
-            };
-            if bit.eq(&on_bit) {
-                bitcount += 1;
-            }
-            map[i] = bit;
+    /// The whole predicate tree is evaluated against the dataset's metadata. If an attribute
+    /// named in the query does not exist in the dataset, or its values cannot be compared with
+    /// the values in the query, then an error will be raised.
+    pub fn new<D: SimilaritySearchable + ?Sized>(
+        pq: &PredicateQuery,
+        dataset: &D,
+    ) -> Result<Self, SearchableError> {
+        let metadata = dataset.get_metadata();
+        if metadata.len() != dataset.len() {
+            return Err(SearchableError::SizeMismatch {
+                vectors: dataset.len(),
+                attributes: metadata.len(),
+            });
         }
 
-        Self { map, bitcount }
+        evaluate(pq, metadata)
     }
 
+    pub fn new_full<D: SimilaritySearchable + ?Sized>(dataset: &D) -> Self {
+        Self::full(dataset.len())
+    }
+
+    /// A mask of `len` vectors, none of which are set.
+    pub fn empty(len: usize) -> Self {
+        Self {
+            words: vec![0; len.div_ceil(WORD_BITS)],
+            len,
+        }
+    }
+
+    /// A mask of `len` vectors, all of which are set.
+    pub fn full(len: usize) -> Self {
+        let mut mask = Self {
+            words: vec![u64::MAX; len.div_ceil(WORD_BITS)],
+            len,
+        };
+        mask.clear_tail();
+        mask
+    }
+
+    /// The number of vectors that the mask covers, whether they are set or not.
     pub fn capacity(&self) -> usize {
-        self.map.len()
+        self.len
+    }
+
+    /// The number of bytes used by the bits of the mask.
+    pub fn memory_usage(&self) -> usize {
+        self.words.capacity() * std::mem::size_of::<u64>()
     }
 
+    /// A hash of which vectors are set and how many the mask covers, used to check that a saved
+    /// index was built over the same vectors. It is computed with 64-bit FNV-1a, so it is the same
+    /// across runs and builds.
+    pub fn fingerprint(&self) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+
+        std::iter::once(self.len as u64)
+            .chain(self.words.iter().copied())
+            .flat_map(u64::to_le_bytes)
+            .fold(FNV_OFFSET_BASIS, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+            })
+    }
+
+    /// The number of vectors that are set.
     pub fn bitcount(&self) -> usize {
-        self.bitcount
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        assert!(
+            i < self.len,
+            "index {i} out of range for mask of {}",
+            self.len
+        );
+        self.words[i / WORD_BITS] & (1 << (i % WORD_BITS)) != 0
+    }
+
+    pub fn set(&mut self, i: usize, value: bool) {
+        assert!(
+            i < self.len,
+            "index {i} out of range for mask of {}",
+            self.len
+        );
+        let bit = 1 << (i % WORD_BITS);
+        if value {
+            self.words[i / WORD_BITS] |= bit;
+        } else {
+            self.words[i / WORD_BITS] &= !bit;
+        }
+    }
+
+    /// Iterates over the indexes of the vectors that are set, in increasing order.
+    pub fn iter_ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, &word)| {
+            let mut remaining = word;
+            std::iter::from_fn(move || {
+                if remaining == 0 {
+                    return None;
+                }
+                let bit = remaining.trailing_zeros() as usize;
+                remaining &= remaining - 1;
+                Some(i * WORD_BITS + bit)
+            })
+        })
     }
 
-    pub fn new_full<D: SimilaritySearchable>(dataset: &D) -> Self {
-        let map = vec![true as i8; dataset.len()];
-        let bitcount = map.len();
-        Self { map, bitcount }
+    /// Expands the mask into the filter map format used by ACORN, with one `c_char` per vector
+    /// that is 1 if the vector is set and 0 otherwise. This should only be needed at the FFI
+    /// boundary.
+    pub fn to_filter_map(&self) -> Vec<c_char> {
+        (0..self.len).map(|i| self.get(i) as c_char).collect()
     }
 
-    pub fn to_hashset(&self) -> HashSet<i8> {
-        self.map.clone().into_iter().collect()
+    /// The number of vectors that are set in both masks.
+    pub fn intersection_count(&self, other: &Self) -> usize {
+        self.assert_same_capacity(other);
+        self.words
+            .iter()
+            .zip(&other.words)
+            .map(|(a, b)| (a & b).count_ones() as usize)
+            .sum()
     }
 
+    /// The number of vectors that are set in either mask.
+    pub fn union_count(&self, other: &Self) -> usize {
+        self.assert_same_capacity(other);
+        self.words
+            .iter()
+            .zip(&other.words)
+            .map(|(a, b)| (a | b).count_ones() as usize)
+            .sum()
+    }
+
+    /// Whether every vector that is set in this mask is also set in `other`.
+    pub fn is_subset_of(&self, other: &Self) -> bool {
+        self.assert_same_capacity(other);
+        self.words
+            .iter()
+            .zip(&other.words)
+            .all(|(a, b)| a & !b == 0)
+    }
+
+    /// The Jaccard similarity of the sets of vectors in the two masks, i.e. the size of their
+    /// intersection over the size of their union. This is 0 if neither mask has any vectors set.
     pub fn jaccard_similarity(&self, other: &Self) -> f64 {
-        // Convert vectors to sets
-        let set1 = self.to_hashset();
-        let set2 = other.to_hashset();
+        let union = self.union_count(other);
+        if union == 0 {
+            return 0.0;
+        }
+        self.intersection_count(other) as f64 / union as f64
+    }
+
+    /// The fraction of the vectors in this mask that are also in `other`. For a query mask and
+    /// the mask of a partition, this is the fraction of the vectors matching the query that a
+    /// search over the partition can find. An empty mask is entirely covered by any other.
+    pub fn coverage_by(&self, other: &Self) -> f64 {
+        let bitcount = self.bitcount();
+        if bitcount == 0 {
+            return 1.0;
+        }
+        self.intersection_count(other) as f64 / bitcount as f64
+    }
 
-        // Calculate the intersection and union
-        let intersection: HashSet<_> = set1.intersection(&set2).cloned().collect();
-        let union: HashSet<_> = set1.union(&set2).cloned().collect();
+    /// The fraction of the vectors in `other` that are also in this mask. For a query mask and
+    /// the mask of a partition, this is the fraction of the partition that matches the query. It
+    /// is 0 if `other` is empty.
+    pub fn density_in(&self, other: &Self) -> f64 {
+        let bitcount = other.bitcount();
+        if bitcount == 0 {
+            return 0.0;
+        }
+        self.intersection_count(other) as f64 / bitcount as f64
+    }
 
-        // Calculate Jaccard similarity
-        if union.is_empty() {
-            0.0 // Handle edge case when both sets are empty
-        } else {
-            intersection.len() as f64 / union.len() as f64
+    /// Clears the unused bits of the last word, which operations such as `!` will have set.
+    fn clear_tail(&mut self) {
+        let used = self.len % WORD_BITS;
+        if used != 0 {
+            if let Some(last) = self.words.last_mut() {
+                *last &= (1 << used) - 1;
+            }
+        }
+    }
+
+    fn assert_same_capacity(&self, other: &Self) {
+        assert_eq!(
+            self.len, other.len,
+            "cannot combine masks over different numbers of vectors"
+        );
+    }
+}
+
+impl FromIterator<bool> for Bitmask {
+    fn from_iter<I: IntoIterator<Item = bool>>(iter: I) -> Self {
+        let mut words = Vec::new();
+        let mut len = 0;
+        for value in iter {
+            if len % WORD_BITS == 0 {
+                words.push(0);
+            }
+            if value {
+                *words.last_mut().unwrap() |= 1 << (len % WORD_BITS);
+            }
+            len += 1;
         }
+        Self { words, len }
     }
 }
 
-impl From<Bitmask> for Vec<i8> {
-    fn from(mask: Bitmask) -> Self {
-        mask.map
+impl BitAndAssign<&Bitmask> for Bitmask {
+    fn bitand_assign(&mut self, other: &Bitmask) {
+        self.assert_same_capacity(other);
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= other;
+        }
+    }
+}
+
+impl BitOrAssign<&Bitmask> for Bitmask {
+    fn bitor_assign(&mut self, other: &Bitmask) {
+        self.assert_same_capacity(other);
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+}
+
+impl BitAnd for &Bitmask {
+    type Output = Bitmask;
+
+    fn bitand(self, other: &Bitmask) -> Bitmask {
+        let mut mask = self.clone();
+        mask &= other;
+        mask
     }
 }
 
-impl From<&Bitmask> for Vec<i8> {
-    fn from(mask: &Bitmask) -> Self {
-        // NOTE: we take cloning the Bitmask here as acceptable, as the values are only i8s
-        mask.map.clone()
+impl BitOr for &Bitmask {
+    type Output = Bitmask;
+
+    fn bitor(self, other: &Bitmask) -> Bitmask {
+        let mut mask = self.clone();
+        mask |= other;
+        mask
+    }
+}
+
+impl Not for &Bitmask {
+    type Output = Bitmask;
+
+    fn not(self) -> Bitmask {
+        let mut mask = Bitmask {
+            words: self.words.iter().map(|word| !word).collect(),
+            len: self.len,
+        };
+        mask.clear_tail();
+        mask
     }
 }
 
 impl From<Vec<i32>> for Bitmask {
     fn from(attrs: Vec<i32>) -> Bitmask {
-        let bitcount = attrs.iter().sum::<i32>() as usize;
-        let map = attrs.into_iter().map(|x| x as i8).collect();
-        Self { map, bitcount }
+        attrs.into_iter().map(|x| x != 0).collect()
     }
 }
 
 impl From<Vec<i8>> for Bitmask {
     fn from(map: Vec<i8>) -> Bitmask {
-        let bitcount = map.iter().sum::<i8>() as usize;
-        Self { map, bitcount }
+        map.into_iter().map(|x| x != 0).collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dataset::AttributeColumn;
+    use crate::fvecs::{AttributeCsv, FvecsDataset};
+    use crate::predicate::{PredicateOp, PredicateRhs};
 
     #[test]
     fn test_serialize() {
-        let dataset = FvecsDataset::new("data/sift_query".to_string()).unwrap();
-        let pq = PredicateQuery {
-            op: PredicateOp::Equals,
-            rhs: PredicateRhs::Number(10),
+        let dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
+        let pq = PredicateQuery::Attribute {
+            lhs: "attr0".to_string(),
+            op: PredicateOp::Equals(PredicateRhs::Number(10)),
+        };
+
+        let bitmap = Bitmask::new(&pq, &dataset).unwrap();
+        assert!(bitmap.bitcount() > 0);
+    }
+
+    #[test]
+    fn test_unknown_attribute() {
+        let dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
+        let pq = PredicateQuery::equals("no_such_attribute", PredicateRhs::Number(10));
+
+        assert_eq!(
+            Bitmask::new(&pq, &dataset).err(),
+            Some(SearchableError::UnknownAttribute(
+                "no_such_attribute".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_attributes_size_mismatch() {
+        let mut dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
+        dataset.metadata = HybridSearchMetadata::without_attributes(dataset.len() - 1);
+        let pq = PredicateQuery::equals("attr0", PredicateRhs::Number(10));
+
+        assert_eq!(
+            Bitmask::new(&pq, &dataset).err(),
+            Some(SearchableError::SizeMismatch {
+                vectors: 1000,
+                attributes: 999
+            })
+        );
+    }
+
+    #[test]
+    fn test_evaluate_compound_predicate() {
+        let metadata = HybridSearchMetadata::new(
+            vec!["category".to_string(), "price".to_string()],
+            vec![
+                AttributeColumn::Int(vec![1, 2, 3, 4, 5]),
+                AttributeColumn::Float(vec![1.0, 9.0, 3.0, f64::NAN, 2.0]),
+            ],
+        );
+        let attribute = |lhs: &str, op| PredicateQuery::Attribute {
+            lhs: lhs.to_string(),
+            op,
         };
 
-        let bitmap = Bitmask::new(&pq, &dataset);
-        let one = 1 as c_char;
-        assert!(bitmap.map.contains(&one));
+        // category IN (1, 2, 5) AND NOT (price BETWEEN 2 AND 9)
+        let pq = PredicateQuery::And(vec![
+            attribute(
+                "category",
+                PredicateOp::In(vec![
+                    PredicateRhs::Number(1),
+                    PredicateRhs::Number(2),
+                    PredicateRhs::Number(5),
+                ]),
+            ),
+            PredicateQuery::Not(Box::new(attribute(
+                "price",
+                PredicateOp::Between(PredicateRhs::Number(2), PredicateRhs::Number(9)),
+            ))),
+        ]);
+        assert_eq!(
+            evaluate(&pq, &metadata).unwrap().to_filter_map(),
+            vec![1, 0, 0, 0, 0]
+        );
+
+        // category >= 4 OR price < 2.5, where NaN never matches
+        let pq = PredicateQuery::Or(vec![
+            attribute(
+                "category",
+                PredicateOp::GreaterThanOrEquals(PredicateRhs::Number(4)),
+            ),
+            attribute("price", PredicateOp::LessThan(PredicateRhs::Float(2.5))),
+        ]);
+        assert_eq!(
+            evaluate(&pq, &metadata).unwrap().to_filter_map(),
+            vec![1, 0, 0, 1, 1]
+        );
+
+        let pq = attribute("price", PredicateOp::NotEquals(PredicateRhs::Number(9)));
+        assert_eq!(
+            evaluate(&pq, &metadata).unwrap().to_filter_map(),
+            vec![1, 0, 1, 0, 1]
+        );
+
+        // NaN matches neither a comparison nor its negation, however the negation is written.
+        let pq = PredicateQuery::Not(Box::new(attribute(
+            "price",
+            PredicateOp::Equals(PredicateRhs::Number(9)),
+        )));
+        assert_eq!(
+            evaluate(&pq, &metadata).unwrap().to_filter_map(),
+            vec![1, 0, 1, 0, 1]
+        );
+        let pq = PredicateQuery::Not(Box::new(attribute(
+            "price",
+            PredicateOp::NotEquals(PredicateRhs::Number(9)),
+        )));
+        assert_eq!(
+            evaluate(&pq, &metadata).unwrap().to_filter_map(),
+            vec![0, 1, 0, 0, 0]
+        );
+
+        // A NaN price leaves a conjunction unknown only if nothing else in it is false, and a
+        // disjunction unknown only if nothing else in it is true.
+        let category = |category| {
+            attribute(
+                "category",
+                PredicateOp::Equals(PredicateRhs::Number(category)),
+            )
+        };
+        let price = || attribute("price", PredicateOp::Equals(PredicateRhs::Number(9)));
+        for (category_matched, expected) in [(3, vec![1, 1, 1, 1, 1]), (4, vec![1, 1, 1, 0, 1])] {
+            let pq = PredicateQuery::Not(Box::new(PredicateQuery::And(vec![
+                category(category_matched),
+                price(),
+            ])));
+            assert_eq!(evaluate(&pq, &metadata).unwrap().to_filter_map(), expected);
+        }
+        for (category_matched, expected) in [(3, vec![1, 0, 0, 0, 1]), (4, vec![1, 0, 1, 0, 1])] {
+            let pq = PredicateQuery::Not(Box::new(PredicateQuery::Or(vec![
+                category(category_matched),
+                price(),
+            ])));
+            assert_eq!(evaluate(&pq, &metadata).unwrap().to_filter_map(), expected);
+        }
+
+        let pq = attribute(
+            "category",
+            PredicateOp::Equals(PredicateRhs::Text("a".to_string())),
+        );
+        assert_eq!(
+            evaluate(&pq, &metadata),
+            Err(SearchableError::AttributeTypeMismatch(
+                "category".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_packed_operations() {
+        // Spans more than one word, so that the partially used last word is exercised.
+        let len = 130;
+        let evens: Bitmask = (0..len).map(|i| i % 2 == 0).collect();
+        let low: Bitmask = (0..len).map(|i| i < 100).collect();
+
+        assert_eq!(evens.capacity(), len);
+        assert_eq!(evens.bitcount(), 65);
+        assert!(evens.get(128));
+        assert!(!evens.get(129));
+
+        let both = &evens & &low;
+        assert_eq!(both.bitcount(), 50);
+        assert!(both.iter_ones().all(|i| i % 2 == 0 && i < 100));
+
+        let either = &evens | &low;
+        assert_eq!(either.bitcount(), 115);
+
+        let odds = !&evens;
+        assert_eq!(odds.bitcount(), 65);
+        assert_eq!(odds.iter_ones().next(), Some(1));
+        assert_eq!(odds.iter_ones().last(), Some(129));
+        assert_eq!(!&odds, evens);
+
+        assert!(both.is_subset_of(&evens));
+        assert!(!evens.is_subset_of(&both));
+        assert_eq!(Bitmask::full(len), !&Bitmask::empty(len));
+        assert_eq!(Bitmask::full(len).bitcount(), len);
+
+        let mut mask = Bitmask::empty(len);
+        mask.set(64, true);
+        mask.set(3, true);
+        mask.set(3, false);
+        assert_eq!(mask.iter_ones().collect::<Vec<_>>(), vec![64]);
+        assert_eq!(mask.to_filter_map()[63..66], [0, 1, 0]);
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let mut a = Bitmask::empty(100);
+        a.set(3, true);
+        let mut b = Bitmask::empty(100);
+        b.set(4, true);
+        assert_ne!(a.fingerprint(), b.fingerprint());
+        b.set(4, false);
+        b.set(3, true);
+        assert_eq!(a.fingerprint(), b.fingerprint());
+
+        // Masks that set the same vectors but cover different numbers of them differ.
+        let mut c = Bitmask::empty(101);
+        c.set(3, true);
+        assert_ne!(a.fingerprint(), c.fingerprint());
+    }
+
+    #[test]
+    fn test_similarity_metrics() {
+        let query = Bitmask::from(vec![1i8, 1, 1, 1, 0, 0, 0, 0]);
+        let partition = Bitmask::from(vec![0i8, 0, 1, 1, 1, 1, 1, 1]);
+
+        assert_eq!(query.intersection_count(&partition), 2);
+        assert_eq!(query.union_count(&partition), 8);
+        assert_eq!(query.jaccard_similarity(&partition), 0.25);
+        assert_eq!(query.coverage_by(&partition), 0.5);
+        assert_eq!(query.density_in(&partition), 2.0 / 6.0);
+
+        // Masks with the same mix of values are no longer treated as identical.
+        let other = Bitmask::from(vec![0i8, 0, 0, 0, 1, 1, 1, 1]);
+        assert_eq!(query.jaccard_similarity(&other), 0.0);
+        assert_eq!(query.jaccard_similarity(&query), 1.0);
+
+        // A query contained in a partition is fully covered by it.
+        let contained = Bitmask::from(vec![0i8, 0, 0, 1, 1, 0, 0, 0]);
+        assert_eq!(contained.coverage_by(&partition), 1.0);
+        assert_eq!(contained.density_in(&partition), 2.0 / 6.0);
+        assert_eq!(contained.jaccard_similarity(&partition), 2.0 / 6.0);
+
+        let empty = Bitmask::empty(8);
+        assert_eq!(empty.jaccard_similarity(&empty), 0.0);
+        assert_eq!(empty.coverage_by(&partition), 1.0);
+        assert_eq!(query.density_in(&empty), 0.0);
     }
 }
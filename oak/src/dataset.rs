@@ -1,8 +1,13 @@
 use crate::bitmask::Bitmask;
 use crate::fvecs::FlattenedVecs;
-use crate::predicate::PredicateQuery;
+use crate::predicate::{PredicateQuery, PredicateRhs};
 
 use anyhow::Result;
+use std::cmp::Ordering;
+use std::iter::Sum;
+use std::mem::size_of;
+use std::ops::{Add, AddAssign, Range};
+use std::path::PathBuf;
 use thiserror::Error;
 
 /// The errors that can be returned from searching an OAK dataset.
@@ -12,10 +17,25 @@ pub enum SearchableError {
     DatasetIsNotIndexed,
     #[error("Could not serialize the predicate")]
     PredicateSerializationError,
+    #[error("The dataset has no attribute named `{0}`")]
+    UnknownAttribute(String),
+    #[error("The values of attribute `{0}` cannot be compared with the value in the predicate")]
+    AttributeTypeMismatch(String),
     #[error("Underlying C++ error: {0}")]
     CppError(String),
+    #[error("The batch has {queries} query vectors but {filters} filters")]
+    BatchSizeMismatch { queries: usize, filters: usize },
+    #[error("The dataset has {vectors} vectors, but attributes for {attributes}")]
+    SizeMismatch { vectors: usize, attributes: usize },
+    #[error("The dataset has vectors of dimensionality {expected}, but the queries have {found}")]
+    DimensionalityMismatch { expected: usize, found: usize },
+    #[error("The dataset has {vectors} vectors, but a mask over {mask}")]
+    MaskSizeMismatch { vectors: usize, mask: usize },
+    #[error("{k} neighbours for each of {queries} queries are too many to return")]
+    TooManyResults { k: usize, queries: usize },
 }
 
+#[cfg(feature = "hnsw_faiss")]
 impl From<cxx::Exception> for SearchableError {
     fn from(err: cxx::Exception) -> Self {
         // Customize the conversion logic as needed
@@ -25,7 +45,125 @@ impl From<cxx::Exception> for SearchableError {
 
 /// The errors that can be returned from constructing an OAK dataset.
 #[derive(Error, Debug)]
-pub enum ConstructionError {}
+pub enum ConstructionError {
+    #[error("Could not save or load the index at {path}: {reason}")]
+    IndexFileError { path: PathBuf, reason: String },
+    #[error("The index on disk has {found_count} vectors of dimensionality {found_dimensionality}, but the dataset has {expected_count} vectors of dimensionality {expected_dimensionality}")]
+    IndexMismatch {
+        expected_count: usize,
+        expected_dimensionality: usize,
+        found_count: usize,
+        found_dimensionality: usize,
+    },
+    #[error(
+        "The index at {path} was built over a different set of vectors than it is being loaded for"
+    )]
+    IndexVectorsMismatch { path: PathBuf },
+    #[error("Underlying C++ error: {0}")]
+    CppError(String),
+    #[error("The dataset has {vectors} vectors, but attributes for {attributes}")]
+    SizeMismatch { vectors: usize, attributes: usize },
+    #[error("An index cannot be built over a dataset without any vectors")]
+    EmptyDataset,
+    #[error("The dataset already has an attribute named `{0}`")]
+    DuplicateAttribute(String),
+    #[error("ACORN is built over 32-bit integers, but attribute `{name}` has the value {value}")]
+    AcornAttributeOutOfRange { name: String, value: i64 },
+}
+
+#[cfg(feature = "hnsw_faiss")]
+impl From<cxx::Exception> for ConstructionError {
+    fn from(err: cxx::Exception) -> Self {
+        ConstructionError::CppError(err.to_string())
+    }
+}
+
+/// Checks that an index can be built over `flattened`, with one row of `metadata` for each vector.
+pub(crate) fn check_index_input(
+    flattened: &FlattenedVecs,
+    metadata: &HybridSearchMetadata,
+) -> Result<(), ConstructionError> {
+    if flattened.is_empty() {
+        return Err(ConstructionError::EmptyDataset);
+    }
+    if flattened.len() != metadata.len() {
+        return Err(ConstructionError::SizeMismatch {
+            vectors: flattened.len(),
+            attributes: metadata.len(),
+        });
+    }
+    Ok(())
+}
+
+/// How one query in a batch passed to `SimilaritySearchable::search_batch` is filtered.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryFilter {
+    /// Every vector can be returned.
+    Unfiltered,
+    /// Only vectors that match the predicate can be returned.
+    Predicate(PredicateQuery),
+    /// Only vectors that are set in the mask can be returned. As for `search_with_bitmask`, the
+    /// mask is over the vectors of the base dataset.
+    Bitmask(Bitmask),
+}
+
+impl From<Option<PredicateQuery>> for QueryFilter {
+    fn from(predicate_query: Option<PredicateQuery>) -> Self {
+        match predicate_query {
+            Some(pq) => QueryFilter::Predicate(pq),
+            None => QueryFilter::Unfiltered,
+        }
+    }
+}
+
+impl From<PredicateQuery> for QueryFilter {
+    fn from(pq: PredicateQuery) -> Self {
+        QueryFilter::Predicate(pq)
+    }
+}
+
+impl From<Bitmask> for QueryFilter {
+    fn from(mask: Bitmask) -> Self {
+        QueryFilter::Bitmask(mask)
+    }
+}
+
+/// Checks that a batch passed to `SimilaritySearchable::search_batch` has one filter for each
+/// query vector.
+pub(crate) fn check_batch_size<F>(
+    query_vectors: &FlattenedVecs,
+    filters: &[F],
+) -> Result<(), SearchableError> {
+    if filters.len() != query_vectors.len() {
+        return Err(SearchableError::BatchSizeMismatch {
+            queries: query_vectors.len(),
+            filters: filters.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Checks that the query vectors passed to a search have the `dimensionality` of the vectors that
+/// they are searched against, and that the batch holds a whole number of them.
+pub(crate) fn check_query_dimensionality(
+    query_vectors: &FlattenedVecs,
+    dimensionality: usize,
+) -> Result<(), SearchableError> {
+    if query_vectors.dimensionality != dimensionality {
+        return Err(SearchableError::DimensionalityMismatch {
+            expected: dimensionality,
+            found: query_vectors.dimensionality,
+        });
+    }
+    // A batch that ends part way through a vector has a last query of the wrong dimensionality.
+    if !query_vectors.data.len().is_multiple_of(dimensionality) {
+        return Err(SearchableError::DimensionalityMismatch {
+            expected: dimensionality,
+            found: query_vectors.data.len() % dimensionality,
+        });
+    }
+    Ok(())
+}
 
 /// t[0] is the index of the vector that is similar in the dataset, t[1] is a f32 representing the
 /// distance of the found vector from the original query.
@@ -37,49 +175,259 @@ pub type TopKSearchResult = Vec<SimilaritySearchResult>;
 // A batch of items with type `TopKSearchResult`.
 pub type TopKSearchResultBatch = Vec<TopKSearchResult>;
 
-/// The type in which the attributes for hybrid search are notated. At the moment the assumed
-/// constraint is that there is at most one attribute per vector, and it is always an i32.
+/// The values of one attribute across all vectors in a dataset. The type of a column is inferred
+/// when it is loaded: a column is `Int` if all of its values are integers, `Float` if they are all
+/// numbers, and `Text` otherwise.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeColumn {
+    Int(Vec<i64>),
+    Float(Vec<f64>),
+    Text(Vec<String>),
+}
+
+impl AttributeColumn {
+    pub fn len(&self) -> usize {
+        match self {
+            AttributeColumn::Int(values) => values.len(),
+            AttributeColumn::Float(values) => values.len(),
+            AttributeColumn::Text(values) => values.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether values of this column can be compared with `rhs`. Numbers can be compared with
+    /// each other regardless of whether they are integers, but text only with text.
+    pub fn is_comparable(&self, rhs: &PredicateRhs) -> bool {
+        matches!(
+            (self, rhs),
+            (
+                AttributeColumn::Int(_) | AttributeColumn::Float(_),
+                PredicateRhs::Number(_) | PredicateRhs::Float(_)
+            ) | (AttributeColumn::Text(_), PredicateRhs::Text(_))
+        )
+    }
+
+    /// Compares the value of the `i`th vector with `rhs`. Returns `None` if the two cannot be
+    /// compared, either because their types differ or because one of them is NaN.
+    pub fn compare(&self, i: usize, rhs: &PredicateRhs) -> Option<Ordering> {
+        match (self, rhs) {
+            (AttributeColumn::Int(values), PredicateRhs::Number(n)) => Some(values[i].cmp(n)),
+            (AttributeColumn::Int(values), PredicateRhs::Float(f)) => {
+                (values[i] as f64).partial_cmp(f)
+            }
+            (AttributeColumn::Float(values), PredicateRhs::Number(n)) => {
+                values[i].partial_cmp(&(*n as f64))
+            }
+            (AttributeColumn::Float(values), PredicateRhs::Float(f)) => values[i].partial_cmp(f),
+            (AttributeColumn::Text(values), PredicateRhs::Text(s)) => {
+                Some(values[i].as_str().cmp(s))
+            }
+            _ => None,
+        }
+    }
+
+    /// The value of the `i`th vector, as it would be written on the right-hand side of a
+    /// predicate.
+    pub fn value(&self, i: usize) -> PredicateRhs {
+        match self {
+            AttributeColumn::Int(values) => PredicateRhs::Number(values[i]),
+            AttributeColumn::Float(values) => PredicateRhs::Float(values[i]),
+            AttributeColumn::Text(values) => PredicateRhs::Text(values[i].clone()),
+        }
+    }
+
+    /// The number of bytes used by the values of the column.
+    fn memory_usage(&self) -> usize {
+        match self {
+            AttributeColumn::Int(values) => values.capacity() * size_of::<i64>(),
+            AttributeColumn::Float(values) => values.capacity() * size_of::<f64>(),
+            AttributeColumn::Text(values) => {
+                values.capacity() * size_of::<String>()
+                    + values.iter().map(|value| value.capacity()).sum::<usize>()
+            }
+        }
+    }
+
+    fn filter_via_bitmask(&self, mask: &Bitmask) -> Self {
+        fn keep<T: Clone>(values: &[T], mask: &Bitmask) -> Vec<T> {
+            mask.iter_ones().map(|i| values[i].clone()).collect()
+        }
+
+        match self {
+            AttributeColumn::Int(values) => AttributeColumn::Int(keep(values, mask)),
+            AttributeColumn::Float(values) => AttributeColumn::Float(keep(values, mask)),
+            AttributeColumn::Text(values) => AttributeColumn::Text(keep(values, mask)),
+        }
+    }
+}
+
+/// The attributes over the vectors in a dataset, over which predicates for hybrid search are
+/// evaluated. Each attribute is a named, typed column with one value per vector.
+#[derive(Clone)]
 pub struct HybridSearchMetadata {
-    attrs: Vec<i32>,
+    names: Vec<String>,
+    columns: Vec<AttributeColumn>,
+    len: usize,
 }
 
 impl HybridSearchMetadata {
-    pub fn new(attrs: Vec<i32>) -> Self {
-        Self { attrs }
+    /// Creates metadata from named columns, which must all have the same length.
+    pub fn new(names: Vec<String>, columns: Vec<AttributeColumn>) -> Self {
+        assert_eq!(names.len(), columns.len());
+        let len = columns.first().map_or(0, |column| column.len());
+        assert!(columns.iter().all(|column| column.len() == len));
+
+        Self {
+            names,
+            columns,
+            len,
+        }
     }
 
-    pub fn new_from_bitmask(other: &Self, mask: &Bitmask) -> Self {
-        let filtered_attrs: Vec<i32> = other
-            .attrs
-            .iter()
-            .zip(mask.map.iter())
-            .filter_map(|(&attr, &keep)| {
-                if keep == 1 {
-                    Some(attr) // Keep the attribute if the bitmask allows
-                } else {
-                    None
-                }
-            })
-            .collect();
+    /// Creates metadata for `len` vectors that have no attributes.
+    pub fn without_attributes(len: usize) -> Self {
+        Self {
+            names: vec![],
+            columns: vec![],
+            len,
+        }
+    }
 
+    pub fn new_from_bitmask(other: &Self, mask: &Bitmask) -> Self {
         HybridSearchMetadata {
-            attrs: filtered_attrs,
+            names: other.names.clone(),
+            columns: other
+                .columns
+                .iter()
+                .map(|column| column.filter_via_bitmask(mask))
+                .collect(),
+            len: mask.bitcount(),
         }
     }
 
     pub fn len(&self) -> usize {
-        self.attrs.len()
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Adds an attribute after the existing ones. The column must have a value for every vector,
+    /// and its name must not already be used by another attribute.
+    pub fn push_column(
+        &mut self,
+        name: String,
+        column: AttributeColumn,
+    ) -> Result<(), ConstructionError> {
+        if self.names.contains(&name) {
+            return Err(ConstructionError::DuplicateAttribute(name));
+        }
+        if column.len() != self.len {
+            return Err(ConstructionError::SizeMismatch {
+                vectors: self.len,
+                attributes: column.len(),
+            });
+        }
+        self.names.push(name);
+        self.columns.push(column);
+        Ok(())
+    }
+
+    /// The names of the attributes, in the order of their columns.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Looks up the column for an attribute by name. Columns can also be referred to by their
+    /// position as `attr0`, `attr1`, and so on, unless another column has that name.
+    pub fn column(&self, name: &str) -> Option<&AttributeColumn> {
+        if let Some(i) = self.names.iter().position(|n| n == name) {
+            return self.columns.get(i);
+        }
+        name.strip_prefix("attr")
+            .and_then(|i| i.parse::<usize>().ok())
+            .and_then(|i| self.columns.get(i))
+    }
+
+    /// The number of bytes used by the names and values of the attributes.
+    pub fn memory_usage(&self) -> usize {
+        let names: usize = self.names.iter().map(|name| name.capacity()).sum();
+        let columns: usize = self
+            .columns
+            .iter()
+            .map(|column| column.memory_usage())
+            .sum();
+        names + columns
+    }
+
+    /// ACORN is constructed with a single integer attribute per vector. We pass it the first
+    /// column if that holds integers, and zeros otherwise. A value that does not fit in an `i32`
+    /// is an error, rather than being truncated into a different value.
+    pub fn acorn_attrs(&self) -> Result<Vec<i32>, ConstructionError> {
+        match self.columns.first() {
+            Some(AttributeColumn::Int(values)) => values
+                .iter()
+                .map(|&value| {
+                    i32::try_from(value).map_err(|_| ConstructionError::AcornAttributeOutOfRange {
+                        name: self.names[0].clone(),
+                        value,
+                    })
+                })
+                .collect(),
+            _ => Ok(vec![0; self.len]),
+        }
+    }
+}
+
+/// The memory used by a dataset or an index, in bytes, broken down by what it is used for. Only
+/// memory that is allocated on the heap is counted, not memory-mapped files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// The vectors, including any copies of them held by an index.
+    pub vectors: usize,
+    /// The graph of an index.
+    pub graph: usize,
+    /// The attributes of the vectors, and bookkeeping such as masks and maps between ids.
+    pub metadata: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.vectors + self.graph + self.metadata
+    }
+}
+
+impl Add for MemoryUsage {
+    type Output = MemoryUsage;
+
+    fn add(self, other: MemoryUsage) -> MemoryUsage {
+        MemoryUsage {
+            vectors: self.vectors + other.vectors,
+            graph: self.graph + other.graph,
+            metadata: self.metadata + other.metadata,
+        }
     }
 }
 
-impl AsRef<Vec<i32>> for HybridSearchMetadata {
-    fn as_ref(&self) -> &Vec<i32> {
-        self.attrs.as_ref()
+impl AddAssign for MemoryUsage {
+    fn add_assign(&mut self, other: MemoryUsage) {
+        *self = *self + other;
+    }
+}
+
+impl Sum for MemoryUsage {
+    fn sum<I: Iterator<Item = MemoryUsage>>(iter: I) -> MemoryUsage {
+        iter.fold(MemoryUsage::default(), Add::add)
     }
 }
 
 /// These parameters are currently essentially ACORN parameters, taken from
 /// https://github.com/csirianni/ACORN/blob/main/README.md
+#[derive(Clone, Copy, Debug)]
 pub struct OakIndexOptions {
     /// Degree bound for traversed nodes during ACORN search
     pub m: i32,
@@ -100,22 +448,91 @@ impl Default for OakIndexOptions {
     }
 }
 
-/// Trait for a dataset of vectors.
+/// A source of the vectors of a dataset, such as a file in one of the formats that ANN benchmarks
+/// are distributed in. Vectors are always exposed as `f32`s, so sources that store another type
+/// convert their vectors as they are loaded.
+pub trait DatasetSource: Send + Sync {
+    /// The number of vectors.
+    fn len(&self) -> usize;
 
-pub trait SimilaritySearchable {
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The dimensionality of every vector.
+    fn dimensionality(&self) -> usize;
+
+    /// The vector at position `i`.
+    fn get(&self, i: usize) -> &[f32];
+
+    /// The number of bytes used by the vectors on the heap. Memory-mapped files are not counted.
+    fn memory_usage(&self) -> usize;
+
+    /// Copies the vectors at the positions in `range` into a contiguous buffer, as is needed to
+    /// pass them over FFI.
+    fn to_flattened_range(&self, range: Range<usize>) -> FlattenedVecs {
+        let mut data = Vec::with_capacity(range.len() * self.dimensionality());
+        for i in range {
+            data.extend_from_slice(self.get(i));
+        }
+        FlattenedVecs {
+            dimensionality: self.dimensionality(),
+            data,
+        }
+    }
+
+    /// Copies all of the vectors into a contiguous buffer.
+    fn to_flattened(&self) -> FlattenedVecs {
+        self.to_flattened_range(0..self.len())
+    }
+
+    /// Copies only the vectors that are set in `bitmask` into a contiguous buffer, in order.
+    fn to_flattened_via_bitmask(&self, bitmask: &Bitmask) -> FlattenedVecs {
+        let mut data = Vec::with_capacity(bitmask.bitcount() * self.dimensionality());
+        for i in bitmask.iter_ones() {
+            data.extend_from_slice(self.get(i));
+        }
+        FlattenedVecs {
+            dimensionality: self.dimensionality(),
+            data,
+        }
+    }
+}
+
+/// Trait for a dataset of vectors. Datasets are shared between the threads that serve queries
+/// and those that build indexes, so must be `Send + Sync`.
+pub trait SimilaritySearchable: Send + Sync {
     /// Provide the number of vectors that have been added to the dataset.
     fn len(&self) -> usize;
 
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Provide the dimensionality of the vectors in the dataset.
     fn get_dimensionality(&self) -> usize;
 
     /// Get the metadata that represents the attributes over the vectors (for hybrid search).
     fn get_metadata(&self) -> &HybridSearchMetadata;
 
+    /// The predicate that selected the vectors of this dataset from a larger one, if it is a
+    /// partition that was built from a predicate.
+    fn get_predicate(&self) -> Option<&PredicateQuery> {
+        None
+    }
+
+    /// The vectors of the larger dataset that this dataset holds, if it is a partition of one.
+    fn mask(&self) -> Option<&Bitmask> {
+        None
+    }
+
     /// Build the index associated with this dataset. If an index has not been built, all search
     /// methods will throw an error.
     fn initialize(&mut self, opts: &OakIndexOptions) -> Result<(), ConstructionError>;
 
+    /// The memory used by the dataset and its index, if it has been built.
+    fn memory_usage(&self) -> MemoryUsage;
+
     /// Takes a Vec<Fvec> and returns a Vec<Vec<(usize, f32)>>, whereby each inner Vec<(usize, f32)> is an array
     /// of tuples in which t[0] is the index of the resthe `topk` vectors returned from the result.
     fn search(
@@ -128,6 +545,9 @@ pub trait SimilaritySearchable {
 
     /// Takes a Vec<Fvec> and returns a Vec<Vec<(usize, f32)>>, whereby each inner Vec<(usize, f32)> is an array
     /// of tuples in which t[0] is the index of the resthe `topk` vectors returned from the result.
+    ///
+    /// `bitmask` is always over the vectors of the base dataset, even when searching a partition
+    /// of it, in which case the partition translates it into its own id space.
     fn search_with_bitmask(
         &self,
         query_vectors: &FlattenedVecs,
@@ -135,4 +555,41 @@ pub trait SimilaritySearchable {
         topk: usize,
         efsearch: i64,
     ) -> Result<Vec<TopKSearchResult>, SearchableError>;
+
+    /// Searches a batch of queries, each with its own filter in `filters`, which must have one
+    /// entry for each query vector. Returns one result list for each query, in the same order.
+    ///
+    /// By default each query is searched on its own. Datasets with an index should override this
+    /// to search the whole batch at once.
+    fn search_batch(
+        &self,
+        query_vectors: &FlattenedVecs,
+        filters: &[QueryFilter],
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        check_batch_size(query_vectors, filters)?;
+
+        query_vectors
+            .data
+            .chunks_exact(query_vectors.dimensionality)
+            .zip(filters)
+            .map(|(query, filter)| {
+                let query_vector = FlattenedVecs {
+                    dimensionality: query_vectors.dimensionality,
+                    data: query.to_vec(),
+                };
+                let mut results = match filter {
+                    QueryFilter::Unfiltered => self.search(&query_vector, &None, topk, efsearch),
+                    QueryFilter::Predicate(pq) => {
+                        self.search(&query_vector, &Some(pq.clone()), topk, efsearch)
+                    }
+                    QueryFilter::Bitmask(mask) => {
+                        self.search_with_bitmask(&query_vector, mask, topk, efsearch)
+                    }
+                }?;
+                Ok(results.pop().unwrap_or_default())
+            })
+            .collect()
+    }
 }
@@ -1,60 +1,348 @@
-use crate::acorn::AcornHnswIndex;
+#[cfg(feature = "hnsw_faiss")]
+use crate::acorn::AcornHnswIndex as HnswIndex;
 use crate::bitmask::Bitmask;
 use crate::dataset::{
-    ConstructionError, HybridSearchMetadata, OakIndexOptions, SearchableError,
-    SimilaritySearchable, TopKSearchResult,
+    check_batch_size, AttributeColumn, ConstructionError, DatasetSource, HybridSearchMetadata,
+    MemoryUsage, OakIndexOptions, QueryFilter, SearchableError, SimilaritySearchable,
+    TopKSearchResult,
 };
+use crate::groundtruth;
+#[cfg(feature = "hnsw_rust")]
+use crate::hnsw::RustHnswIndex as HnswIndex;
+use crate::npy;
 use crate::predicate::PredicateQuery;
 use slog_scope::debug;
 
 use anyhow::Result;
 use byteorder::{ByteOrder, LittleEndian};
-use csv::ReaderBuilder;
+use csv::{ReaderBuilder, Writer};
 use memmap2::Mmap;
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use thiserror::Error;
 
 const FOUR_BYTES: usize = std::mem::size_of::<f32>();
 
-fn read_csv_to_vec(file_path: &PathBuf) -> Result<Vec<i32>> {
+/// The errors that can be returned from reading a .fvecs file, or a .bvecs or .ivecs file, which
+/// share its layout. Offsets are in bytes from the start of the file, and records are numbered
+/// from 0.
+#[derive(Error, Debug, PartialEq)]
+pub enum FvecsError {
+    #[error("The file is empty")]
+    Empty,
+    #[error("The first record, at byte 0, has dimensionality 0")]
+    ZeroDimensionality,
+    #[error("Record {record}, at byte {offset}, has dimensionality {found}, but the first record has dimensionality {expected}")]
+    DimensionalityMismatch {
+        record: usize,
+        offset: usize,
+        expected: usize,
+        found: usize,
+    },
+    #[error("Record {record}, at byte {offset}, is truncated: it should be {expected} bytes, but the file ends after {found}")]
+    Truncated {
+        record: usize,
+        offset: usize,
+        expected: usize,
+        found: usize,
+    },
+    #[error("Record {record} has a value that is not finite, {value}, at byte {offset}")]
+    NonFinite {
+        record: usize,
+        offset: usize,
+        value: f32,
+    },
+    #[error("{0} bytes cannot be read as `f32`s, as that is not a multiple of 4")]
+    InvalidLength(usize),
+    #[error("{} is not a .fvecs, .bvecs, .npy or .npz file", .0.display())]
+    UnsupportedFormat(PathBuf),
+}
+
+/// How thoroughly a .fvecs file is checked as it is loaded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FvecsValidation {
+    /// Every record must have the dimensionality of the first, and the file must hold a whole
+    /// number of records. This only reads the header of each record.
+    #[default]
+    Headers,
+    /// As for `Headers`, and every value must also be finite. This reads the whole file.
+    Strict,
+}
+
+/// Checks that `bytes` holds a well-formed .fvecs file, returning the number of vectors in it and
+/// their dimensionality.
+pub fn validate_fvecs(
+    bytes: &[u8],
+    validation: FvecsValidation,
+) -> Result<(usize, usize), FvecsError> {
+    validate_records(bytes, FOUR_BYTES, validation == FvecsValidation::Strict)
+}
+
+/// Checks that `bytes` holds records in the layout shared by .fvecs, .bvecs and .ivecs files: a
+/// 4-byte little-endian dimensionality, followed by that many values of `value_len` bytes each.
+/// If `check_finite` is set, the values are `f32`s, each of which must be finite. Returns the
+/// number of records and their dimensionality.
+fn validate_records(
+    bytes: &[u8],
+    value_len: usize,
+    check_finite: bool,
+) -> Result<(usize, usize), FvecsError> {
+    if bytes.is_empty() {
+        return Err(FvecsError::Empty);
+    }
+    let header = |offset: usize| {
+        (bytes.len() - offset >= FOUR_BYTES)
+            .then(|| LittleEndian::read_u32(&bytes[offset..offset + FOUR_BYTES]) as usize)
+    };
+
+    let dimensionality = header(0).ok_or(FvecsError::Truncated {
+        record: 0,
+        offset: 0,
+        expected: FOUR_BYTES,
+        found: bytes.len(),
+    })?;
+    if dimensionality == 0 {
+        return Err(FvecsError::ZeroDimensionality);
+    }
+    let record_len = FOUR_BYTES + dimensionality * value_len;
+
+    // Records are walked one at a time, rather than counted by dividing the length of the file,
+    // so that a record with the wrong dimensionality is reported where it starts.
+    let mut count = 0;
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let truncated = || FvecsError::Truncated {
+            record: count,
+            offset,
+            expected: record_len,
+            found: bytes.len() - offset,
+        };
+        let found = header(offset).ok_or_else(truncated)?;
+        if found != dimensionality {
+            return Err(FvecsError::DimensionalityMismatch {
+                record: count,
+                offset,
+                expected: dimensionality,
+                found,
+            });
+        }
+        if bytes.len() - offset < record_len {
+            return Err(truncated());
+        }
+
+        if check_finite {
+            let values = &bytes[offset + FOUR_BYTES..offset + record_len];
+            for (i, chunk) in values.chunks_exact(FOUR_BYTES).enumerate() {
+                let value = LittleEndian::read_f32(chunk);
+                if !value.is_finite() {
+                    return Err(FvecsError::NonFinite {
+                        record: count,
+                        offset: offset + (1 + i) * FOUR_BYTES,
+                        value,
+                    });
+                }
+            }
+        }
+
+        offset += record_len;
+        count += 1;
+    }
+
+    Ok((count, dimensionality))
+}
+
+/// Infers the type of a column from its values, as described on `AttributeColumn`.
+fn parse_column(values: Vec<String>) -> AttributeColumn {
+    if let Ok(ints) = values.iter().map(|v| v.parse::<i64>()).collect() {
+        AttributeColumn::Int(ints)
+    } else if let Ok(floats) = values.iter().map(|v| v.parse::<f64>()).collect() {
+        AttributeColumn::Float(floats)
+    } else {
+        AttributeColumn::Text(values)
+    }
+}
+
+/// How the attributes of a dataset are loaded from the "{fname}.csv" alongside its vectors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttributeCsv {
+    /// The dataset has no attributes, and no CSV is read.
+    Skip,
+    /// The first row of the CSV is a header naming the columns.
+    Header,
+    /// Every row of the CSV holds the attributes of a vector, and the columns are named `attr0`,
+    /// `attr1`, and so on.
+    NoHeader,
+}
+
+impl AttributeCsv {
+    /// Loads the CSV, with or without a header, such as when that is chosen by a flag.
+    pub fn with_header(header: bool) -> Self {
+        if header {
+            AttributeCsv::Header
+        } else {
+            AttributeCsv::NoHeader
+        }
+    }
+}
+
+/// Reads the attributes for a dataset from a CSV, in which each row holds the attributes of the
+/// vector at the same index. If `has_header` is set, the first row names the columns; otherwise,
+/// the columns are named `attr0`, `attr1`, and so on.
+fn read_csv_to_metadata(file_path: &Path, has_header: bool) -> Result<HybridSearchMetadata> {
     // Open the file
     let file = File::open(file_path)?;
 
-    // Create a CSV reader
-    let mut reader = ReaderBuilder::new()
-        .has_headers(false) // No headers in this example
-        .from_reader(file);
+    // Create a CSV reader. The header, if there is one, is read as the first record so that it is
+    // handled the same way as a row of positional names.
+    let mut reader = ReaderBuilder::new().has_headers(false).from_reader(file);
+    let mut records = reader.records();
+
+    let first = match records.next() {
+        Some(record) => record?,
+        None => return Ok(HybridSearchMetadata::new(vec![], vec![])),
+    };
 
-    // Collect integers from the CSV
-    let mut numbers = Vec::new();
-    for result in reader.records() {
+    let names: Vec<String> = if has_header {
+        first.iter().map(|field| field.trim().to_string()).collect()
+    } else {
+        (0..first.len()).map(|i| format!("attr{i}")).collect()
+    };
+
+    let mut values: Vec<Vec<String>> = vec![vec![]; names.len()];
+    let data_records = std::iter::once(Ok(first))
+        .filter(|_| !has_header)
+        .chain(records);
+    for result in data_records {
         let record = result?;
-        if let Some(field) = record.get(0) {
-            // Assuming a single column
-            numbers.push(field.parse::<i32>()?);
+        for (column, field) in values.iter_mut().zip(record.iter()) {
+            column.push(field.trim().to_string());
         }
     }
 
-    debug!("{} attributes loaded from CSV.", numbers.len());
+    let columns: Vec<AttributeColumn> = values.into_iter().map(parse_column).collect();
+    let metadata = HybridSearchMetadata::new(names, columns);
+    debug!(
+        "{} attributes loaded from CSV for {} vectors: {:?}.",
+        metadata.names().len(),
+        metadata.len(),
+        metadata.names()
+    );
 
-    Ok(numbers)
+    Ok(metadata)
+}
+
+/// Writes the attributes of a dataset to a CSV that `read_csv_to_metadata` reads back, with a
+/// header naming the columns and a row for each vector.
+pub fn write_csv_from_metadata(path: &Path, metadata: &HybridSearchMetadata) -> Result<()> {
+    let mut writer = Writer::from_path(path)?;
+    writer.write_record(metadata.names())?;
+    let columns: Vec<&AttributeColumn> = metadata
+        .names()
+        .iter()
+        .map(|name| metadata.column(name).unwrap())
+        .collect();
+    for i in 0..metadata.len() {
+        // Floats are written with their decimal point, so that a column of floats that happen to
+        // be whole is not read back as integers.
+        writer.write_record(columns.iter().map(|column| match column {
+            AttributeColumn::Int(values) => values[i].to_string(),
+            AttributeColumn::Float(values) => format!("{:?}", values[i]),
+            AttributeColumn::Text(values) => values[i].clone(),
+        }))?;
+    }
+    writer.flush()?;
+    debug!(
+        "{} attributes written to CSV for {} vectors.",
+        metadata.names().len(),
+        metadata.len()
+    );
+
+    Ok(())
+}
+
+/// The file next to a saved index that holds the fingerprint of the mask of the vectors that the
+/// index was built over.
+fn fingerprint_path(path: &Path) -> PathBuf {
+    let mut fname = path.as_os_str().to_owned();
+    fname.push(".mask");
+    PathBuf::from(fname)
+}
+
+/// Saves `index` to `path`, along with the fingerprint of `mask`, the vectors it was built over.
+fn save_index_with_mask(
+    index: &HnswIndex,
+    path: &Path,
+    mask: &Bitmask,
+) -> Result<(), ConstructionError> {
+    index.save_index(path)?;
+    let fingerprint_path = fingerprint_path(path);
+    std::fs::write(&fingerprint_path, format!("{:016x}", mask.fingerprint())).map_err(|e| {
+        ConstructionError::IndexFileError {
+            path: fingerprint_path,
+            reason: e.to_string(),
+        }
+    })
+}
+
+/// Loads a previously saved index from `path`, checking that it was built over the same number of
+/// vectors, with the same dimensionality, as `dataset`, and over the vectors in `mask`. The
+/// vectors are compared by the fingerprint saved next to the index, so that an index for one
+/// partition is not loaded for another that happens to be the same size.
+fn load_matching_index<D: SimilaritySearchable>(
+    dataset: &D,
+    path: &Path,
+    mask: &Bitmask,
+) -> Result<HnswIndex, ConstructionError> {
+    let index = HnswIndex::load_index(path)?;
+    if index.len() != dataset.len() || index.get_dimensionality() != dataset.get_dimensionality() {
+        return Err(ConstructionError::IndexMismatch {
+            expected_count: dataset.len(),
+            expected_dimensionality: dataset.get_dimensionality(),
+            found_count: index.len(),
+            found_dimensionality: index.get_dimensionality(),
+        });
+    }
+    let fingerprint_path = fingerprint_path(path);
+    let fingerprint = std::fs::read_to_string(&fingerprint_path).map_err(|e| {
+        ConstructionError::IndexFileError {
+            path: fingerprint_path,
+            reason: e.to_string(),
+        }
+    })?;
+    if u64::from_str_radix(fingerprint.trim(), 16) != Ok(mask.fingerprint()) {
+        return Err(ConstructionError::IndexVectorsMismatch {
+            path: path.to_path_buf(),
+        });
+    }
+    debug!(
+        "Skipping index construction, as a matching index was found at {}.",
+        path.display()
+    );
+    Ok(index)
 }
 
 /// Converts a slice of `u8` into a `Vec<f32>` assuming little-endian format.
 ///
-/// # Panics
-/// - Panics if the length of the input slice is not a multiple of 4.
+/// # Errors
+/// - Returns `FvecsError::InvalidLength` if the length of the input slice is not a multiple of 4.
 ///
 /// # Parameters
 /// - `data`: A byte slice containing the raw `f32` data.
 ///
 /// # Returns
 /// A vector of `f32` values parsed from the byte slice.
-pub fn parse_u8_to_f32(data: &[u8]) -> Vec<f32> {
-    assert!(
-        data.len() % 4 == 0,
-        "Input data length must be a multiple of 4"
-    );
+pub fn parse_u8_to_f32(data: &[u8]) -> Result<Vec<f32>, FvecsError> {
+    if !data.len().is_multiple_of(FOUR_BYTES) {
+        return Err(FvecsError::InvalidLength(data.len()));
+    }
 
     let mut result = Vec::with_capacity(data.len() / FOUR_BYTES);
 
@@ -63,7 +351,7 @@ pub fn parse_u8_to_f32(data: &[u8]) -> Vec<f32> {
         result.push(value);
     }
 
-    result
+    Ok(result)
 }
 
 #[allow(dead_code)]
@@ -84,6 +372,15 @@ impl FlattenedVecs {
         self.data.len() / self.dimensionality
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// The number of bytes used by the vectors.
+    pub fn memory_usage(&self) -> usize {
+        self.data.capacity() * FOUR_BYTES
+    }
+
     pub fn to_vec(self) -> Vec<FlattenedVecs> {
         self.data
             .chunks(self.dimensionality)
@@ -97,18 +394,10 @@ impl FlattenedVecs {
     /// Creates a new FlattenedVecs based on a bitmask and an original one.
     /// Only the necessary items (items that match the bitmask) are copied.
     pub fn clone_via_bitmask(&self, bitmask: &Bitmask) -> Self {
-        let new_data: Vec<f32> = self
-            .data
-            .chunks_exact(self.dimensionality)
-            .zip(bitmask.map.iter())
-            .filter_map(|(vector, &keep)| {
-                if keep == 1 {
-                    Some(vector) // Keep the vector if bitmask says so
-                } else {
-                    None
-                }
-            })
-            .flat_map(|vector| vector.iter().copied())
+        let new_data: Vec<f32> = bitmask
+            .iter_ones()
+            .flat_map(|i| &self.data[i * self.dimensionality..(i + 1) * self.dimensionality])
+            .copied()
             .collect();
 
         Self {
@@ -122,69 +411,99 @@ impl FlattenedVecs {
     /// representation). This is necessary reformatting for calling ACORN methods via FFI, and the
     /// transformation also ensures that the vectors are in memory (rather than on disk).
     pub fn read_from_mmap(mmap: &Mmap, count: usize, dimensionality: usize) -> Self {
-        let mut all_fvecs = Vec::with_capacity(count * dimensionality);
-        let fvec_len_in_bytes = (dimensionality + 1) * FOUR_BYTES;
-        let mut current_index = 0;
-        for i in mmap.chunks_exact(fvec_len_in_bytes) {
-            let mut fvecs = parse_u8_to_f32(&i);
-            // skip the dimensionality, we don't need it in the flattened.
-            fvecs.drain(0..1);
-            all_fvecs.splice(current_index..current_index, fvecs);
-            current_index += dimensionality;
-        }
+        FvecsView::new(mmap, dimensionality).to_flattened_range(0..count)
+    }
+}
+
+/// A view over the vectors of a .fvecs file that reads each vector in place, skipping over the
+/// dimensionality that precedes it, so that no vectors are copied until a caller asks for a copy.
+#[derive(Clone, Copy)]
+pub struct FvecsView<'a> {
+    bytes: &'a [u8],
+    count: usize,
+    dimensionality: usize,
+}
 
+impl<'a> FvecsView<'a> {
+    /// Creates a view over the .fvecs records in `bytes`, each of which holds a vector of
+    /// `dimensionality` values. Any incomplete record at the end is ignored.
+    ///
+    /// # Panics
+    /// - Panics if `bytes` is not aligned for `f32`, which memory maps always are.
+    pub fn new(bytes: &'a [u8], dimensionality: usize) -> Self {
+        assert_eq!(
+            bytes.as_ptr().align_offset(std::mem::align_of::<f32>()),
+            0,
+            "fvecs records must be aligned to be read in place"
+        );
+        let count = bytes.len() / ((1 + dimensionality) * FOUR_BYTES);
         Self {
+            bytes,
+            count,
             dimensionality,
-            data: all_fvecs,
         }
     }
-}
 
-impl From<&FvecsDataset> for FlattenedVecs {
-    fn from(dataset: &FvecsDataset) -> Self {
-        FlattenedVecs::read_from_mmap(&dataset.mmap, dataset.count, dataset.dimensionality)
+    /// The vector at position `i`, read directly from the underlying bytes.
+    pub fn get(&self, i: usize) -> &'a [f32] {
+        assert!(i < self.count, "vector {i} is out of bounds");
+        let stride = (1 + self.dimensionality) * FOUR_BYTES;
+        let start = i * stride + FOUR_BYTES;
+        let bytes = &self.bytes[start..start + self.dimensionality * FOUR_BYTES];
+        // SAFETY: every bit pattern is a valid `f32`, and the view starts at an aligned address
+        // and each record is a whole number of `f32`s long, so the vector is aligned. .fvecs files
+        // are little-endian, as is every target that OAK builds for.
+        let (prefix, values, suffix) = unsafe { bytes.align_to::<f32>() };
+        debug_assert!(prefix.is_empty() && suffix.is_empty());
+        values
+    }
+
+    /// A view over the vectors at the positions in `range`, which shares the same bytes.
+    pub fn range(&self, range: Range<usize>) -> Self {
+        assert!(range.start <= range.end && range.end <= self.count);
+        let stride = (1 + self.dimensionality) * FOUR_BYTES;
+        Self {
+            bytes: &self.bytes[range.start * stride..range.end * stride],
+            count: range.len(),
+            dimensionality: self.dimensionality,
+        }
+    }
+
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = &'a [f32]> + 'a {
+        let view = *self;
+        (0..self.count).map(move |i| view.get(i))
     }
 }
 
-/// Create a Vec<PredicateQuery> representing queries that match the specified attribute in the
-/// query vectors. The 0th element in the returned Vec, for example, will be a PredicateQuery for
-/// all vectors that match attribute X, where X is the attribute on the 0th query vector.
-impl From<&FvecsDataset> for Vec<PredicateQuery> {
-    fn from(dataset: &FvecsDataset) -> Self {
-        dataset
-            .metadata
-            .as_ref()
-            .iter()
-            // NOTE: we assume here that the attribute loaded is safe to cast to a u8, as we have
-            // generated the attributes as such. The reason that `dataset.metadata` is a u32 is
-            // because this is what the ACORN code expects (and as such should probably be changed
-            // to a u8 so that implementation details aren't leaky to higher-level abstractions).
-            .map(|x| PredicateQuery::new((*x).try_into().unwrap()))
-            .collect()
+impl DatasetSource for FvecsView<'_> {
+    fn len(&self) -> usize {
+        self.count
+    }
+
+    fn dimensionality(&self) -> usize {
+        self.dimensionality
+    }
+
+    fn get(&self, i: usize) -> &[f32] {
+        FvecsView::get(self, i)
+    }
+
+    fn memory_usage(&self) -> usize {
+        0
     }
 }
 
-/// Dataset sourced from a .fvecs file
-pub struct FvecsDataset {
-    pub mmap: Mmap,
+/// A memory-mapped .fvecs file, whose vectors are read in place.
+pub struct FvecsFile {
+    mmap: Mmap,
     count: usize,
     dimensionality: usize,
-    index: Option<AcornHnswIndex>,
-    pub metadata: HybridSearchMetadata,
-    pub flat: FlattenedVecs,
 }
 
-impl FvecsDataset {
-    /// Create a new dataset, loading all fvecs into memory. The `fname` should represent a
-    /// filename that corresponds to both a "{fname}.fvecs" that contains the vectors, and a
-    /// "{fname}.csv" that contains the attributes (over which predicates can be constructed) for
-    /// those vectors. Each row in the CSV corresponds to the vector at the same index in the fvecs
-    /// file, and each column represents an attribute on that vector.
-    pub fn new(fname: String, load_csv: bool) -> Result<Self> {
-        let mut fvecs_fname = PathBuf::new();
-        fvecs_fname.push(&format!("{}.fvecs", fname));
-
-        let f = File::open(fvecs_fname)?;
+impl FvecsFile {
+    /// Memory-maps the .fvecs file at `path`, checking it as thoroughly as `validation` says.
+    pub fn open(path: &Path, validation: FvecsValidation) -> Result<Self> {
+        let f = File::open(path)?;
 
         // SAFETY: For the purposes of our benchmarking suite, we are assuming that the underlying
         // file will not be modified throughout the duration of the program, as we control the file
@@ -198,66 +517,364 @@ impl FvecsDataset {
         // This will throw an error if RAM is not large enough.
         // let _ = mmap.lock()?;
 
-        let dimensionality = LittleEndian::read_u32(&mmap[..FOUR_BYTES]) as usize;
-        assert_eq!(
-            dimensionality,
-            LittleEndian::read_u32(&mmap[0..FOUR_BYTES]) as usize
-        );
-
         // Each fvec is a dimensionality (4 bytes) followed by `dimensionality` number of f32
         // values. Fvecs are contiguous in the file.
-        let count = &mmap[..].len() / ((1 + dimensionality) * FOUR_BYTES);
-        debug!(
-            "First dimensionality read from file is {dimensionality}; assuming the same for all remaining."
-        );
-        debug!("The file read has {count} vectors.");
+        let (count, dimensionality) = validate_fvecs(&mmap, validation)?;
+        debug!("The file read has {count} vectors of dimensionality {dimensionality}.");
 
-        let metadata = if load_csv {
-            let mut metadata_fname = PathBuf::new();
-            metadata_fname.push(&format!("{}.csv", fname));
-            let metadata_vec = read_csv_to_vec(&metadata_fname)?;
-            HybridSearchMetadata::new(metadata_vec)
-        } else {
-            // NOTE: this is a bad hack. It should really be an option
-            HybridSearchMetadata::new(vec![])
+        Ok(Self {
+            mmap,
+            count,
+            dimensionality,
+        })
+    }
+
+    pub fn view(&self) -> FvecsView<'_> {
+        FvecsView::new(&self.mmap, self.dimensionality)
+    }
+}
+
+impl DatasetSource for FvecsFile {
+    fn len(&self) -> usize {
+        self.count
+    }
+
+    fn dimensionality(&self) -> usize {
+        self.dimensionality
+    }
+
+    fn get(&self, i: usize) -> &[f32] {
+        self.view().get(i)
+    }
+
+    fn memory_usage(&self) -> usize {
+        0
+    }
+
+    fn to_flattened_range(&self, range: Range<usize>) -> FlattenedVecs {
+        self.view().to_flattened_range(range)
+    }
+
+    fn to_flattened_via_bitmask(&self, bitmask: &Bitmask) -> FlattenedVecs {
+        self.view().to_flattened_via_bitmask(bitmask)
+    }
+}
+
+impl DatasetSource for FlattenedVecs {
+    fn len(&self) -> usize {
+        FlattenedVecs::len(self)
+    }
+
+    fn dimensionality(&self) -> usize {
+        self.dimensionality
+    }
+
+    fn get(&self, i: usize) -> &[f32] {
+        &self.data[i * self.dimensionality..(i + 1) * self.dimensionality]
+    }
+
+    fn memory_usage(&self) -> usize {
+        FlattenedVecs::memory_usage(self)
+    }
+}
+
+/// Reads a .bvecs file, in which each value is a `u8`, converting the vectors to `f32`s as they are
+/// read. The file is memory-mapped, so that only the converted vectors are held in memory.
+pub fn read_bvecs(path: &Path) -> Result<FlattenedVecs> {
+    let f = File::open(path)?;
+    // SAFETY: As for `FvecsFile::open`, the file is assumed not to be modified while it is read.
+    let mmap = unsafe { Mmap::map(&f)? };
+    let (count, dimensionality) = validate_records(&mmap, 1, false)?;
+    debug!("The file read has {count} vectors of dimensionality {dimensionality}.");
+
+    let mut data = Vec::with_capacity(count * dimensionality);
+    for record in mmap.chunks_exact(FOUR_BYTES + dimensionality) {
+        data.extend(record[FOUR_BYTES..].iter().map(|&value| f32::from(value)));
+    }
+    Ok(FlattenedVecs {
+        dimensionality,
+        data,
+    })
+}
+
+/// Reads a .ivecs file, in which each value is an `i32`, such as the groundtruth of an ANN
+/// benchmark, which holds the ids of the nearest neighbours of each query.
+pub fn read_ivecs(path: &Path) -> Result<Vec<Vec<i32>>> {
+    let bytes = std::fs::read(path)?;
+    let (count, dimensionality) = validate_records(&bytes, FOUR_BYTES, false)?;
+    debug!("The file read has {count} vectors of dimensionality {dimensionality}.");
+
+    Ok(bytes
+        .chunks_exact((1 + dimensionality) * FOUR_BYTES)
+        .map(|record| {
+            record[FOUR_BYTES..]
+                .chunks_exact(FOUR_BYTES)
+                .map(LittleEndian::read_i32)
+                .collect()
+        })
+        .collect())
+}
+
+/// Writes records in the layout shared by .fvecs and .ivecs files, each prefixed by its
+/// dimensionality. All records must have the same dimensionality.
+fn write_records<'a, T: Copy + 'a>(
+    path: &Path,
+    records: impl IntoIterator<Item = &'a [T]>,
+    to_le_bytes: fn(T) -> [u8; FOUR_BYTES],
+) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let mut dimensionality = None;
+    let mut count = 0;
+    for record in records {
+        let expected = *dimensionality.get_or_insert(record.len());
+        if record.len() != expected {
+            return Err(FvecsError::DimensionalityMismatch {
+                record: count,
+                offset: count * (1 + expected) * FOUR_BYTES,
+                expected,
+                found: record.len(),
+            }
+            .into());
+        }
+        writer.write_all(&(record.len() as u32).to_le_bytes())?;
+        for &value in record {
+            writer.write_all(&to_le_bytes(value))?;
+        }
+        count += 1;
+    }
+    writer.flush()?;
+    debug!(
+        "Wrote {count} vectors of dimensionality {} to {}.",
+        dimensionality.unwrap_or(0),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Writes `vectors` to a .fvecs file, overwriting `path` if it exists. The vectors are written as
+/// they are iterated, so they can be streamed from a memory-mapped file.
+pub fn write_fvecs<'a>(path: &Path, vectors: impl IntoIterator<Item = &'a [f32]>) -> Result<()> {
+    write_records(path, vectors, f32::to_le_bytes)
+}
+
+/// Writes `vectors` to a .ivecs file, such as the ids of the nearest neighbours of each query,
+/// overwriting `path` if it exists.
+pub fn write_ivecs<'a>(path: &Path, vectors: impl IntoIterator<Item = &'a [i32]>) -> Result<()> {
+    write_records(path, vectors, i32::to_le_bytes)
+}
+
+/// The extensions of the files that a dataset's vectors can be loaded from, in the order that
+/// `FvecsDataset::new` looks for them.
+const VECTOR_EXTENSIONS: [&str; 4] = ["fvecs", "bvecs", "npy", "npz"];
+
+/// Opens the vectors in `path` as a dataset source, choosing the reader by the file's extension.
+/// .fvecs files are memory-mapped, and checked as thoroughly as `validation` says; other formats
+/// are read into memory, converting their vectors to `f32`s. The first array in a .npz archive is
+/// used.
+pub fn open_vectors(path: &Path, validation: FvecsValidation) -> Result<Box<dyn DatasetSource>> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("fvecs") => Ok(Box::new(FvecsFile::open(path, validation)?)),
+        Some("bvecs") => Ok(Box::new(read_bvecs(path)?)),
+        Some("npy") => Ok(Box::new(npy::read_npy(path)?)),
+        Some("npz") => Ok(Box::new(npy::read_npz(path, None)?)),
+        _ => Err(FvecsError::UnsupportedFormat(path.to_path_buf()).into()),
+    }
+}
+
+impl From<&FvecsDataset> for FlattenedVecs {
+    fn from(dataset: &FvecsDataset) -> Self {
+        dataset.vectors().to_flattened()
+    }
+}
+
+/// Create a Vec<PredicateQuery> representing queries that match the first attribute of the
+/// query vectors. The 0th element in the returned Vec, for example, will be a PredicateQuery for
+/// all vectors whose first attribute is X, where X is the first attribute on the 0th query vector.
+impl From<&FvecsDataset> for Vec<PredicateQuery> {
+    fn from(dataset: &FvecsDataset) -> Self {
+        let metadata = &dataset.metadata;
+        let Some(name) = metadata.names().first() else {
+            return vec![];
+        };
+        let column = metadata.column(name).unwrap();
+
+        (0..metadata.len())
+            .map(|i| PredicateQuery::equals(name, column.value(i)))
+            .collect()
+    }
+}
+
+/// Evaluates each distinct predicate among the filters of a batch over `dataset`, keyed by how it
+/// is written. Batches often repeat predicates, so each is only evaluated once.
+pub(crate) fn evaluate_batch_predicates<D: SimilaritySearchable + ?Sized>(
+    dataset: &D,
+    filters: &[QueryFilter],
+) -> Result<HashMap<String, Bitmask>, SearchableError> {
+    let mut masks = HashMap::new();
+    for filter in filters {
+        if let QueryFilter::Predicate(pq) = filter {
+            if let Entry::Vacant(entry) = masks.entry(pq.to_string()) {
+                entry.insert(Bitmask::new(pq, dataset)?);
+            }
+        }
+    }
+    Ok(masks)
+}
+
+/// Dataset sourced from a .fvecs file, or any other `DatasetSource`. The vectors of a .fvecs file
+/// are read in place from the memory-mapped file, and only copied while the index is built over
+/// them.
+pub struct FvecsDataset {
+    vectors: Box<dyn DatasetSource>,
+    count: usize,
+    dimensionality: usize,
+    index: Option<HnswIndex>,
+    pub metadata: HybridSearchMetadata,
+}
+
+impl FvecsDataset {
+    /// Create a new dataset, memory-mapping the fvecs. The `fname` should represent a
+    /// filename that corresponds to both a "{fname}.fvecs" that contains the vectors, and a
+    /// "{fname}.csv" that contains the attributes (over which predicates can be constructed) for
+    /// those vectors, which is read as `csv` says. Each row in the CSV corresponds to the vector
+    /// at the same index in the fvecs file, and each column represents an attribute on that
+    /// vector. A CSV without a row for every vector is rejected. If there is no .fvecs file,
+    /// the vectors are read from a "{fname}.bvecs", "{fname}.npy" or "{fname}.npz" file instead.
+    ///
+    /// The header of every record in the file is checked, but not the values; see
+    /// `new_with_validation`.
+    pub fn new(fname: String, csv: AttributeCsv) -> Result<Self> {
+        Self::new_with_validation(fname, csv, FvecsValidation::Headers)
+    }
+
+    /// Creates a new dataset as for `new`, checking the .fvecs file as thoroughly as `validation`
+    /// says. A file that is not well-formed is rejected with an `FvecsError` that says where the
+    /// problem is.
+    pub fn new_with_validation(
+        fname: String,
+        csv: AttributeCsv,
+        validation: FvecsValidation,
+    ) -> Result<Self> {
+        // A missing .fvecs file is reported as such if there is no file in another format.
+        let vectors_fname = VECTOR_EXTENSIONS
+            .iter()
+            .map(|extension| PathBuf::from(format!("{fname}.{extension}")))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| PathBuf::from(format!("{fname}.fvecs")));
+        let vectors = open_vectors(&vectors_fname, validation)?;
+
+        let metadata_fname = PathBuf::from(format!("{fname}.csv"));
+        let metadata = match csv {
+            AttributeCsv::Skip => HybridSearchMetadata::without_attributes(vectors.len()),
+            AttributeCsv::Header => read_csv_to_metadata(&metadata_fname, true)?,
+            AttributeCsv::NoHeader => read_csv_to_metadata(&metadata_fname, false)?,
         };
 
-        let flat = FlattenedVecs::read_from_mmap(&mmap, count, dimensionality);
+        Ok(Self::from_source(vectors, metadata)?)
+    }
+
+    /// Creates a dataset over the vectors of `source`, whose attributes are in `metadata`, which
+    /// must have a row for every vector.
+    pub fn from_source(
+        source: Box<dyn DatasetSource>,
+        metadata: HybridSearchMetadata,
+    ) -> Result<Self, ConstructionError> {
+        if metadata.len() != source.len() {
+            return Err(ConstructionError::SizeMismatch {
+                vectors: source.len(),
+                attributes: metadata.len(),
+            });
+        }
         Ok(Self {
+            count: source.len(),
+            dimensionality: source.dimensionality(),
+            vectors: source,
             index: None,
-            count,
-            mmap,
-            dimensionality,
             metadata,
-            flat,
         })
     }
 
+    /// The vectors of the dataset, which are read in place if they come from a .fvecs file.
+    pub fn vectors(&self) -> &dyn DatasetSource {
+        self.vectors.as_ref()
+    }
+
     #[allow(dead_code)]
     fn get_data(&self) -> Result<Vec<Fvec>> {
-        let vecs = self
-            .flat
-            .data
-            .chunks_exact(self.dimensionality)
-            .map(|x| Fvec {
+        let vecs = (0..self.count)
+            .map(|i| Fvec {
                 dimensionality: self.dimensionality,
-                data: x.to_vec(),
+                data: self.vectors.get(i).to_vec(),
             })
             .collect();
         Ok(vecs)
     }
 
-    pub fn view(&self, pq: &PredicateQuery) -> FvecsDatasetPartition {
-        let mask = Bitmask::new(pq, self);
+    /// Loads the index from `path` if a file exists there, and otherwise builds the index and
+    /// saves it to `path` so that later runs can skip construction. A fingerprint of the vectors
+    /// that the index is built over is saved to "{path}.mask". An error is returned if the index
+    /// on disk does not match this dataset, rather than overwriting it.
+    pub fn initialize_cached(
+        &mut self,
+        opts: &OakIndexOptions,
+        path: &Path,
+    ) -> Result<(), ConstructionError> {
+        let mask = Bitmask::full(self.count);
+        if path.exists() {
+            self.index = Some(load_matching_index(self, path, &mask)?);
+            return Ok(());
+        }
+        self.initialize(opts)?;
+        save_index_with_mask(self.index.as_ref().unwrap(), path, &mask)
+    }
+
+    /// Creates a partition of the vectors that match `pq`. The partition shares ownership of the
+    /// dataset, so the two can be moved between threads independently.
+    pub fn view(
+        self: &Arc<Self>,
+        pq: &PredicateQuery,
+    ) -> Result<FvecsDatasetPartition, SearchableError> {
+        let mask = Bitmask::new(pq, self.as_ref())?;
         let metadata = HybridSearchMetadata::new_from_bitmask(&self.metadata, &mask);
+        let ids = mask.iter_ones().collect();
 
-        FvecsDatasetPartition {
-            base: self,
+        Ok(FvecsDatasetPartition {
+            base: Arc::clone(self),
+            predicate: pq.clone(),
             mask,
-            flat: None,
+            ids,
             index: None,
             metadata,
-        }
+        })
+    }
+
+    /// Finds the exact `topk` nearest neighbours of each query that match its filter, by
+    /// comparing it with every vector in the dataset on `threads` threads, such as to compute the
+    /// groundtruth that an index's results are measured against. The dataset does not need to be
+    /// indexed. Like the results of an index, the distances are squared L2 distances.
+    ///
+    /// Queries of a different dimensionality than the dataset, and masks over a different number
+    /// of vectors, are rejected.
+    pub fn exact_search_batch(
+        &self,
+        query_vectors: &FlattenedVecs,
+        filters: &[QueryFilter],
+        topk: usize,
+        threads: usize,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        check_batch_size(query_vectors, filters)?;
+
+        let predicate_masks = evaluate_batch_predicates(self, filters)?;
+        let masks: Vec<Option<&Bitmask>> = filters
+            .iter()
+            .map(|filter| match filter {
+                QueryFilter::Unfiltered => None,
+                QueryFilter::Predicate(pq) => Some(&predicate_masks[&pq.to_string()]),
+                QueryFilter::Bitmask(mask) => Some(mask),
+            })
+            .collect();
+        groundtruth::brute_force_search_batch(self.vectors(), query_vectors, &masks, topk, threads)
     }
 }
 
@@ -267,7 +884,7 @@ impl SimilaritySearchable for FvecsDataset {
     }
 
     fn get_dimensionality(&self) -> usize {
-        self.dimensionality as usize
+        self.dimensionality
     }
 
     fn get_metadata(&self) -> &HybridSearchMetadata {
@@ -275,11 +892,26 @@ impl SimilaritySearchable for FvecsDataset {
     }
 
     fn initialize(&mut self, opts: &OakIndexOptions) -> Result<(), ConstructionError> {
-        let index = AcornHnswIndex::new(self, &self.flat, opts)?;
+        // The index needs the vectors in a contiguous buffer, and keeps its own copy of them, so
+        // this copy only lives while the index is built.
+        let index = HnswIndex::new(&self.vectors().to_flattened(), &self.metadata, opts)?;
         self.index = Some(index);
         Ok(())
     }
 
+    fn memory_usage(&self) -> MemoryUsage {
+        // Vectors that are read in place from a memory-mapped file are not counted.
+        let own = MemoryUsage {
+            vectors: self.vectors.memory_usage(),
+            graph: 0,
+            metadata: self.metadata.memory_usage(),
+        };
+        own + self
+            .index
+            .as_ref()
+            .map_or_else(MemoryUsage::default, HnswIndex::memory_usage)
+    }
+
     fn search(
         &self,
         query_vectors: &FlattenedVecs,
@@ -294,15 +926,15 @@ impl SimilaritySearchable for FvecsDataset {
         debug!("query_vectors len: {}", query_vectors.len());
         debug!("fvecs dataset len: {}", self.len());
 
-        let mut mask = match predicate_query {
-            None => Bitmask::new_full(self),
-            Some(pq) => Bitmask::new(pq, self),
+        let mask = match predicate_query {
+            None => None,
+            Some(pq) => Some(Bitmask::new(pq, self)?),
         };
 
         self.index
             .as_ref()
             .unwrap()
-            .search(query_vectors, &mut mask.map, topk, efsearch)
+            .search(query_vectors, mask.as_ref(), topk, efsearch)
     }
 
     fn search_with_bitmask(
@@ -312,55 +944,182 @@ impl SimilaritySearchable for FvecsDataset {
         topk: usize,
         efsearch: i64,
     ) -> Result<Vec<TopKSearchResult>, SearchableError> {
-        let mut filter_id_map = Vec::<i8>::from(bitmask);
-
-        // TODO: this & to filter_id_map should not have to be mutable
         self.index
             .as_ref()
             .unwrap()
-            .search(query_vectors, &mut filter_id_map, topk, efsearch)
+            .search(query_vectors, Some(bitmask), topk, efsearch)
+    }
+
+    fn search_batch(
+        &self,
+        query_vectors: &FlattenedVecs,
+        filters: &[QueryFilter],
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        let Some(index) = &self.index else {
+            return Err(SearchableError::DatasetIsNotIndexed);
+        };
+        check_batch_size(query_vectors, filters)?;
+
+        let predicate_masks = evaluate_batch_predicates(self, filters)?;
+        let masks: Vec<Option<&Bitmask>> = filters
+            .iter()
+            .map(|filter| match filter {
+                QueryFilter::Unfiltered => None,
+                QueryFilter::Predicate(pq) => Some(&predicate_masks[&pq.to_string()]),
+                QueryFilter::Bitmask(mask) => Some(mask),
+            })
+            .collect();
+        index.search_batch(query_vectors, &masks, topk, efsearch)
     }
 }
 
 /// A 'partition' of the FvecsDataset, originally represented just by a base dataset and a Bitmask.
-pub struct FvecsDatasetPartition<'a> {
-    base: &'a FvecsDataset,
+pub struct FvecsDatasetPartition {
+    base: Arc<FvecsDataset>,
+    /// The predicate that the partition was built from, which selects the vectors in `mask`.
+    predicate: PredicateQuery,
     mask: Bitmask,
-    index: Option<AcornHnswIndex>,
-    /// We have an Option here so that the copying of the base vectors can be deferred to the point
-    /// at which we decide to build the index. This is an implementation detail, as one could
-    /// imagine a pure Rust implementation of the search methods that does not require this
-    /// original copy.
-    flat: Option<FlattenedVecs>,
-    /// The same with the metadata
+    /// The index of the partition is built over a compacted copy of the vectors in the mask, so
+    /// the labels it returns are positions within the partition. This maps each such position to
+    /// the id of the vector in the base dataset.
+    ids: Vec<usize>,
+    index: Option<HnswIndex>,
+    /// The attributes of the vectors in the partition, in the same order as `ids`.
     metadata: HybridSearchMetadata,
 }
 
-impl<'a> SimilaritySearchable for FvecsDatasetPartition<'a> {
+impl FvecsDatasetPartition {
+    /// Loads the index from `path` if a file exists there, and otherwise builds the index and
+    /// saves it to `path`. See `FvecsDataset::initialize_cached`.
+    pub fn initialize_cached(
+        &mut self,
+        opts: &OakIndexOptions,
+        path: &Path,
+    ) -> Result<(), ConstructionError> {
+        if path.exists() {
+            self.index = Some(load_matching_index(self, path, &self.mask)?);
+            return Ok(());
+        }
+        self.initialize(opts)?;
+        save_index_with_mask(self.index.as_ref().unwrap(), path, &self.mask)
+    }
+
+    /// Starts building the partition's index on a background thread, so that queries can keep
+    /// being served meanwhile. The partition cannot be searched until the result has been passed
+    /// to `finish_initialize`.
+    pub fn initialize_in_background(&self, opts: &OakIndexOptions) -> PendingIndex {
+        let base = Arc::clone(&self.base);
+        let mask = self.mask.clone();
+        let metadata = self.metadata.clone();
+        let opts = *opts;
+        PendingIndex(thread::spawn(move || {
+            let flat = base.vectors().to_flattened_via_bitmask(&mask);
+            HnswIndex::new(&flat, &metadata, &opts)
+        }))
+    }
+
+    /// Waits for an index started by `initialize_in_background` to be built, and then uses it for
+    /// the partition.
+    pub fn finish_initialize(&mut self, pending: PendingIndex) -> Result<(), ConstructionError> {
+        let index = pending
+            .0
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+        self.index = Some(index);
+        Ok(())
+    }
+
+    /// The id in the base dataset of the vector at position `local_id` in the partition.
+    pub fn global_id(&self, local_id: usize) -> usize {
+        self.ids[local_id]
+    }
+
+    /// Projects a mask over the base dataset into the partition's own compacted id space, which
+    /// is what its index expects. If every vector in the partition is set in the mask, there is
+    /// nothing to filter, so `None` is returned.
+    fn to_local_mask(&self, bitmask: &Bitmask) -> Result<Option<Bitmask>, SearchableError> {
+        if bitmask.capacity() != self.base.len() {
+            return Err(SearchableError::MaskSizeMismatch {
+                vectors: self.base.len(),
+                mask: bitmask.capacity(),
+            });
+        }
+        if self.mask.is_subset_of(bitmask) {
+            debug!("Query mask covers the whole partition, so it will not be filtered.");
+            return Ok(None);
+        }
+        Ok(Some(self.ids.iter().map(|&id| bitmask.get(id)).collect()))
+    }
+
+    /// Translates the labels returned by the partition's index into ids in the base dataset, so
+    /// that results are comparable with those from searching the base dataset.
+    fn to_global_ids(&self, results: Vec<TopKSearchResult>) -> Vec<TopKSearchResult> {
+        results
+            .into_iter()
+            .map(|topk| {
+                topk.into_iter()
+                    .map(|(id, distance)| (self.global_id(id), distance))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// The index of a partition that is being built on a background thread.
+pub struct PendingIndex(JoinHandle<Result<HnswIndex, ConstructionError>>);
+
+impl PendingIndex {
+    /// Whether the index has finished building, in which case `finish_initialize` will not block.
+    pub fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
+}
+
+impl SimilaritySearchable for FvecsDatasetPartition {
     fn len(&self) -> usize {
-        self.mask.bitcount()
+        self.ids.len()
     }
 
     fn get_metadata(&self) -> &HybridSearchMetadata {
         &self.metadata
     }
 
+    fn get_predicate(&self) -> Option<&PredicateQuery> {
+        Some(&self.predicate)
+    }
+
+    fn mask(&self) -> Option<&Bitmask> {
+        Some(&self.mask)
+    }
+
     fn get_dimensionality(&self) -> usize {
         self.base.dimensionality
     }
 
     fn initialize(&mut self, opts: &OakIndexOptions) -> Result<(), ConstructionError> {
-        let og = &self.base.flat;
-        let flat = og.clone_via_bitmask(&self.mask);
-
-        let index = AcornHnswIndex::new(self, &flat, opts)?;
-
-        self.index = Some(index);
-        self.flat = Some(flat);
+        // Only the vectors in the partition are copied, and only while the index is built.
+        let flat = self.base.vectors().to_flattened_via_bitmask(&self.mask);
+        self.index = Some(HnswIndex::new(&flat, &self.metadata, opts)?);
 
         Ok(())
     }
 
+    fn memory_usage(&self) -> MemoryUsage {
+        let own = MemoryUsage {
+            vectors: 0,
+            graph: 0,
+            metadata: self.metadata.memory_usage()
+                + self.mask.memory_usage()
+                + self.ids.capacity() * std::mem::size_of::<usize>(),
+        };
+        own + self
+            .index
+            .as_ref()
+            .map_or_else(MemoryUsage::default, HnswIndex::memory_usage)
+    }
+
     fn search(
         &self,
         query_vectors: &FlattenedVecs,
@@ -375,15 +1134,17 @@ impl<'a> SimilaritySearchable for FvecsDatasetPartition<'a> {
         debug!("query_vectors len: {}", query_vectors.len());
         debug!("fvecs dataset len: {}", self.len());
 
-        let mut mask = match predicate_query {
-            None => Bitmask::new_full(self),
-            Some(pq) => Bitmask::new(pq, self),
+        let mask = match predicate_query {
+            None => None,
+            Some(pq) => Some(Bitmask::new(pq, self)?),
         };
 
-        self.index
-            .as_ref()
-            .unwrap()
-            .search(query_vectors, &mut mask.map, topk, efsearch)
+        let results =
+            self.index
+                .as_ref()
+                .unwrap()
+                .search(query_vectors, mask.as_ref(), topk, efsearch)?;
+        Ok(self.to_global_ids(results))
     }
 
     fn search_with_bitmask(
@@ -393,13 +1154,44 @@ impl<'a> SimilaritySearchable for FvecsDatasetPartition<'a> {
         topk: usize,
         efsearch: i64,
     ) -> Result<Vec<TopKSearchResult>, SearchableError> {
-        let mut filter_id_map = Vec::<i8>::from(bitmask);
+        let Some(index) = &self.index else {
+            return Err(SearchableError::DatasetIsNotIndexed);
+        };
 
-        // TODO: this & to filter_id_map should not have to be mutable
-        self.index
-            .as_ref()
-            .unwrap()
-            .search(query_vectors, &mut filter_id_map, topk, efsearch)
+        let local_mask = self.to_local_mask(bitmask)?;
+        let results = index.search(query_vectors, local_mask.as_ref(), topk, efsearch)?;
+        Ok(self.to_global_ids(results))
+    }
+
+    fn search_batch(
+        &self,
+        query_vectors: &FlattenedVecs,
+        filters: &[QueryFilter],
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        let Some(index) = &self.index else {
+            return Err(SearchableError::DatasetIsNotIndexed);
+        };
+        check_batch_size(query_vectors, filters)?;
+
+        // Predicates are evaluated over the partition's own metadata, but masks are over the base
+        // dataset and so are projected into the partition.
+        let predicate_masks = evaluate_batch_predicates(self, filters)?;
+        let masks = filters
+            .iter()
+            .map(|filter| match filter {
+                QueryFilter::Unfiltered => Ok(None),
+                QueryFilter::Predicate(pq) => {
+                    Ok(Some(Cow::Borrowed(&predicate_masks[&pq.to_string()])))
+                }
+                QueryFilter::Bitmask(mask) => Ok(self.to_local_mask(mask)?.map(Cow::Owned)),
+            })
+            .collect::<Result<Vec<Option<Cow<Bitmask>>>, SearchableError>>()?;
+        let masks: Vec<Option<&Bitmask>> = masks.iter().map(|mask| mask.as_deref()).collect();
+
+        let results = index.search_batch(query_vectors, &masks, topk, efsearch)?;
+        Ok(self.to_global_ids(results))
     }
 }
 
@@ -407,11 +1199,10 @@ impl<'a> SimilaritySearchable for FvecsDatasetPartition<'a> {
 mod tests {
     use super::*;
     use crate::stubs::generate_random_vector;
-    use std::sync::Once;
 
     #[test]
     fn test_not_initialized_error() {
-        let dataset = FvecsDataset::new("data/sift_query".to_string(), false).unwrap();
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::Skip).unwrap();
         let predicate: Option<PredicateQuery> = None;
         let dimensionality = dataset.dimensionality;
         let query_vector = FlattenedVecs {
@@ -420,19 +1211,643 @@ mod tests {
         };
 
         assert!(dataset.index.is_none());
-        // TODO: the following gives a build error; something to do with
-        // undefined reference to `typeinfo for faiss::FaissException'
-        // i.e. the handling of errors across the FFI boundary.
-        // let result = dataset.search(query_vector, predicate, 1);
-        // assert_eq!(result, Err(SearchableError::DatasetIsNotIndexed));
+        let result = dataset.search(&query_vector, &predicate, 1, 16);
+        assert_eq!(result, Err(SearchableError::DatasetIsNotIndexed));
+    }
+
+    #[test]
+    fn test_initialize_checks_attributes() {
+        let mut dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::Skip).unwrap();
+        dataset.metadata = HybridSearchMetadata::without_attributes(dataset.len() - 1);
+        let result = dataset.initialize(&OakIndexOptions::default());
+        assert!(matches!(
+            result,
+            Err(ConstructionError::SizeMismatch {
+                vectors: 1000,
+                attributes: 999
+            })
+        ));
+        assert!(dataset.index.is_none());
+
+        // Attributes without a row for every vector are rejected when the dataset is loaded.
+        let vectors = FlattenedVecs {
+            dimensionality: 2,
+            data: vec![0.0; 6],
+        };
+        let result = FvecsDataset::from_source(
+            Box::new(vectors),
+            HybridSearchMetadata::without_attributes(2),
+        );
+        assert!(matches!(
+            result,
+            Err(ConstructionError::SizeMismatch {
+                vectors: 3,
+                attributes: 2
+            })
+        ));
+
+        // A predicate that matches nothing leaves a partition with nothing to index.
+        let dataset = Arc::new(
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap(),
+        );
+        let mut partition = dataset.view(&"attr0 = 100".parse().unwrap()).unwrap();
+        let result = partition.initialize(&OakIndexOptions::default());
+        assert!(matches!(result, Err(ConstructionError::EmptyDataset)));
+    }
+
+    #[cfg(feature = "hnsw_faiss")]
+    #[test]
+    fn test_initialize_cached_round_trip() {
+        let path = std::env::temp_dir().join("oak_test_initialize_cached.index");
+        let _ = std::fs::remove_file(&path);
+        let opts = OakIndexOptions::default();
+
+        let mut built =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
+        built.initialize_cached(&opts, &path).unwrap();
+        let built = Arc::new(built);
+        assert!(path.exists());
+
+        let mut loaded =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
+        loaded.initialize_cached(&opts, &path).unwrap();
+
+        let dimensionality = built.dimensionality;
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: generate_random_vector(dimensionality),
+        };
+        let mask = Bitmask::new_full(built.as_ref());
+        assert_eq!(
+            built.search_with_bitmask(&query_vector, &mask, 10, 16),
+            loaded.search_with_bitmask(&query_vector, &mask, 10, 16)
+        );
+
+        let mut partition = built.view(&PredicateQuery::new(1)).unwrap();
+        let result = partition.initialize_cached(&opts, &path);
+        assert!(matches!(
+            result,
+            Err(ConstructionError::IndexMismatch { .. })
+        ));
+
+        // Every twelfth vector has each value of attr0, so these partitions are the same size,
+        // but hold different vectors.
+        let partition_path =
+            std::env::temp_dir().join("oak_test_initialize_cached_partition.index");
+        let _ = std::fs::remove_file(&partition_path);
+        let mut first = built.view(&"attr0 = 1".parse().unwrap()).unwrap();
+        first.initialize_cached(&opts, &partition_path).unwrap();
+        let mut second = built.view(&"attr0 = 2".parse().unwrap()).unwrap();
+        assert_eq!(first.len(), second.len());
+        assert!(matches!(
+            second.initialize_cached(&opts, &partition_path),
+            Err(ConstructionError::IndexVectorsMismatch { .. })
+        ));
+        let mut again = built.view(&"attr0 = 1".parse().unwrap()).unwrap();
+        again.initialize_cached(&opts, &partition_path).unwrap();
+
+        for path in [path, partition_path] {
+            std::fs::remove_file(fingerprint_path(&path)).unwrap();
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_partition_returns_base_ids() {
+        let mut dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let dataset = Arc::new(dataset);
+        let pq = PredicateQuery::new(1);
+
+        let mut partition = dataset.view(&pq).unwrap();
+        partition.initialize(&OakIndexOptions::default()).unwrap();
+
+        // Query with a vector from the partition that is not at the same position in the base.
+        let id = (0..partition.len())
+            .map(|local_id| partition.global_id(local_id))
+            .enumerate()
+            .find_map(|(local_id, id)| (local_id != id).then_some(id))
+            .unwrap();
+        let dimensionality = dataset.dimensionality;
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.vectors().get(id).to_vec(),
+        };
+
+        let routed = partition.search(&query_vector, &None, 1, 16).unwrap();
+        let unrouted = dataset
+            .search(&query_vector, &Some(pq.clone()), 1, 16)
+            .unwrap();
+        assert_eq!(routed[0][0].0, id);
+        assert_eq!(unrouted[0][0].0, id);
+    }
+
+    #[test]
+    fn test_partition_projects_base_mask() {
+        let mut dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let dataset = Arc::new(dataset);
+        let mut partition = dataset.view(&PredicateQuery::new(1)).unwrap();
+        partition.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.dimensionality;
+        let id = partition.global_id(partition.len() - 1);
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.vectors().get(id).to_vec(),
+        };
+
+        // The vector is the last in the partition, so that is the only bit set once projected.
+        let mut only_id = Bitmask::empty(dataset.len());
+        only_id.set(id, true);
+        let local_mask = partition.to_local_mask(&only_id).unwrap().unwrap();
+        assert_eq!(local_mask.capacity(), partition.len());
+        assert_eq!(
+            local_mask.iter_ones().collect::<Vec<_>>(),
+            vec![partition.len() - 1]
+        );
+        let result = partition
+            .search_with_bitmask(&query_vector, &only_id, 1, 16)
+            .unwrap();
+        assert_eq!(result[0], vec![(id, 0.0)]);
+
+        // A mask over the whole base dataset covers the partition, so no filtering is needed.
+        let full = Bitmask::new_full(dataset.as_ref());
+        assert!(partition.to_local_mask(&full).unwrap().is_none());
+        let result = partition
+            .search_with_bitmask(&query_vector, &full, 1, 16)
+            .unwrap();
+        assert_eq!(result[0][0].0, id);
+
+        // Masks over the partition's own id space are rejected.
+        assert_eq!(
+            partition.search_with_bitmask(&query_vector, &Bitmask::new_full(&partition), 1, 16),
+            Err(SearchableError::MaskSizeMismatch {
+                vectors: dataset.len(),
+                mask: partition.len()
+            })
+        );
+    }
+
+    #[test]
+    fn test_search_batch_filters_each_query() {
+        let mut dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let dataset = Arc::new(dataset);
+        let mut partition = dataset.view(&PredicateQuery::new(1)).unwrap();
+        partition.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.dimensionality;
+        let num_queries = 24;
+        let query_vectors = FlattenedVecs {
+            dimensionality,
+            data: dataset.vectors().to_flattened_range(0..num_queries).data,
+        };
+        let predicates = Vec::<PredicateQuery>::from(dataset.as_ref());
+        let mut even_ids = Bitmask::empty(dataset.len());
+        for id in (0..dataset.len()).step_by(2) {
+            even_ids.set(id, true);
+        }
+        let filters: Vec<QueryFilter> = (0..num_queries)
+            .map(|i| match i % 3 {
+                0 => QueryFilter::Unfiltered,
+                1 => QueryFilter::Predicate(predicates[i].clone()),
+                _ => QueryFilter::Bitmask(even_ids.clone()),
+            })
+            .collect();
+
+        let searchables: [&dyn SimilaritySearchable; 2] = [dataset.as_ref(), &partition];
+        for searchable in searchables {
+            let results = searchable
+                .search_batch(&query_vectors, &filters, 5, 16)
+                .unwrap();
+            assert_eq!(results.len(), num_queries);
+            let queries = query_vectors
+                .data
+                .chunks(dimensionality)
+                .map(|data| FlattenedVecs {
+                    dimensionality,
+                    data: data.to_vec(),
+                });
+            for (i, (query, filter)) in queries.zip(&filters).enumerate() {
+                let expected = match filter {
+                    QueryFilter::Unfiltered => searchable.search(&query, &None, 5, 16),
+                    QueryFilter::Predicate(pq) => {
+                        searchable.search(&query, &Some(pq.clone()), 5, 16)
+                    }
+                    QueryFilter::Bitmask(mask) => {
+                        searchable.search_with_bitmask(&query, mask, 5, 16)
+                    }
+                };
+                assert_eq!(results[i], expected.unwrap()[0]);
+                if let QueryFilter::Bitmask(mask) = filter {
+                    assert!(results[i].iter().all(|(id, _)| mask.get(*id)));
+                }
+            }
+        }
+
+        assert_eq!(
+            dataset.search_batch(&query_vectors, &filters[1..], 5, 16),
+            Err(SearchableError::BatchSizeMismatch {
+                queries: num_queries,
+                filters: num_queries - 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_exact_search_batch() {
+        // The dataset does not need to be indexed to be searched exhaustively.
+        let dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
+        let num_queries = 12;
+        let query_vectors = dataset.vectors().to_flattened_range(0..num_queries);
+        let predicates = Vec::<PredicateQuery>::from(&dataset);
+        let filters: Vec<QueryFilter> = (0..num_queries)
+            .map(|i| match i % 2 {
+                0 => QueryFilter::Unfiltered,
+                _ => QueryFilter::Predicate(predicates[i].clone()),
+            })
+            .collect();
+
+        let results = dataset
+            .exact_search_batch(&query_vectors, &filters, 10, 4)
+            .unwrap();
+        assert_eq!(
+            results,
+            dataset
+                .exact_search_batch(&query_vectors, &filters, 10, 1)
+                .unwrap()
+        );
+        for (i, (result, filter)) in results.iter().zip(&filters).enumerate() {
+            // Each query is a vector of the dataset that matches its own predicate, so it is its
+            // own nearest neighbour.
+            assert_eq!(result.len(), 10);
+            assert_eq!(result[0], (i, 0.0));
+            assert!(result.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+            if let QueryFilter::Predicate(pq) = filter {
+                let mask = Bitmask::new(pq, &dataset).unwrap();
+                assert!(result.iter().all(|(id, _)| mask.get(*id)));
+            }
+        }
+
+        assert_eq!(
+            dataset.exact_search_batch(&query_vectors, &filters[1..], 10, 1),
+            Err(SearchableError::BatchSizeMismatch {
+                queries: num_queries,
+                filters: num_queries - 1,
+            })
+        );
+        let truncated = FlattenedVecs {
+            dimensionality: query_vectors.dimensionality - 1,
+            data: query_vectors.data[..query_vectors.dimensionality - 1].to_vec(),
+        };
+        assert_eq!(
+            dataset.exact_search_batch(&truncated, &filters[..1], 10, 1),
+            Err(SearchableError::DimensionalityMismatch {
+                expected: 128,
+                found: 127,
+            })
+        );
+        let short_mask = QueryFilter::Bitmask(Bitmask::full(dataset.len() - 1));
+        assert_eq!(
+            dataset.exact_search_batch(&query_vectors, &vec![short_mask; num_queries], 10, 1),
+            Err(SearchableError::MaskSizeMismatch {
+                vectors: 1000,
+                mask: 999,
+            })
+        );
+    }
+
+    #[test]
+    fn test_initialize_in_background() {
+        let dataset = Arc::new(
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap(),
+        );
+        let mut partition = dataset.view(&PredicateQuery::new(1)).unwrap();
+
+        let pending = partition.initialize_in_background(&OakIndexOptions::default());
+        assert!(partition.index.is_none());
+        partition.finish_initialize(pending).unwrap();
+
+        let dimensionality = dataset.dimensionality;
+        let id = partition.global_id(0);
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.vectors().get(id).to_vec(),
+        };
+        let result = partition.search(&query_vector, &None, 1, 16).unwrap();
+        assert_eq!(result[0][0].0, id);
+    }
+
+    #[test]
+    fn test_read_csv_to_metadata() {
+        let with_header = std::env::temp_dir().join("oak_test_metadata_with_header.csv");
+        std::fs::write(&with_header, "category,price,tenant\n1,2.5,a\n2,3,b\n").unwrap();
+        let metadata = read_csv_to_metadata(&with_header, true).unwrap();
+        std::fs::remove_file(&with_header).unwrap();
+
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata.names(), ["category", "price", "tenant"]);
+        assert_eq!(
+            metadata.column("category"),
+            Some(&AttributeColumn::Int(vec![1, 2]))
+        );
+        assert_eq!(
+            metadata.column("price"),
+            Some(&AttributeColumn::Float(vec![2.5, 3.0]))
+        );
+        assert_eq!(
+            metadata.column("attr2"),
+            Some(&AttributeColumn::Text(vec![
+                "a".to_string(),
+                "b".to_string()
+            ]))
+        );
+
+        let without_header = std::env::temp_dir().join("oak_test_metadata_without_header.csv");
+        std::fs::write(&without_header, "1,7,red\n2,8,blue\n3,9,red\n").unwrap();
+        let metadata = read_csv_to_metadata(&without_header, false).unwrap();
+        std::fs::remove_file(&without_header).unwrap();
+
+        // The first row is not taken to be a header, even though one of its fields is text.
+        assert_eq!(metadata.len(), 3);
+        assert_eq!(metadata.names(), ["attr0", "attr1", "attr2"]);
+        assert_eq!(
+            metadata.column("attr1"),
+            Some(&AttributeColumn::Int(vec![7, 8, 9]))
+        );
+        assert_eq!(
+            metadata.column("attr2"),
+            Some(&AttributeColumn::Text(vec![
+                "red".to_string(),
+                "blue".to_string(),
+                "red".to_string()
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_push_column() {
+        let mut metadata = HybridSearchMetadata::without_attributes(2);
+        metadata
+            .push_column("label".to_string(), AttributeColumn::Int(vec![1, 2]))
+            .unwrap();
+        assert!(matches!(
+            metadata.push_column("label".to_string(), AttributeColumn::Int(vec![3, 4])),
+            Err(ConstructionError::DuplicateAttribute(name)) if name == "label"
+        ));
+        assert!(matches!(
+            metadata.push_column("price".to_string(), AttributeColumn::Float(vec![1.0])),
+            Err(ConstructionError::SizeMismatch {
+                vectors: 2,
+                attributes: 1
+            })
+        ));
+        assert_eq!(metadata.names(), ["label"]);
     }
 
     #[test]
     fn test_fvecs_to_flattened_vec() {
-        let dataset = FvecsDataset::new("data/sift_query".to_string(), true).unwrap();
+        let dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
         let dataset_len = dataset.len();
         let vecs = FlattenedVecs::from(&dataset);
 
         assert_eq!(vecs.len(), dataset_len);
     }
+
+    /// Encodes `records` as a .fvecs file, with each record prefixed by its own length.
+    fn encode_fvecs(records: &[&[f32]]) -> Vec<u8> {
+        let mut bytes = vec![];
+        for record in records {
+            bytes.extend((record.len() as u32).to_le_bytes());
+            for value in *record {
+                bytes.extend(value.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_validate_fvecs() {
+        use FvecsValidation::{Headers, Strict};
+
+        let valid = encode_fvecs(&[&[1.0, 2.0], &[3.0, 4.0], &[5.0, 6.0]]);
+        assert_eq!(validate_fvecs(&valid, Strict), Ok((3, 2)));
+
+        assert_eq!(validate_fvecs(&[], Headers), Err(FvecsError::Empty));
+        assert_eq!(
+            validate_fvecs(&encode_fvecs(&[&[]]), Headers),
+            Err(FvecsError::ZeroDimensionality)
+        );
+        assert_eq!(
+            validate_fvecs(&valid[..valid.len() - 3], Headers),
+            Err(FvecsError::Truncated {
+                record: 2,
+                offset: 24,
+                expected: 12,
+                found: 9
+            })
+        );
+
+        let mixed = encode_fvecs(&[&[1.0, 2.0], &[3.0, 4.0, 5.0], &[6.0, 7.0]]);
+        assert_eq!(
+            validate_fvecs(&mixed, Headers),
+            Err(FvecsError::DimensionalityMismatch {
+                record: 1,
+                offset: 12,
+                expected: 2,
+                found: 3
+            })
+        );
+
+        // Values are only checked in strict mode.
+        let nan = encode_fvecs(&[&[1.0, 2.0], &[3.0, f32::INFINITY]]);
+        assert_eq!(validate_fvecs(&nan, Headers), Ok((2, 2)));
+        assert_eq!(
+            validate_fvecs(&nan, Strict),
+            Err(FvecsError::NonFinite {
+                record: 1,
+                offset: 20,
+                value: f32::INFINITY
+            })
+        );
+
+        assert_eq!(parse_u8_to_f32(&[0; 6]), Err(FvecsError::InvalidLength(6)));
+    }
+
+    #[test]
+    fn test_new_rejects_invalid_file() {
+        let path = std::env::temp_dir().join("oak_test_new_rejects_invalid_file");
+        let fname = path.to_str().unwrap().to_string();
+        std::fs::write(
+            format!("{fname}.fvecs"),
+            encode_fvecs(&[&[1.0, 2.0], &[f32::NAN, 4.0]]),
+        )
+        .unwrap();
+
+        let dataset = FvecsDataset::new(fname.clone(), AttributeCsv::Skip).unwrap();
+        assert_eq!(dataset.len(), 2);
+        let error = FvecsDataset::new_with_validation(
+            fname.clone(),
+            AttributeCsv::Skip,
+            FvecsValidation::Strict,
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(
+            error.downcast_ref::<FvecsError>(),
+            Some(FvecsError::NonFinite {
+                record: 1,
+                offset: 16,
+                ..
+            })
+        ));
+
+        std::fs::remove_file(format!("{fname}.fvecs")).unwrap();
+    }
+
+    #[test]
+    fn test_read_bvecs_and_ivecs() {
+        let dir = std::env::temp_dir();
+        let bvecs = dir.join("oak_test_read_bvecs_and_ivecs.bvecs");
+        let mut bytes = vec![];
+        for record in [[0u8, 1, 255], [7, 8, 9]] {
+            bytes.extend(3u32.to_le_bytes());
+            bytes.extend(record);
+        }
+        std::fs::write(&bvecs, &bytes).unwrap();
+        let vectors = read_bvecs(&bvecs).unwrap();
+        assert_eq!(vectors.dimensionality, 3);
+        assert_eq!(vectors.data, [0.0, 1.0, 255.0, 7.0, 8.0, 9.0]);
+
+        std::fs::write(&bvecs, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(matches!(
+            read_bvecs(&bvecs)
+                .err()
+                .unwrap()
+                .downcast_ref::<FvecsError>(),
+            Some(FvecsError::Truncated { record: 1, .. })
+        ));
+
+        // The values of a .ivecs file have the same width as those of a .fvecs file.
+        let ivecs = dir.join("oak_test_read_bvecs_and_ivecs.ivecs");
+        let mut bytes = vec![];
+        for record in [[3i32, -1], [0, 42]] {
+            bytes.extend(2u32.to_le_bytes());
+            for value in record {
+                bytes.extend(value.to_le_bytes());
+            }
+        }
+        std::fs::write(&ivecs, &bytes).unwrap();
+        assert_eq!(read_ivecs(&ivecs).unwrap(), [[3, -1], [0, 42]]);
+
+        let vectors = open_vectors(&bvecs.with_extension("npy"), FvecsValidation::Headers);
+        assert!(vectors.is_err());
+        let unsupported = open_vectors(&ivecs, FvecsValidation::Headers)
+            .err()
+            .unwrap();
+        assert_eq!(
+            unsupported.downcast_ref::<FvecsError>(),
+            Some(&FvecsError::UnsupportedFormat(ivecs.clone()))
+        );
+
+        std::fs::remove_file(&bvecs).unwrap();
+        std::fs::remove_file(&ivecs).unwrap();
+    }
+
+    #[test]
+    fn test_writers_round_trip() {
+        let dir = std::env::temp_dir();
+        let fvecs = dir.join("oak_test_writers_round_trip.fvecs");
+        let vectors = [[1.0, -2.5], [f32::MAX, 0.0]];
+        write_fvecs(&fvecs, vectors.iter().map(|v| v.as_slice())).unwrap();
+        let file = FvecsFile::open(&fvecs, FvecsValidation::Strict).unwrap();
+        assert_eq!(file.to_flattened().data, vectors.concat());
+
+        let ragged: [&[f32]; 2] = [&[1.0, 2.0], &[3.0]];
+        assert!(matches!(
+            write_fvecs(&fvecs, ragged)
+                .err()
+                .unwrap()
+                .downcast_ref::<FvecsError>(),
+            Some(FvecsError::DimensionalityMismatch {
+                record: 1,
+                offset: 12,
+                expected: 2,
+                found: 1
+            })
+        ));
+
+        let ivecs = dir.join("oak_test_writers_round_trip.ivecs");
+        let ids = vec![vec![7, 3, 0], vec![-1, 2, 9]];
+        write_ivecs(&ivecs, ids.iter().map(Vec::as_slice)).unwrap();
+        assert_eq!(read_ivecs(&ivecs).unwrap(), ids);
+
+        let csv = dir.join("oak_test_writers_round_trip.csv");
+        let metadata = HybridSearchMetadata::new(
+            vec![
+                "label".to_string(),
+                "price".to_string(),
+                "attr2".to_string(),
+            ],
+            vec![
+                AttributeColumn::Int(vec![1, -4]),
+                AttributeColumn::Float(vec![2.0, 0.5]),
+                AttributeColumn::Text(vec!["red".to_string(), "blue".to_string()]),
+            ],
+        );
+        write_csv_from_metadata(&csv, &metadata).unwrap();
+        let read = read_csv_to_metadata(&csv, true).unwrap();
+        assert_eq!(read.names(), metadata.names());
+        for name in metadata.names() {
+            assert_eq!(read.column(name), metadata.column(name));
+        }
+
+        for path in [fvecs, ivecs, csv] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_fvecs_view_reads_in_place() {
+        let file =
+            FvecsFile::open(Path::new("data/sift_query.fvecs"), FvecsValidation::Headers).unwrap();
+        let dimensionality = file.dimensionality();
+        let view = file.view();
+        assert_eq!(view.len(), file.len());
+
+        // Each vector is the record's values after its leading dimensionality.
+        let stride = (1 + dimensionality) * FOUR_BYTES;
+        for i in [0, 1, view.len() - 1] {
+            let record = &file.mmap[i * stride..(i + 1) * stride];
+            assert_eq!(view.get(i), parse_u8_to_f32(&record[FOUR_BYTES..]).unwrap());
+            assert_eq!(
+                view.get(i).as_ptr() as *const u8,
+                record[FOUR_BYTES..].as_ptr()
+            );
+        }
+
+        let flat = view.to_flattened();
+        assert_eq!(flat.len(), view.len());
+        assert_eq!(
+            &flat.data[5 * dimensionality..6 * dimensionality],
+            view.get(5)
+        );
+
+        let range = view.range(10..20);
+        assert_eq!(range.len(), 10);
+        assert_eq!(range.get(0), view.get(10));
+
+        let mut mask = Bitmask::empty(view.len());
+        mask.set(3, true);
+        mask.set(7, true);
+        let masked = view.to_flattened_via_bitmask(&mask);
+        assert_eq!(masked.data, [view.get(3), view.get(7)].concat());
+    }
 }
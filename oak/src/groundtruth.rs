@@ -0,0 +1,208 @@
+use crate::bitmask::Bitmask;
+use crate::dataset::{check_batch_size, DatasetSource, SearchableError, TopKSearchResult};
+use crate::fvecs::FlattenedVecs;
+use slog_scope::debug;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::thread;
+
+/// The number of base vectors that each thread compares all of its queries with before moving on,
+/// so that the chunk is read from memory, or from a memory-mapped file, once rather than once per
+/// query.
+const CHUNK_VECTORS: usize = 4096;
+
+/// A candidate neighbour, ordered by distance and then by id, so that the results do not depend on
+/// the order in which vectors at the same distance are compared.
+#[derive(PartialEq)]
+struct Neighbour {
+    distance: f32,
+    id: usize,
+}
+
+impl Eq for Neighbour {}
+
+impl Ord for Neighbour {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.id.cmp(&other.id))
+    }
+}
+
+impl PartialOrd for Neighbour {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The squared L2 distance between two vectors, which is what FAISS reports, so that distances can
+/// be compared with those returned by an index.
+fn squared_l2(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+/// Finds the exact `topk` nearest neighbours of each query among the vectors of `base` that are
+/// set in the query's mask, or among all of them if the query has no mask, by comparing the query
+/// with every vector. The results are sorted from nearest to furthest, and have fewer than `topk`
+/// neighbours if fewer vectors match. Queries of a different dimensionality than `base`, and
+/// masks over a different number of vectors, are rejected.
+///
+/// The queries are divided between `threads` threads, each of which scans `base` in chunks.
+pub fn brute_force_search_batch(
+    base: &dyn DatasetSource,
+    query_vectors: &FlattenedVecs,
+    masks: &[Option<&Bitmask>],
+    topk: usize,
+    threads: usize,
+) -> Result<Vec<TopKSearchResult>, SearchableError> {
+    check_batch_size(query_vectors, masks)?;
+    if query_vectors.dimensionality != base.dimensionality() {
+        return Err(SearchableError::DimensionalityMismatch {
+            expected: base.dimensionality(),
+            found: query_vectors.dimensionality,
+        });
+    }
+    if let Some(mask) = masks
+        .iter()
+        .flatten()
+        .find(|mask| mask.capacity() != base.len())
+    {
+        return Err(SearchableError::MaskSizeMismatch {
+            vectors: base.len(),
+            mask: mask.capacity(),
+        });
+    }
+    let count = query_vectors.len();
+    if count == 0 || topk == 0 {
+        return Ok(vec![vec![]; count]);
+    }
+    debug!(
+        "Searching {} vectors exhaustively for the {topk} nearest neighbours of {count} queries.",
+        base.len()
+    );
+
+    let queries_per_thread = count.div_ceil(threads.max(1));
+    let queries: Vec<&[f32]> = (0..count).map(|i| query_vectors.get(i)).collect();
+    Ok(thread::scope(|scope| {
+        let handles: Vec<_> = queries
+            .chunks(queries_per_thread)
+            .zip(masks.chunks(queries_per_thread))
+            .map(|(queries, masks)| scope.spawn(move || search_chunked(base, queries, masks, topk)))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    }))
+}
+
+/// Searches `base` for the neighbours of `queries` on the current thread.
+fn search_chunked(
+    base: &dyn DatasetSource,
+    queries: &[&[f32]],
+    masks: &[Option<&Bitmask>],
+    topk: usize,
+) -> Vec<TopKSearchResult> {
+    // Each heap holds the best neighbours found so far, with the furthest of them on top.
+    let mut heaps: Vec<BinaryHeap<Neighbour>> = (0..queries.len())
+        .map(|_| BinaryHeap::with_capacity(topk + 1))
+        .collect();
+
+    for start in (0..base.len()).step_by(CHUNK_VECTORS) {
+        let end = (start + CHUNK_VECTORS).min(base.len());
+        for ((query, mask), heap) in queries.iter().zip(masks).zip(&mut heaps) {
+            for id in start..end {
+                if mask.is_some_and(|mask| !mask.get(id)) {
+                    continue;
+                }
+                let neighbour = Neighbour {
+                    distance: squared_l2(query, base.get(id)),
+                    id,
+                };
+                if heap.len() < topk {
+                    heap.push(neighbour);
+                } else if neighbour < *heap.peek().unwrap() {
+                    heap.pop();
+                    heap.push(neighbour);
+                }
+            }
+        }
+    }
+
+    heaps
+        .into_iter()
+        .map(|heap| {
+            heap.into_sorted_vec()
+                .into_iter()
+                .map(|neighbour| (neighbour.id, neighbour.distance))
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_brute_force_search_batch() {
+        // The vectors lie on a line, so the nearest neighbours of a query are those closest to it.
+        let base = FlattenedVecs {
+            dimensionality: 2,
+            data: (0..10_000).flat_map(|i| [i as f32, 0.0]).collect(),
+        };
+        let queries = FlattenedVecs {
+            dimensionality: 2,
+            data: vec![5000.2, 0.0, 0.0, 1.0, 9999.0, 0.0],
+        };
+        let mut odd = Bitmask::empty(base.len());
+        (1..base.len()).step_by(2).for_each(|i| odd.set(i, true));
+        let mut one = Bitmask::empty(base.len());
+        one.set(42, true);
+
+        for threads in [1, 2, 8] {
+            let results = brute_force_search_batch(
+                &base,
+                &queries,
+                &[None, Some(&odd), Some(&one)],
+                3,
+                threads,
+            )
+            .unwrap();
+            let ids: Vec<Vec<usize>> = results
+                .iter()
+                .map(|result| result.iter().map(|&(id, _)| id).collect())
+                .collect();
+            assert_eq!(ids, [vec![5000, 5001, 4999], vec![1, 3, 5], vec![42]]);
+            assert_eq!(results[1][0].1, 2.0);
+        }
+
+        // Vectors at the same distance are ordered by id.
+        let tied = FlattenedVecs {
+            dimensionality: 2,
+            data: vec![4.0, 0.0],
+        };
+        let results = brute_force_search_batch(&base, &tied, &[None], 3, 1).unwrap();
+        assert_eq!(results[0], [(4, 0.0), (3, 1.0), (5, 1.0)]);
+
+        let short = Bitmask::full(base.len() - 1);
+        assert_eq!(
+            brute_force_search_batch(&base, &tied, &[Some(&short)], 3, 1),
+            Err(SearchableError::MaskSizeMismatch {
+                vectors: 10_000,
+                mask: 9_999
+            })
+        );
+        let wide = FlattenedVecs {
+            dimensionality: 3,
+            data: vec![4.0, 0.0, 0.0],
+        };
+        assert_eq!(
+            brute_force_search_batch(&base, &wide, &[None], 3, 1),
+            Err(SearchableError::DimensionalityMismatch {
+                expected: 2,
+                found: 3
+            })
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use crate::bitmask::Bitmask;
+use crate::dataset::{
+    check_batch_size, check_index_input, check_query_dimensionality, ConstructionError,
+    HybridSearchMetadata, MemoryUsage, OakIndexOptions, SearchableError, TopKSearchResult,
+};
+use crate::fvecs::FlattenedVecs;
+
+use hnsw_rs::prelude::*;
+use slog_scope::debug;
+use std::path::Path;
+
+/// hnsw_rs allows at most 16 layers in its graph.
+const MAX_LAYERS: usize = 16;
+
+/// Lets hnsw_rs consult a mask directly during graph traversal, so that it never needs to be
+/// expanded into a filter map as it does for ACORN.
+impl FilterT for Bitmask {
+    fn hnsw_filter(&self, id: &DataId) -> bool {
+        *id < self.capacity() && self.get(*id)
+    }
+}
+
+/// A pure Rust HNSW index, backed by hnsw_rs. It exposes the same methods as `AcornHnswIndex` so
+/// that datasets can use either interchangeably, but predicates are applied by filtering the
+/// graph traversal in Rust rather than through ACORN's predicate-aware graph.
+pub struct RustHnswIndex {
+    index: Hnsw<'static, f32, DistL2>,
+    count: usize,
+    dimensionality: usize,
+}
+
+impl RustHnswIndex {
+    /// Builds the index. hnsw_rs has no equivalent of ACORN's `gamma`, so `m` is used as the
+    /// maximum number of connections per node and `m_beta` as the construction-time `ef`. The
+    /// graph is not filtered by attributes during construction, so `metadata` is only checked to
+    /// have a row for each vector, as for ACORN.
+    pub fn new(
+        flattened: &FlattenedVecs,
+        metadata: &HybridSearchMetadata,
+        options: &OakIndexOptions,
+    ) -> Result<Self, ConstructionError> {
+        check_index_input(flattened, metadata)?;
+        let num_fvecs = flattened.len();
+        let index = Hnsw::<f32, DistL2>::new(
+            options.m as usize,
+            num_fvecs,
+            MAX_LAYERS,
+            options.m_beta as usize,
+            DistL2 {},
+        );
+        debug!(
+            "Constructed index with dimensionality: {}, m: {}, ef_construction: {}",
+            flattened.dimensionality, options.m, options.m_beta
+        );
+
+        debug!("Adding {num_fvecs} vectors to the index...");
+        let data_with_ids: Vec<(&[f32], usize)> = flattened
+            .data
+            .chunks_exact(flattened.dimensionality)
+            .zip(0..num_fvecs)
+            .collect();
+        index.parallel_insert_slice(&data_with_ids);
+        debug!("Added {num_fvecs} vectors to the index.");
+
+        Ok(Self {
+            index,
+            count: num_fvecs,
+            dimensionality: flattened.dimensionality,
+        })
+    }
+
+    /// Persisting indexes is only supported by the `hnsw_faiss` backend.
+    pub fn save_index(&self, path: &Path) -> Result<(), ConstructionError> {
+        Err(ConstructionError::IndexFileError {
+            path: path.to_path_buf(),
+            reason: "persisting indexes is not supported by the hnsw_rust backend".to_string(),
+        })
+    }
+
+    /// Persisting indexes is only supported by the `hnsw_faiss` backend.
+    pub fn load_index(path: &Path) -> Result<Self, ConstructionError> {
+        Err(ConstructionError::IndexFileError {
+            path: path.to_path_buf(),
+            reason: "persisting indexes is not supported by the hnsw_rust backend".to_string(),
+        })
+    }
+
+    /// The number of vectors that have been added to the index.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// The dimensionality of the vectors in the index.
+    pub fn get_dimensionality(&self) -> usize {
+        self.dimensionality
+    }
+
+    /// The memory used by the index, which keeps its own copy of the vectors alongside its graph.
+    /// hnsw_rs does not expose the size of its graph, so it is estimated from the neighbours of
+    /// each vector on the bottom layer, where there are up to twice as many as on other layers.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let neighbour_bytes = std::mem::size_of::<(usize, f32)>();
+        MemoryUsage {
+            vectors: self.count * self.dimensionality * std::mem::size_of::<f32>(),
+            graph: self.count * 2 * self.index.get_max_nb_connection() as usize * neighbour_bytes,
+            metadata: 0,
+        }
+    }
+
+    /// `mask` must have one bit for each vector in the index, and is applied to every query. If
+    /// there is no mask, the graph is traversed without filtering.
+    pub fn search(
+        &self,
+        query_vectors: &FlattenedVecs,
+        mask: Option<&Bitmask>,
+        k: usize,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        self.search_batch(query_vectors, &vec![mask; query_vectors.len()], k, efsearch)
+    }
+
+    /// Searches a batch of queries, each filtered by its own mask in `masks`, which must have one
+    /// entry for each query vector. Each mask must have one bit for each vector in the index; a
+    /// query without a mask is not filtered.
+    pub fn search_batch(
+        &self,
+        query_vectors: &FlattenedVecs,
+        masks: &[Option<&Bitmask>],
+        k: usize,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        check_query_dimensionality(query_vectors, self.dimensionality)?;
+        let number_of_query_vectors: usize = query_vectors.len();
+        debug!("Searching queries: {number_of_query_vectors} in batch.");
+        check_batch_size(query_vectors, masks)?;
+
+        if let Some(mask) = masks
+            .iter()
+            .flatten()
+            .find(|mask| mask.capacity() != self.count)
+        {
+            return Err(SearchableError::MaskSizeMismatch {
+                vectors: self.count,
+                mask: mask.capacity(),
+            });
+        }
+
+        // hnsw_rs needs the search-time `ef` to be at least `k`.
+        let ef = (efsearch.max(0) as usize).max(k);
+
+        let results = query_vectors
+            .data
+            .chunks_exact(query_vectors.dimensionality)
+            .zip(masks)
+            .map(|(query, mask)| {
+                self.index
+                    .search_filter(query, k, ef, mask.map(|mask| mask as &dyn FilterT))
+                    .into_iter()
+                    // FAISS reports squared L2 distances, so these are squared as well to keep
+                    // results comparable between the two backends.
+                    .map(|n| (n.d_id, n.distance * n.distance))
+                    .collect()
+            })
+            .collect();
+
+        debug!("Search complete");
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::SimilaritySearchable;
+    use crate::fvecs::{AttributeCsv, FvecsDataset};
+
+    #[test]
+    fn test_search_finds_query_vector() {
+        let mut dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::Skip).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+
+        let dimensionality = dataset.get_dimensionality();
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.vectors().get(0).to_vec(),
+        };
+        let result = dataset
+            .search_with_bitmask(&query_vector, &Bitmask::new_full(&dataset), 1, 16)
+            .unwrap();
+
+        assert_eq!(result[0][0].0, 0);
+    }
+
+    #[test]
+    fn test_search_respects_filter() {
+        let dataset = FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::Skip).unwrap();
+        let index = RustHnswIndex::new(
+            &dataset.vectors().to_flattened(),
+            &dataset.metadata,
+            &OakIndexOptions::default(),
+        )
+        .unwrap();
+
+        let dimensionality = dataset.get_dimensionality();
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.vectors().get(0).to_vec(),
+        };
+        let mask: Bitmask = (0..dataset.len()).map(|i| i % 2 == 1).collect();
+        let result = index.search(&query_vector, Some(&mask), 10, 16).unwrap();
+
+        assert!(!result[0].is_empty());
+        assert!(result[0].iter().all(|(id, _)| id % 2 == 1));
+    }
+}
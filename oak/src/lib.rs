@@ -1,12 +1,33 @@
+// Ensure that exactly one of FAISS or hnsw_rs is used.
+#[cfg(all(feature = "hnsw_faiss", feature = "hnsw_rust"))]
+compile_error!(
+    "Features `hnsw_faiss` and `hnsw_rust` cannot be enabled at the same time. Please enable only one."
+);
+#[cfg(not(any(feature = "hnsw_faiss", feature = "hnsw_rust")))]
+compile_error!("One of the features `hnsw_faiss` or `hnsw_rust` must be enabled.");
+// .fvecs files are little-endian, and their vectors are read in place rather than decoded.
+#[cfg(target_endian = "big")]
+compile_error!("OAK only supports little-endian targets.");
+
+#[cfg(feature = "hnsw_faiss")]
 pub mod acorn;
 pub mod bitmask;
 pub mod dataset;
 pub mod fvecs;
+pub mod groundtruth;
+#[cfg(feature = "hnsw_rust")]
+pub mod hnsw;
+pub mod npy;
 pub mod predicate;
 pub mod router;
 pub mod stubs;
+pub mod workload;
 
+// Every function returns a `Result`, so that exceptions thrown by FAISS are returned as errors
+// rather than aborting the process.
+#[cfg(feature = "hnsw_faiss")]
 #[cxx::bridge(namespace = "faiss")]
+#[allow(clippy::missing_safety_doc, clippy::too_many_arguments)]
 pub mod ffi {
     unsafe extern "C++" {
         include!("oak/third_party/ACORN/faiss/IndexACORN.h");
@@ -19,25 +40,38 @@ pub mod ffi {
             gamma: i32,
             M_beta: i32,
             metadata: &Vec<i32>,
-        ) -> UniquePtr<IndexACORNFlat>;
+        ) -> Result<UniquePtr<IndexACORNFlat>>;
 
         unsafe fn add_to_index(
             idx: &mut UniquePtr<IndexACORNFlat>,
             n: i64,        // number of vectors to be added
             x: *const f32, // raw pointer to the contiguous array of vectors
-        );
+        ) -> Result<()>;
 
         unsafe fn search_index(
             idx: &UniquePtr<IndexACORNFlat>,
-            n: i64,              // number of query vectors
-            x: *const f32,       // pointer to an array of the query vectors
-            k: i64,              // number of vectors to return for each query vector
+            n: i64,                       // number of query vectors
+            x: *const f32,                // pointer to an array of the query vectors
+            k: i64,                       // number of vectors to return for each query vector
             distances: *mut f32, // pointer to an array of (k*n) floats, each representing a distance of the result from the query vector
             labels: *mut i64, // pointer to an array of (k*n) indices, each representing the ID of the query vector in idx
-            // NOTE: this is mut because of the way that ACORN search is implemented in C++. It
-            // probably shouldn't be.
-            filter_id_map: *mut c_char, // a bitmap of the IDs in the filter, an array of (n * N) bools, where N is the total number of vectors in the index, and a '1' represents that the vector at that index passes the predicate for that query.
-            efsearch: i64,              // the search-time parameter to tweak recall
+            filter_id_map: *const c_char, // a bitmap of the IDs in the filter, an array of (n * N) bools, where N is the total number of vectors in the index, and a '1' represents that the vector at that index passes the predicate for that query.
+            efsearch: i64, // the search-time parameter to tweak recall, which only applies to this search
+        ) -> Result<()>;
+
+        fn write_index_to_file(
+            idx: &UniquePtr<IndexACORNFlat>,
+            path: &str, // the file to write the index to, which is overwritten if it exists
         ) -> Result<()>;
+
+        fn read_index_from_file(
+            path: &str, // the file to read an index written by `write_index_to_file` from
+        ) -> Result<UniquePtr<IndexACORNFlat>>;
+
+        fn index_dimensionality(idx: &UniquePtr<IndexACORNFlat>) -> Result<i32>;
+
+        fn index_count(idx: &UniquePtr<IndexACORNFlat>) -> Result<i64>;
+
+        fn index_graph_bytes(idx: &UniquePtr<IndexACORNFlat>) -> Result<usize>;
     }
 }
@@ -0,0 +1,270 @@
+use crate::fvecs::FlattenedVecs;
+
+use anyhow::Result;
+use byteorder::{ByteOrder, LittleEndian};
+use slog_scope::debug;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use thiserror::Error;
+
+/// Every .npy file starts with these bytes, followed by the major and minor version of the format.
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+/// The errors that can be returned from reading a NumPy array as vectors.
+#[derive(Error, Debug, PartialEq)]
+pub enum NpyError {
+    #[error("The file is not a .npy file, as it does not start with the NumPy magic string")]
+    NotNpy,
+    #[error("Version {0}.{1} of the .npy format is not supported")]
+    UnsupportedVersion(u8, u8),
+    #[error("The header is malformed: {0}")]
+    MalformedHeader(String),
+    #[error(
+        "Arrays of type `{0}` are not supported; only little-endian float32 (`<f4`) arrays are"
+    )]
+    UnsupportedType(String),
+    #[error("Arrays in Fortran order are not supported")]
+    FortranOrder,
+    #[error("Only two-dimensional arrays can be read as vectors, but the array has shape {0:?}")]
+    UnsupportedShape(Vec<usize>),
+    #[error("An array of shape ({rows}, {columns}) should have {expected} bytes of data, but it has {found}")]
+    Truncated {
+        rows: usize,
+        columns: usize,
+        expected: usize,
+        found: usize,
+    },
+    #[error("The archive has no array named `{0}`")]
+    MissingArray(String),
+}
+
+/// The value of `key` in the header of a .npy file, which is the text of a Python dict literal such
+/// as `{'descr': '<f4', 'fortran_order': False, 'shape': (1000, 128), }`.
+fn header_field<'h>(header: &'h str, key: &str) -> Result<&'h str, NpyError> {
+    let missing = || NpyError::MalformedHeader(format!("`{key}` is missing"));
+    let quoted_key = format!("'{key}':");
+    let start = header.find(&quoted_key).ok_or_else(missing)? + quoted_key.len();
+    let rest = header[start..].trim_start();
+    // The shape is a tuple, so contains commas of its own.
+    let end = if rest.starts_with('(') {
+        rest.find(')').map(|i| i + 1)
+    } else {
+        rest.find([',', '}'])
+    };
+    Ok(rest[..end.ok_or_else(missing)?].trim())
+}
+
+/// Parses the shape of an array, such as `(1000, 128)`, or `(1000,)` for a single dimension.
+fn parse_shape(shape: &str) -> Result<Vec<usize>, NpyError> {
+    shape
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .split(',')
+        .map(str::trim)
+        .filter(|dimension| !dimension.is_empty())
+        .map(|dimension| {
+            dimension
+                .parse()
+                .map_err(|_| NpyError::MalformedHeader(format!("shape {shape} is not valid")))
+        })
+        .collect()
+}
+
+/// Reads the contents of a .npy file as vectors, one per row of a two-dimensional float32 array.
+pub fn parse_npy(bytes: &[u8]) -> Result<FlattenedVecs, NpyError> {
+    if bytes.len() < MAGIC.len() + 2 || !bytes.starts_with(MAGIC) {
+        return Err(NpyError::NotNpy);
+    }
+    let (major, minor) = (bytes[MAGIC.len()], bytes[MAGIC.len() + 1]);
+    let rest = &bytes[MAGIC.len() + 2..];
+
+    // Version 1 stores the length of the header in 2 bytes, and later versions in 4.
+    let (header_len, rest) = match major {
+        1 if rest.len() >= 2 => (LittleEndian::read_u16(rest) as usize, &rest[2..]),
+        2 | 3 if rest.len() >= 4 => (LittleEndian::read_u32(rest) as usize, &rest[4..]),
+        1..=3 => return Err(NpyError::MalformedHeader("it is truncated".to_string())),
+        _ => return Err(NpyError::UnsupportedVersion(major, minor)),
+    };
+    if rest.len() < header_len {
+        return Err(NpyError::MalformedHeader("it is truncated".to_string()));
+    }
+    let header = std::str::from_utf8(&rest[..header_len])
+        .map_err(|_| NpyError::MalformedHeader("it is not valid text".to_string()))?;
+    let data = &rest[header_len..];
+
+    let descr = header_field(header, "descr")?.trim_matches(|c| c == '\'' || c == '"');
+    if descr != "<f4" {
+        return Err(NpyError::UnsupportedType(descr.to_string()));
+    }
+    if header_field(header, "fortran_order")? != "False" {
+        return Err(NpyError::FortranOrder);
+    }
+    let shape = parse_shape(header_field(header, "shape")?)?;
+    let &[rows, columns] = shape.as_slice() else {
+        return Err(NpyError::UnsupportedShape(shape));
+    };
+
+    let expected = rows
+        .checked_mul(columns)
+        .and_then(|values| values.checked_mul(std::mem::size_of::<f32>()))
+        .ok_or_else(|| {
+            NpyError::MalformedHeader(format!("shape ({rows}, {columns}) is too large"))
+        })?;
+    if data.len() != expected {
+        return Err(NpyError::Truncated {
+            rows,
+            columns,
+            expected,
+            found: data.len(),
+        });
+    }
+
+    let mut values = vec![0.0; rows * columns];
+    LittleEndian::read_f32_into(data, &mut values);
+    Ok(FlattenedVecs {
+        dimensionality: columns,
+        data: values,
+    })
+}
+
+/// Reads a .npy file holding a two-dimensional float32 array, with one vector per row.
+pub fn read_npy(path: &Path) -> Result<FlattenedVecs> {
+    let vectors = parse_npy(&std::fs::read(path)?)?;
+    debug!(
+        "The file read has {} vectors of dimensionality {}.",
+        vectors.len(),
+        vectors.dimensionality
+    );
+    Ok(vectors)
+}
+
+/// Reads the array called `name` from a .npz archive, as written by `numpy.savez` or
+/// `numpy.savez_compressed`, which must be a two-dimensional float32 array with one vector per
+/// row. If no name is given, the first array in the archive is read.
+pub fn read_npz(path: &Path, name: Option<&str>) -> Result<FlattenedVecs> {
+    let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+    let mut entry = match name {
+        Some(name) => archive
+            .by_name(&format!("{name}.npy"))
+            .map_err(|_| NpyError::MissingArray(name.to_string()))?,
+        None => archive.by_index(0)?,
+    };
+    debug!("Reading array {} from {}.", entry.name(), path.display());
+
+    let mut bytes = Vec::with_capacity(entry.size() as usize);
+    entry.read_to_end(&mut bytes)?;
+    Ok(parse_npy(&bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    /// Encodes a version 1 .npy file with the given header dict and data.
+    fn encode_npy(header: &str, data: &[u8]) -> Vec<u8> {
+        // NumPy pads the header with spaces and a newline so that the data is aligned.
+        let unpadded = MAGIC.len() + 4 + header.len() + 1;
+        let header = format!("{header}{}\n", " ".repeat((64 - unpadded % 64) % 64));
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([1, 0]);
+        bytes.extend((header.len() as u16).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    fn encode_values(values: &[f32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_npy() {
+        let values = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+        let npy = encode_npy(
+            "{'descr': '<f4', 'fortran_order': False, 'shape': (3, 2), }",
+            &encode_values(&values),
+        );
+        let vectors = parse_npy(&npy).unwrap();
+        assert_eq!(vectors.dimensionality, 2);
+        assert_eq!(vectors.data, values);
+
+        let doubles = encode_npy(
+            "{'descr': '<f8', 'fortran_order': False, 'shape': (3, 1), }",
+            &encode_values(&values),
+        );
+        assert_eq!(
+            parse_npy(&doubles).err(),
+            Some(NpyError::UnsupportedType("<f8".to_string()))
+        );
+
+        let flat = encode_npy(
+            "{'descr': '<f4', 'fortran_order': False, 'shape': (6,), }",
+            &encode_values(&values),
+        );
+        assert_eq!(
+            parse_npy(&flat).err(),
+            Some(NpyError::UnsupportedShape(vec![6]))
+        );
+
+        let truncated = encode_npy(
+            "{'descr': '<f4', 'fortran_order': False, 'shape': (4, 2), }",
+            &encode_values(&values),
+        );
+        assert_eq!(
+            parse_npy(&truncated).err(),
+            Some(NpyError::Truncated {
+                rows: 4,
+                columns: 2,
+                expected: 32,
+                found: 24
+            })
+        );
+
+        let huge = encode_npy(
+            &format!(
+                "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, 2), }}",
+                usize::MAX / 2
+            ),
+            &[],
+        );
+        assert!(matches!(
+            parse_npy(&huge),
+            Err(NpyError::MalformedHeader(_))
+        ));
+
+        assert_eq!(parse_npy(b"not numpy").err(), Some(NpyError::NotNpy));
+    }
+
+    #[test]
+    fn test_read_npz() {
+        let path = std::env::temp_dir().join("oak_test_read_npz.npz");
+        let mut writer = zip::ZipWriter::new(File::create(&path).unwrap());
+        for (name, values) in [("queries", [1.0, 2.0]), ("base", [3.0, 4.0])] {
+            writer
+                .start_file(format!("{name}.npy"), SimpleFileOptions::default())
+                .unwrap();
+            let npy = encode_npy(
+                "{'descr': '<f4', 'fortran_order': False, 'shape': (1, 2), }",
+                &encode_values(&values),
+            );
+            writer.write_all(&npy).unwrap();
+        }
+        writer.finish().unwrap();
+
+        assert_eq!(read_npz(&path, None).unwrap().data, [1.0, 2.0]);
+        assert_eq!(read_npz(&path, Some("base")).unwrap().data, [3.0, 4.0]);
+        let error = read_npz(&path, Some("groundtruth")).err().unwrap();
+        assert_eq!(
+            error.downcast_ref::<NpyError>(),
+            Some(&NpyError::MissingArray("groundtruth".to_string()))
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,55 +1,796 @@
+use crate::dataset::AttributeColumn;
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
 /// ACORN specifies the predicates for queries as one bitmap per query, where the bitmap is an
 /// array of length N (the number of total entries in the database). This is presumably so that
 /// ACORN is capable of arbitrary predicates; but it does mean that the query language for the cpp
 /// API is somewhat unwieldy.
 ///
 /// Thus here we allow a more concise representation of predicates with a basic query language that
-/// we can develop as needed. For the POC, the query language _only_ accepts equality for a
-/// singular match. The assumption is also that each vector has one and only one attribute (a u8).
-/// At a first order of approximation, this language accepts constructs with two tokens,
-/// PredicateOp, and PredicateRhs. A basic example: to express the idea that we only want to
-/// retrieve queries "where the attribute matches '10'", we would construct a query as follows:
+/// we can develop as needed. A predicate is a tree whose leaves each compare one attribute (the
+/// `lhs`) using a PredicateOp, and whose inner nodes combine their children with `And`, `Or` and
+/// `Not`. A basic example: to express the idea that we only want to retrieve queries "where the
+/// attribute `category` matches '10' and `price` is below 5.5", we would construct a query as
+/// follows:
+///
+/// query = PredicateQuery::And(vec![
+///     PredicateQuery::Attribute {
+///         lhs: "category".to_string(),
+///         op: PredicateOp::Equals(PredicateRhs::Number(10)),
+///     },
+///     PredicateQuery::Attribute {
+///         lhs: "price".to_string(),
+///         op: PredicateOp::LessThan(PredicateRhs::Float(5.5)),
+///     },
+/// ])
+///
+/// The `lhs` names a column in the CSV that the dataset was loaded with. Columns can also be
+/// referred to by their position, as `attr0`, `attr1`, and so on, which is how the columns of a
+/// CSV without a header are named.
+///
+/// Predicates can also be parsed from a small SQL-like language, as in
+/// `"category = 10 AND price < 5.5".parse::<PredicateQuery>()`. The grammar is:
 ///
-/// query = PredicateQuery {
-///     op: PredicateOp::Equals,
-///     rhs: PredicateRhs::Number(10),
-/// }
+/// expr       := and ("OR" and)*
+/// and        := unary ("AND" unary)*
+/// unary      := "NOT" unary | "(" expr ")" | comparison
+/// comparison := attr ("=" | "!=" | "<>" | "<" | "<=" | ">" | ">=") value
+///             | attr "BETWEEN" value "AND" value
+///             | attr ["NOT"] "IN" "(" value ("," value)* ")"
+/// value      := integer | float | 'text' | "text"
 ///
-/// Only u8 numbers are supported for names and predicate values at present, as the assumption is
-/// that a dataset will be loaded in with a CSV with columns whose titles are all u8 (the `lhs` of
-/// a PredicateQuery), and whose values are _also_ all u8s (the `rhs`).
+/// Keywords are case-insensitive, and `AND` binds more tightly than `OR`. Text can be quoted with
+/// either kind of quote, and a quote of the same kind is included in it by writing it twice, as in
+/// `'it''s'`. Equality is written as `=`; `==` is not accepted.
 ///
-/// If necessary, we can expand this later.
-#[derive(Clone)]
-pub struct PredicateQuery {
-    pub op: PredicateOp,
-    pub rhs: PredicateRhs,
+/// As in SQL, a comparison with a value that cannot be compared, such as NaN, is neither true nor
+/// false, and so does not match, and neither does its negation: a NaN price matches none of
+/// `price = 9`, `price != 9`, `NOT price = 9` and `NOT price != 9`. `AND` and `OR` follow SQL's
+/// three-valued logic, so `price = 9 OR category = 1` still matches a vector in category 1.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PredicateQuery {
+    /// Matches vectors whose attribute `lhs` satisfies `op`.
+    Attribute { lhs: String, op: PredicateOp },
+    /// Matches vectors that match all of the predicates. An empty `And` matches every vector.
+    And(Vec<PredicateQuery>),
+    /// Matches vectors that match any of the predicates. An empty `Or` matches no vector.
+    Or(Vec<PredicateQuery>),
+    /// Matches vectors that do not match the predicate, other than those for which the predicate
+    /// compares a value that cannot be compared, such as NaN.
+    Not(Box<PredicateQuery>),
 }
 
-#[derive(Clone)]
+/// A comparison of an attribute against one or more values.
+#[derive(Clone, Debug, PartialEq)]
 pub enum PredicateOp {
-    Equals,
+    Equals(PredicateRhs),
+    NotEquals(PredicateRhs),
+    LessThan(PredicateRhs),
+    LessThanOrEquals(PredicateRhs),
+    GreaterThan(PredicateRhs),
+    GreaterThanOrEquals(PredicateRhs),
+    /// Matches values between the two bounds, inclusive of both.
+    Between(PredicateRhs, PredicateRhs),
+    /// Matches values equal to any of the values in the set.
+    In(Vec<PredicateRhs>),
 }
 
-#[derive(Clone)]
+/// A value that an attribute can be compared against.
+#[derive(Clone, Debug, PartialEq)]
 pub enum PredicateRhs {
-    Number(u8),
+    Number(i64),
+    Float(f64),
+    Text(String),
 }
 
-impl From<&PredicateRhs> for i32 {
-    fn from(value: &PredicateRhs) -> Self {
-        match value {
-            PredicateRhs::Number(num) => *num as i32,
+impl PredicateQuery {
+    /// Creates a new PredicateQuery where the first attribute is equal to the provided argument
+    /// `num`.
+    pub fn new(num: u8) -> Self {
+        Self::equals("attr0", PredicateRhs::Number(num as i64))
+    }
+
+    /// Creates a new PredicateQuery where the attribute `lhs` is equal to `rhs`.
+    pub fn equals(lhs: &str, rhs: PredicateRhs) -> Self {
+        Self::Attribute {
+            lhs: lhs.to_string(),
+            op: PredicateOp::Equals(rhs),
+        }
+    }
+
+    /// Whether every vector that matches this predicate can be shown to also match `other`,
+    /// without evaluating either over a dataset: for example, `attr = 5` implies
+    /// `attr IN (4, 5, 6)`. This is conservative, so `false` means only that the implication
+    /// could not be proven, not that it does not hold.
+    pub fn implies(&self, other: &PredicateQuery) -> bool {
+        if self == other {
+            return true;
+        }
+        match (self, other) {
+            (_, PredicateQuery::And(children)) => children.iter().all(|child| self.implies(child)),
+            (PredicateQuery::Or(children), _) => children.iter().all(|child| child.implies(other)),
+            (PredicateQuery::And(children), _) => children.iter().any(|child| child.implies(other)),
+            (_, PredicateQuery::Or(children)) => children.iter().any(|child| self.implies(child)),
+            (
+                PredicateQuery::Attribute { lhs, op },
+                PredicateQuery::Attribute {
+                    lhs: other_lhs,
+                    op: other_op,
+                },
+            ) => lhs == other_lhs && op.implies(other_op),
+            _ => false,
         }
     }
 }
 
-impl PredicateQuery {
-    /// Creates a new PredicateQuery where the attribute is equal to the provided argument `num`.
-    pub fn new(num: u8) -> Self {
-        Self {
-            op: PredicateOp::Equals,
-            rhs: PredicateRhs::Number(num),
+/// The values satisfying a comparison, as a range. Each bound is the value and whether it is
+/// included; a missing bound is unbounded.
+struct Interval<'a> {
+    lower: Option<(&'a PredicateRhs, bool)>,
+    upper: Option<(&'a PredicateRhs, bool)>,
+}
+
+impl Interval<'_> {
+    fn contains_value(&self, value: &PredicateRhs) -> bool {
+        let above_lower = self
+            .lower
+            .is_none_or(|(bound, inclusive)| match value.compare(bound) {
+                Some(Ordering::Greater) => true,
+                Some(Ordering::Equal) => inclusive,
+                _ => false,
+            });
+        let below_upper = self
+            .upper
+            .is_none_or(|(bound, inclusive)| match value.compare(bound) {
+                Some(Ordering::Less) => true,
+                Some(Ordering::Equal) => inclusive,
+                _ => false,
+            });
+        above_lower && below_upper
+    }
+
+    /// Whether every value in `inner` is also in this interval.
+    fn contains(&self, inner: &Interval) -> bool {
+        // A bound of `inner` is within a bound of `self` if it is strictly tighter, or the same
+        // value where `self` includes it or `inner` excludes it.
+        let within = |outer: Option<(&PredicateRhs, bool)>,
+                      inner: Option<(&PredicateRhs, bool)>,
+                      tighter: Ordering| match (outer, inner) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some((outer, outer_inclusive)), Some((inner, inner_inclusive))) => {
+                match inner.compare(outer) {
+                    Some(Ordering::Equal) => outer_inclusive || !inner_inclusive,
+                    Some(ordering) => ordering == tighter,
+                    None => false,
+                }
+            }
+        };
+        within(self.lower, inner.lower, Ordering::Greater)
+            && within(self.upper, inner.upper, Ordering::Less)
+    }
+}
+
+impl PredicateRhs {
+    /// Compares two values in the same way that `AttributeColumn::compare` compares an attribute
+    /// with a value, so that integers and floats can be compared with each other.
+    fn compare(&self, other: &PredicateRhs) -> Option<Ordering> {
+        match (self, other) {
+            (PredicateRhs::Number(a), PredicateRhs::Number(b)) => Some(a.cmp(b)),
+            (PredicateRhs::Number(a), PredicateRhs::Float(b)) => (*a as f64).partial_cmp(b),
+            (PredicateRhs::Float(a), PredicateRhs::Number(b)) => a.partial_cmp(&(*b as f64)),
+            (PredicateRhs::Float(a), PredicateRhs::Float(b)) => a.partial_cmp(b),
+            (PredicateRhs::Text(a), PredicateRhs::Text(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+}
+
+impl PredicateOp {
+    /// The range of values that the comparison matches, if it matches a single range.
+    fn interval(&self) -> Option<Interval<'_>> {
+        let (lower, upper) = match self {
+            PredicateOp::Equals(rhs) => (Some((rhs, true)), Some((rhs, true))),
+            PredicateOp::LessThan(rhs) => (None, Some((rhs, false))),
+            PredicateOp::LessThanOrEquals(rhs) => (None, Some((rhs, true))),
+            PredicateOp::GreaterThan(rhs) => (Some((rhs, false)), None),
+            PredicateOp::GreaterThanOrEquals(rhs) => (Some((rhs, true)), None),
+            PredicateOp::Between(low, high) => (Some((low, true)), Some((high, true))),
+            PredicateOp::NotEquals(_) | PredicateOp::In(_) => return None,
+        };
+        Some(Interval { lower, upper })
+    }
+
+    /// Whether every value that satisfies this comparison can be shown to also satisfy `other`.
+    /// See `PredicateQuery::implies`.
+    fn implies(&self, other: &PredicateOp) -> bool {
+        if let PredicateOp::In(values) = self {
+            return values
+                .iter()
+                .all(|value| PredicateOp::Equals(value.clone()).implies(other));
+        }
+        match (self, other) {
+            (PredicateOp::NotEquals(a), PredicateOp::NotEquals(b)) => {
+                a.compare(b) == Some(Ordering::Equal)
+            }
+            (PredicateOp::Equals(value), PredicateOp::In(values)) => values
+                .iter()
+                .any(|other| value.compare(other) == Some(Ordering::Equal)),
+            (_, PredicateOp::NotEquals(excluded)) => {
+                // The excluded value must be comparable, and outside of the range.
+                self.interval().is_some_and(|interval| {
+                    self.operands()
+                        .iter()
+                        .all(|rhs| rhs.compare(excluded).is_some())
+                        && !interval.contains_value(excluded)
+                })
+            }
+            _ => match (self.interval(), other.interval()) {
+                (Some(inner), Some(outer)) => outer.contains(&inner),
+                _ => false,
+            },
+        }
+    }
+
+    /// The values that the attribute is compared against.
+    pub fn operands(&self) -> Vec<&PredicateRhs> {
+        match self {
+            PredicateOp::Equals(rhs)
+            | PredicateOp::NotEquals(rhs)
+            | PredicateOp::LessThan(rhs)
+            | PredicateOp::LessThanOrEquals(rhs)
+            | PredicateOp::GreaterThan(rhs)
+            | PredicateOp::GreaterThanOrEquals(rhs) => vec![rhs],
+            PredicateOp::Between(low, high) => vec![low, high],
+            PredicateOp::In(values) => values.iter().collect(),
+        }
+    }
+
+    /// Whether the value of the `i`th vector in `column` can be compared with every operand, so
+    /// that the comparison is either true or false. Otherwise, neither it nor its negation
+    /// matches the vector.
+    pub fn is_known(&self, column: &AttributeColumn, i: usize) -> bool {
+        self.operands()
+            .into_iter()
+            .all(|rhs| column.compare(i, rhs).is_some())
+    }
+
+    /// Whether the value of the `i`th vector in `column` satisfies the comparison. Values that
+    /// cannot be compared (such as NaN) never match, not even for `NotEquals`.
+    pub fn matches(&self, column: &AttributeColumn, i: usize) -> bool {
+        let cmp = |rhs: &PredicateRhs| column.compare(i, rhs);
+        match self {
+            PredicateOp::Equals(rhs) => cmp(rhs) == Some(Ordering::Equal),
+            PredicateOp::NotEquals(rhs) => {
+                matches!(cmp(rhs), Some(Ordering::Less | Ordering::Greater))
+            }
+            PredicateOp::LessThan(rhs) => cmp(rhs) == Some(Ordering::Less),
+            PredicateOp::LessThanOrEquals(rhs) => {
+                matches!(cmp(rhs), Some(Ordering::Less | Ordering::Equal))
+            }
+            PredicateOp::GreaterThan(rhs) => cmp(rhs) == Some(Ordering::Greater),
+            PredicateOp::GreaterThanOrEquals(rhs) => {
+                matches!(cmp(rhs), Some(Ordering::Greater | Ordering::Equal))
+            }
+            PredicateOp::Between(low, high) => {
+                matches!(cmp(low), Some(Ordering::Greater | Ordering::Equal))
+                    && matches!(cmp(high), Some(Ordering::Less | Ordering::Equal))
+            }
+            PredicateOp::In(values) => values.iter().any(|rhs| cmp(rhs) == Some(Ordering::Equal)),
+        }
+    }
+}
+
+/// The error returned when a predicate cannot be parsed from a string. `position` is the byte
+/// offset in the string at which the problem was found.
+#[derive(Error, Debug, PartialEq)]
+#[error("Invalid predicate at position {position}: {message}")]
+pub struct PredicateParseError {
+    pub position: usize,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Number(i64),
+    Float(f64),
+    Text(String),
+    Op(&'static str),
+    LeftParen,
+    RightParen,
+    Comma,
+}
+
+const OPS: [&str; 7] = ["!=", "<>", "<=", ">=", "=", "<", ">"];
+
+/// Splits a predicate string into tokens, each paired with the byte offset at which it starts.
+fn tokenize(input: &str) -> Result<Vec<(Token, usize)>, PredicateParseError> {
+    let bytes = input.as_bytes();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let c = bytes[i] as char;
+        let start = i;
+
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let token = match c {
+            '(' => {
+                i += 1;
+                Token::LeftParen
+            }
+            ')' => {
+                i += 1;
+                Token::RightParen
+            }
+            ',' => {
+                i += 1;
+                Token::Comma
+            }
+            '\'' | '"' => {
+                // A doubled quote stands for one quote within the text, rather than ending it.
+                let mut text = String::new();
+                i += 1;
+                loop {
+                    let end = input[i..].find(c).ok_or_else(|| PredicateParseError {
+                        position: start,
+                        message: "unterminated string".to_string(),
+                    })?;
+                    text.push_str(&input[i..i + end]);
+                    i += end + 1;
+                    if bytes.get(i) == Some(&(c as u8)) {
+                        text.push(c);
+                        i += 1;
+                    } else {
+                        break;
+                    }
+                }
+                Token::Text(text)
+            }
+            c if c.is_ascii_digit()
+                || (c == '-' && bytes.get(i + 1).is_some_and(|b| b.is_ascii_digit())) =>
+            {
+                i += 1;
+                let mut is_float = false;
+                while i < bytes.len() {
+                    match bytes[i] {
+                        b'0'..=b'9' => {}
+                        b'.' | b'e' | b'E' => is_float = true,
+                        b'+' | b'-' if matches!(bytes[i - 1], b'e' | b'E') => {}
+                        _ => break,
+                    }
+                    i += 1;
+                }
+                let text = &input[start..i];
+                let invalid = || PredicateParseError {
+                    position: start,
+                    message: format!("invalid number `{text}`"),
+                };
+                if is_float {
+                    Token::Float(text.parse().map_err(|_| invalid())?)
+                } else {
+                    Token::Number(text.parse().map_err(|_| invalid())?)
+                }
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                Token::Word(input[start..i].to_string())
+            }
+            _ => match OPS.iter().find(|op| input[i..].starts_with(*op)) {
+                Some(op) => {
+                    i += op.len();
+                    Token::Op(op)
+                }
+                None => {
+                    return Err(PredicateParseError {
+                        position: start,
+                        message: format!(
+                            "unexpected character `{}`",
+                            &input[i..].chars().next().unwrap()
+                        ),
+                    })
+                }
+            },
+        };
+        tokens.push((token, start));
+    }
+
+    Ok(tokens)
+}
+
+fn is_keyword(word: &str) -> bool {
+    ["AND", "OR", "NOT", "IN", "BETWEEN"]
+        .iter()
+        .any(|keyword| word.eq_ignore_ascii_case(keyword))
+}
+
+/// A recursive descent parser over the grammar documented on `PredicateQuery`.
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    next: usize,
+    /// The length of the input, which is the position reported for errors at its end.
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(token, _)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map_or(self.end, |(_, position)| *position)
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, PredicateParseError> {
+        Err(PredicateParseError {
+            position: self.position(),
+            message,
+        })
+    }
+
+    fn describe_next(&self) -> String {
+        match self.peek() {
+            None => "the end of the predicate".to_string(),
+            Some(Token::Word(word)) => format!("`{word}`"),
+            Some(Token::Number(n)) => format!("`{n}`"),
+            Some(Token::Float(f)) => format!("`{f}`"),
+            Some(Token::Text(text)) => format!("'{text}'"),
+            Some(Token::Op(op)) => format!("`{op}`"),
+            Some(Token::LeftParen) => "`(`".to_string(),
+            Some(Token::RightParen) => "`)`".to_string(),
+            Some(Token::Comma) => "`,`".to_string(),
         }
     }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.next += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), PredicateParseError> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            self.error(format!(
+                "expected `{keyword}` but found {}",
+                self.describe_next()
+            ))
+        }
+    }
+
+    fn expect(&mut self, expected: Token, name: &str) -> Result<(), PredicateParseError> {
+        if self.peek() == Some(&expected) {
+            self.next += 1;
+            Ok(())
+        } else {
+            self.error(format!(
+                "expected {name} but found {}",
+                self.describe_next()
+            ))
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<PredicateQuery, PredicateParseError> {
+        let mut children = vec![self.parse_and()?];
+        while self.eat_keyword("OR") {
+            children.push(self.parse_and()?);
+        }
+        Ok(if children.len() == 1 {
+            children.pop().unwrap()
+        } else {
+            PredicateQuery::Or(children)
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<PredicateQuery, PredicateParseError> {
+        let mut children = vec![self.parse_unary()?];
+        while self.eat_keyword("AND") {
+            children.push(self.parse_unary()?);
+        }
+        Ok(if children.len() == 1 {
+            children.pop().unwrap()
+        } else {
+            PredicateQuery::And(children)
+        })
+    }
+
+    fn parse_unary(&mut self) -> Result<PredicateQuery, PredicateParseError> {
+        if self.eat_keyword("NOT") {
+            return Ok(PredicateQuery::Not(Box::new(self.parse_unary()?)));
+        }
+        if self.peek() == Some(&Token::LeftParen) {
+            self.next += 1;
+            let query = self.parse_or()?;
+            self.expect(Token::RightParen, "`)`")?;
+            return Ok(query);
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<PredicateQuery, PredicateParseError> {
+        let lhs = match self.peek() {
+            Some(Token::Word(word)) if !is_keyword(word) => word.clone(),
+            _ => {
+                return self.error(format!(
+                    "expected an attribute but found {}",
+                    self.describe_next()
+                ))
+            }
+        };
+        self.next += 1;
+
+        let op = if self.eat_keyword("BETWEEN") {
+            let low = self.parse_value()?;
+            self.expect_keyword("AND")?;
+            PredicateOp::Between(low, self.parse_value()?)
+        } else if self.eat_keyword("IN") {
+            PredicateOp::In(self.parse_list()?)
+        } else if self.eat_keyword("NOT") {
+            self.expect_keyword("IN")?;
+            let op = PredicateOp::In(self.parse_list()?);
+            return Ok(PredicateQuery::Not(Box::new(PredicateQuery::Attribute {
+                lhs,
+                op,
+            })));
+        } else if let Some(Token::Op(op)) = self.peek() {
+            let op = *op;
+            self.next += 1;
+            let rhs = self.parse_value()?;
+            match op {
+                "=" => PredicateOp::Equals(rhs),
+                "!=" | "<>" => PredicateOp::NotEquals(rhs),
+                "<" => PredicateOp::LessThan(rhs),
+                "<=" => PredicateOp::LessThanOrEquals(rhs),
+                ">" => PredicateOp::GreaterThan(rhs),
+                _ => PredicateOp::GreaterThanOrEquals(rhs),
+            }
+        } else {
+            return self.error(format!(
+                "expected a comparison, `BETWEEN` or `IN` after `{lhs}` but found {}",
+                self.describe_next()
+            ));
+        };
+
+        Ok(PredicateQuery::Attribute { lhs, op })
+    }
+
+    fn parse_value(&mut self) -> Result<PredicateRhs, PredicateParseError> {
+        let value = match self.peek() {
+            Some(Token::Number(n)) => PredicateRhs::Number(*n),
+            Some(Token::Float(f)) => PredicateRhs::Float(*f),
+            Some(Token::Text(text)) => PredicateRhs::Text(text.clone()),
+            _ => {
+                return self.error(format!(
+                    "expected a value but found {}",
+                    self.describe_next()
+                ))
+            }
+        };
+        self.next += 1;
+        Ok(value)
+    }
+
+    fn parse_list(&mut self) -> Result<Vec<PredicateRhs>, PredicateParseError> {
+        self.expect(Token::LeftParen, "`(`")?;
+        let mut values = vec![self.parse_value()?];
+        while self.peek() == Some(&Token::Comma) {
+            self.next += 1;
+            values.push(self.parse_value()?);
+        }
+        self.expect(Token::RightParen, "`)`")?;
+        Ok(values)
+    }
+}
+
+impl FromStr for PredicateQuery {
+    type Err = PredicateParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            next: 0,
+            end: s.len(),
+        };
+        let query = parser.parse_or()?;
+        if parser.peek().is_some() {
+            return parser.error(format!("unexpected {}", parser.describe_next()));
+        }
+        Ok(query)
+    }
+}
+
+impl fmt::Display for PredicateRhs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PredicateRhs::Number(n) => write!(f, "{n}"),
+            // Debug formatting always includes a decimal point or exponent, so that the value is
+            // parsed back as a float.
+            PredicateRhs::Float(x) => write!(f, "{x:?}"),
+            PredicateRhs::Text(text) => write!(f, "'{}'", text.replace('\'', "''")),
+        }
+    }
+}
+
+/// Formats the predicate in the language accepted by `PredicateQuery::from_str`.
+impl fmt::Display for PredicateQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Compound children are always parenthesised, rather than only where precedence needs it.
+        fn child(f: &mut fmt::Formatter<'_>, query: &PredicateQuery) -> fmt::Result {
+            match query {
+                PredicateQuery::And(_) | PredicateQuery::Or(_) => write!(f, "({query})"),
+                _ => write!(f, "{query}"),
+            }
+        }
+        fn join(f: &mut fmt::Formatter<'_>, children: &[PredicateQuery], sep: &str) -> fmt::Result {
+            for (i, query) in children.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " {sep} ")?;
+                }
+                child(f, query)?;
+            }
+            Ok(())
+        }
+
+        match self {
+            PredicateQuery::Attribute { lhs, op } => match op {
+                PredicateOp::Equals(rhs) => write!(f, "{lhs} = {rhs}"),
+                PredicateOp::NotEquals(rhs) => write!(f, "{lhs} != {rhs}"),
+                PredicateOp::LessThan(rhs) => write!(f, "{lhs} < {rhs}"),
+                PredicateOp::LessThanOrEquals(rhs) => write!(f, "{lhs} <= {rhs}"),
+                PredicateOp::GreaterThan(rhs) => write!(f, "{lhs} > {rhs}"),
+                PredicateOp::GreaterThanOrEquals(rhs) => write!(f, "{lhs} >= {rhs}"),
+                PredicateOp::Between(low, high) => write!(f, "{lhs} BETWEEN {low} AND {high}"),
+                PredicateOp::In(values) => {
+                    let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                    write!(f, "{lhs} IN ({})", values.join(", "))
+                }
+            },
+            PredicateQuery::And(children) => join(f, children, "AND"),
+            PredicateQuery::Or(children) => join(f, children, "OR"),
+            PredicateQuery::Not(query) => {
+                write!(f, "NOT ")?;
+                child(f, query)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attribute(lhs: &str, op: PredicateOp) -> PredicateQuery {
+        PredicateQuery::Attribute {
+            lhs: lhs.to_string(),
+            op,
+        }
+    }
+
+    #[test]
+    fn test_parse_compound_predicate() {
+        let pq: PredicateQuery = "attr0 = 5 AND (attr1 < 10 OR attr2 IN (1,2,3))"
+            .parse()
+            .unwrap();
+        let expected = PredicateQuery::And(vec![
+            attribute("attr0", PredicateOp::Equals(PredicateRhs::Number(5))),
+            PredicateQuery::Or(vec![
+                attribute("attr1", PredicateOp::LessThan(PredicateRhs::Number(10))),
+                attribute(
+                    "attr2",
+                    PredicateOp::In(vec![
+                        PredicateRhs::Number(1),
+                        PredicateRhs::Number(2),
+                        PredicateRhs::Number(3),
+                    ]),
+                ),
+            ]),
+        ]);
+        assert_eq!(pq, expected);
+    }
+
+    #[test]
+    fn test_parse_precedence_and_keywords() {
+        let pq: PredicateQuery = "not a >= -2.5 or b between 1 and 3 and c not in ('x', \"y\")"
+            .parse()
+            .unwrap();
+        let expected = PredicateQuery::Or(vec![
+            PredicateQuery::Not(Box::new(attribute(
+                "a",
+                PredicateOp::GreaterThanOrEquals(PredicateRhs::Float(-2.5)),
+            ))),
+            PredicateQuery::And(vec![
+                attribute(
+                    "b",
+                    PredicateOp::Between(PredicateRhs::Number(1), PredicateRhs::Number(3)),
+                ),
+                PredicateQuery::Not(Box::new(attribute(
+                    "c",
+                    PredicateOp::In(vec![
+                        PredicateRhs::Text("x".to_string()),
+                        PredicateRhs::Text("y".to_string()),
+                    ]),
+                ))),
+            ]),
+        ]);
+        assert_eq!(pq, expected);
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for input in [
+            "attr0 = 5",
+            "category != 'books' AND (price <= 9.99 OR price > 1e20)",
+            "NOT (a BETWEEN 1 AND 2) OR b IN (1, 2, 3)",
+            "title = 'it''s \"quoted\"' OR title IN (\"'\", \"\"\"\", '')",
+        ] {
+            let pq: PredicateQuery = input.parse().unwrap();
+            assert_eq!(pq.to_string().parse::<PredicateQuery>().unwrap(), pq);
+        }
+
+        let pq = PredicateQuery::equals("title", PredicateRhs::Text("it's \"quoted\"".to_string()));
+        assert_eq!(pq.to_string(), "title = 'it''s \"quoted\"'");
+        assert_eq!(pq.to_string().parse::<PredicateQuery>().unwrap(), pq);
+    }
+
+    #[test]
+    fn test_implies() {
+        let implies = |a: &str, b: &str| {
+            a.parse::<PredicateQuery>()
+                .unwrap()
+                .implies(&b.parse().unwrap())
+        };
+
+        assert!(implies("attr = 5", "attr IN (4, 5, 6)"));
+        assert!(implies("attr IN (4, 6)", "attr IN (4, 5, 6)"));
+        assert!(implies("attr = 5", "attr = 5.0"));
+        assert!(implies("attr BETWEEN 2 AND 3", "attr > 1"));
+        assert!(implies("attr IN (1, 2)", "attr <= 2"));
+        assert!(implies("attr < 4", "attr <= 4"));
+        assert!(implies("attr > 4", "attr != 4"));
+        assert!(implies("attr = 'a'", "attr < 'b'"));
+        assert!(implies("attr = 5 AND b = 1", "attr IN (5, 6)"));
+        assert!(implies("attr = 5 OR attr = 6", "attr IN (5, 6)"));
+        assert!(implies("attr = 5", "attr = 4 OR attr = 5"));
+        assert!(implies("attr = 5 AND b = 1", "b = 1 AND attr >= 5"));
+        assert!(implies("NOT attr = 5", "NOT attr = 5"));
+
+        assert!(!implies("attr IN (4, 5, 6)", "attr = 5"));
+        assert!(!implies("attr <= 4", "attr < 4"));
+        assert!(!implies("attr >= 4", "attr != 4"));
+        assert!(!implies("attr = 5", "b = 5"));
+        assert!(!implies("attr = 5", "attr = 'a'"));
+        assert!(!implies("attr = 5 OR b = 1", "attr = 5"));
+        assert!(!implies("NOT attr = 5", "attr != 5"));
+    }
+
+    #[test]
+    fn test_parse_errors_have_positions() {
+        let error = |input: &str| input.parse::<PredicateQuery>().unwrap_err().position;
+
+        assert_eq!(error("attr0 = "), 8);
+        assert_eq!(error("attr0 5"), 6);
+        assert_eq!(error("attr0 = 5 AND (attr1 < 3"), 24);
+        assert_eq!(error("attr0 = 'five"), 8);
+        assert_eq!(error("attr0 = 5 attr1 = 6"), 10);
+        assert_eq!(error("attr0 ~ 5"), 6);
+        assert_eq!(error("AND = 5"), 0);
+        assert_eq!(error("attr0 == 5"), 7);
+    }
 }
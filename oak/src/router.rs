@@ -1,25 +1,734 @@
 use crate::bitmask::Bitmask;
-use crate::dataset::SimilaritySearchable;
-use slog_scope::debug;
+use crate::dataset::{
+    check_batch_size, MemoryUsage, QueryFilter, SearchableError, SimilaritySearchable,
+    TopKSearchResult,
+};
+use crate::fvecs::{evaluate_batch_predicates, FlattenedVecs};
+use crate::predicate::PredicateQuery;
+use crate::workload::WorkloadTracker;
+use slog_scope::{debug, info};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
 
-pub struct Router<'a> {
-    base: &'a dyn SimilaritySearchable,
-    opportunistic: Vec<(&'a Bitmask, &'a dyn SimilaritySearchable)>,
+/// What a `RoutingPolicy` is told about an opportunistic index that a query could be sent to.
+pub struct RoutingCandidate<'a> {
+    /// The vectors of the base dataset that are in the index.
+    pub mask: &'a Bitmask,
+    /// The number of vectors in the index.
+    pub len: usize,
+    /// The fraction of the vectors matching the query that are in the index, and so the best
+    /// recall that searching it can achieve.
+    pub coverage: f64,
 }
 
-impl<'a> Router<'a> {
+/// Where a `RoutingPolicy` decides that a query should be searched.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RoutingDecision {
+    Base,
+    /// The opportunistic index at this position in the candidates.
+    Opportunistic(usize),
+    /// The opportunistic indexes at these positions in the candidates, whose results are merged.
+    /// This is used when no one index covers enough of the query, but several together do.
+    FanOut(Vec<usize>),
+}
+
+/// Greedily picks candidates that together cover at least `min_coverage` of the query, each time
+/// taking the one that covers the most of what is still uncovered. Returns `None` if that much of
+/// the query cannot be covered.
+fn greedy_cover(
+    query_mask: &Bitmask,
+    candidates: &[RoutingCandidate],
+    min_coverage: f64,
+) -> Option<Vec<usize>> {
+    let matching = query_mask.bitcount();
+    let mut uncovered = query_mask.clone();
+    let mut chosen = vec![];
+
+    while (matching - uncovered.bitcount()) as f64 / (matching.max(1) as f64) < min_coverage {
+        let (i, gain) = candidates
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !chosen.contains(i))
+            .map(|(i, candidate)| (i, uncovered.intersection_count(candidate.mask)))
+            .max_by_key(|&(_, gain)| gain)?;
+        if gain == 0 {
+            return None;
+        }
+        uncovered &= &!candidates[i].mask;
+        chosen.push(i);
+    }
+
+    Some(chosen)
+}
+
+/// Decides which index a query is searched over, given the mask of vectors matching the query
+/// (over the base dataset, which has `base_len` vectors) and the candidate opportunistic indexes.
+pub trait RoutingPolicy: Send + Sync {
+    fn route(
+        &self,
+        query_mask: &Bitmask,
+        base_len: usize,
+        candidates: &[RoutingCandidate],
+    ) -> RoutingDecision;
+
+    /// Decides between the base dataset and opportunistic indexes whose predicates are known to
+    /// contain every vector matching the query, so that no masks need to be built. `candidate_lens`
+    /// holds the number of vectors in each, and `matching` the number of vectors matching the
+    /// query, if it is known without a mask, which is when one of the indexes was built for an
+    /// equivalent predicate. The decision is `Base` or `Opportunistic`, made by the same criterion
+    /// as `route`, or `None` if the policy cannot decide without the query's mask.
+    ///
+    /// Every candidate covers the whole query, so by default the smallest is used, as it has the
+    /// least to search.
+    fn route_contained(
+        &self,
+        _base_len: usize,
+        candidate_lens: &[usize],
+        _matching: Option<usize>,
+    ) -> Option<RoutingDecision> {
+        let decision = candidate_lens
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, len)| len)
+            .map_or(RoutingDecision::Base, |(i, _)| {
+                RoutingDecision::Opportunistic(i)
+            });
+        Some(decision)
+    }
+}
+
+/// The heuristic from the OAK paper, which scores each candidate by how much smaller it is than
+/// the base dataset, multiplied by the Jaccard similarity of its mask with the query's. The best
+/// candidate is used if its score is above `score_threshold`. Neither part was ever tuned.
+pub struct HeuristicPolicy {
+    pub score_threshold: f64,
+}
+
+impl Default for HeuristicPolicy {
+    fn default() -> Self {
+        Self {
+            score_threshold: 10.,
+        }
+    }
+}
+
+impl RoutingPolicy for HeuristicPolicy {
+    fn route(
+        &self,
+        query_mask: &Bitmask,
+        base_len: usize,
+        candidates: &[RoutingCandidate],
+    ) -> RoutingDecision {
+        let best = candidates
+            .iter()
+            .map(|candidate| {
+                let perf_gain = base_len as f64 / candidate.len as f64;
+                debug!("Performance gain: {}", perf_gain);
+                let similarity = query_mask.jaccard_similarity(candidate.mask);
+                debug!("Jaccard similarity: {}", similarity);
+                // Similarity is a maximum of 1 (if masks perfectly overlap).
+                perf_gain * similarity
+            })
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        match best {
+            Some((i, score)) if score > self.score_threshold => {
+                debug!("The best opportunistic index is at position {i} with a score of {score}");
+                RoutingDecision::Opportunistic(i)
+            }
+            _ => RoutingDecision::Base,
+        }
+    }
+
+    /// A candidate that contains the query has a Jaccard similarity with it of `matching / len`,
+    /// which can only be scored once `matching` is known.
+    fn route_contained(
+        &self,
+        base_len: usize,
+        candidate_lens: &[usize],
+        matching: Option<usize>,
+    ) -> Option<RoutingDecision> {
+        let matching = matching? as f64;
+        let best = candidate_lens
+            .iter()
+            .map(|&len| {
+                let len = len as f64;
+                (base_len as f64 / len) * (matching / len)
+            })
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        Some(match best {
+            Some((i, score)) if score > self.score_threshold => {
+                debug!("The best containing index is at position {i} with a score of {score}");
+                RoutingDecision::Opportunistic(i)
+            }
+            _ => RoutingDecision::Base,
+        })
+    }
+}
+
+/// Always searches the base dataset, as ACORN alone would.
+pub struct AlwaysBasePolicy;
+
+impl RoutingPolicy for AlwaysBasePolicy {
+    fn route(&self, _: &Bitmask, _: usize, _: &[RoutingCandidate]) -> RoutingDecision {
+        RoutingDecision::Base
+    }
+
+    fn route_contained(&self, _: usize, _: &[usize], _: Option<usize>) -> Option<RoutingDecision> {
+        Some(RoutingDecision::Base)
+    }
+}
+
+/// Picks the candidate that covers the most of the query, preferring smaller indexes when several
+/// cover it equally, as long as it covers at least `min_coverage` of the query. If none does, but
+/// several candidates do together, the query is fanned out across them.
+pub struct BestCoveragePolicy {
+    pub min_coverage: f64,
+}
+
+impl Default for BestCoveragePolicy {
+    fn default() -> Self {
+        Self { min_coverage: 1. }
+    }
+}
+
+impl RoutingPolicy for BestCoveragePolicy {
+    fn route(
+        &self,
+        query_mask: &Bitmask,
+        _: usize,
+        candidates: &[RoutingCandidate],
+    ) -> RoutingDecision {
+        let best = candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, candidate)| candidate.coverage >= self.min_coverage)
+            .max_by(|(_, a), (_, b)| {
+                a.coverage
+                    .total_cmp(&b.coverage)
+                    .then_with(|| b.len.cmp(&a.len))
+            });
+        if let Some((i, _)) = best {
+            return RoutingDecision::Opportunistic(i);
+        }
+
+        match greedy_cover(query_mask, candidates, self.min_coverage) {
+            Some(chosen) if chosen.len() > 1 => RoutingDecision::FanOut(chosen),
+            _ => RoutingDecision::Base,
+        }
+    }
+}
+
+/// Picks whichever index is estimated to be cheapest to search, among the base dataset and the
+/// candidates that cover at least `min_coverage` of the query. A fan-out across several candidates
+/// that together cover that much is also considered, at the sum of the costs of its searches.
+///
+/// The cost of a filtered search over a graph of n vectors, of which m pass the filter, is
+/// estimated as ln(n) * n / m: the number of hops to converge grows with ln(n), and only one in
+/// every n / m of the neighbours visited along the way passes the filter.
+pub struct CostBasedPolicy {
+    pub min_coverage: f64,
+}
+
+impl Default for CostBasedPolicy {
+    fn default() -> Self {
+        Self { min_coverage: 1. }
+    }
+}
+
+impl CostBasedPolicy {
+    fn traversal_cost(len: usize, matching: usize) -> f64 {
+        if matching == 0 {
+            return f64::INFINITY;
+        }
+        let len = len as f64;
+        len.ln().max(1.) * len / matching as f64
+    }
+}
+
+impl RoutingPolicy for CostBasedPolicy {
+    fn route(
+        &self,
+        query_mask: &Bitmask,
+        base_len: usize,
+        candidates: &[RoutingCandidate],
+    ) -> RoutingDecision {
+        let base_cost = Self::traversal_cost(base_len, query_mask.bitcount());
+        debug!("Estimated cost of searching the base dataset: {base_cost}");
+
+        let best = candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, candidate)| candidate.coverage >= self.min_coverage)
+            .map(|(i, candidate)| {
+                let matching = query_mask.intersection_count(candidate.mask);
+                let cost = Self::traversal_cost(candidate.len, matching);
+                debug!("Estimated cost of searching opportunistic index {i}: {cost}");
+                (i, cost)
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        let fan_out = greedy_cover(query_mask, candidates, self.min_coverage)
+            .filter(|chosen| chosen.len() > 1)
+            .map(|chosen| {
+                let cost: f64 = chosen
+                    .iter()
+                    .map(|&i| {
+                        let candidate = &candidates[i];
+                        let matching = query_mask.intersection_count(candidate.mask);
+                        Self::traversal_cost(candidate.len, matching)
+                    })
+                    .sum();
+                debug!("Estimated cost of fanning out to {chosen:?}: {cost}");
+                (chosen, cost)
+            });
+
+        let mut decision = RoutingDecision::Base;
+        let mut lowest_cost = base_cost;
+        if let Some((i, cost)) = best {
+            if cost < lowest_cost {
+                decision = RoutingDecision::Opportunistic(i);
+                lowest_cost = cost;
+            }
+        }
+        if let Some((chosen, cost)) = fan_out {
+            if cost < lowest_cost {
+                decision = RoutingDecision::FanOut(chosen);
+            }
+        }
+        decision
+    }
+
+    /// Every candidate that contains the query has all of its matching vectors, so the number of
+    /// them divides every cost equally, and the cheapest index is found without knowing it.
+    fn route_contained(
+        &self,
+        base_len: usize,
+        candidate_lens: &[usize],
+        _matching: Option<usize>,
+    ) -> Option<RoutingDecision> {
+        let base_cost = Self::traversal_cost(base_len, 1);
+        let best = candidate_lens
+            .iter()
+            .map(|&len| Self::traversal_cost(len, 1))
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        Some(match best {
+            Some((i, cost)) if cost < base_cost => RoutingDecision::Opportunistic(i),
+            _ => RoutingDecision::Base,
+        })
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("Invalid routing policy: {0}")]
+pub struct RoutingPolicyParseError(String);
+
+/// Selects and configures one of the routing policies above, so that it can be chosen by name,
+/// such as from the command line. A policy is written as its name, optionally followed by a colon
+/// and its parameter, e.g. `heuristic:20` or `best-coverage:0.9`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RoutingPolicyKind {
+    Heuristic { score_threshold: f64 },
+    AlwaysBase,
+    BestCoverage { min_coverage: f64 },
+    CostBased { min_coverage: f64 },
+}
+
+impl RoutingPolicyKind {
+    pub fn into_policy(self) -> Box<dyn RoutingPolicy> {
+        match self {
+            RoutingPolicyKind::Heuristic { score_threshold } => {
+                Box::new(HeuristicPolicy { score_threshold })
+            }
+            RoutingPolicyKind::AlwaysBase => Box::new(AlwaysBasePolicy),
+            RoutingPolicyKind::BestCoverage { min_coverage } => {
+                Box::new(BestCoveragePolicy { min_coverage })
+            }
+            RoutingPolicyKind::CostBased { min_coverage } => {
+                Box::new(CostBasedPolicy { min_coverage })
+            }
+        }
+    }
+}
+
+impl FromStr for RoutingPolicyKind {
+    type Err = RoutingPolicyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, parameter) = match s.trim().split_once(':') {
+            Some((name, parameter)) => {
+                let parameter = parameter.trim().parse::<f64>().map_err(|_| {
+                    RoutingPolicyParseError(format!("{parameter:?} is not a number"))
+                })?;
+                (name.trim(), Some(parameter))
+            }
+            None => (s.trim(), None),
+        };
+
+        match name {
+            "heuristic" => Ok(RoutingPolicyKind::Heuristic {
+                score_threshold: parameter
+                    .unwrap_or(HeuristicPolicy::default().score_threshold),
+            }),
+            "always-base" if parameter.is_none() => Ok(RoutingPolicyKind::AlwaysBase),
+            "always-base" => Err(RoutingPolicyParseError(
+                "always-base does not take a parameter".to_string(),
+            )),
+            "best-coverage" => Ok(RoutingPolicyKind::BestCoverage {
+                min_coverage: parameter.unwrap_or(BestCoveragePolicy::default().min_coverage),
+            }),
+            "cost-based" => Ok(RoutingPolicyKind::CostBased {
+                min_coverage: parameter.unwrap_or(CostBasedPolicy::default().min_coverage),
+            }),
+            _ => Err(RoutingPolicyParseError(format!(
+                "unknown policy {name:?}, expected one of heuristic, always-base, best-coverage or cost-based"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for RoutingPolicyKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RoutingPolicyKind::Heuristic { score_threshold } => {
+                write!(f, "heuristic:{score_threshold}")
+            }
+            RoutingPolicyKind::AlwaysBase => write!(f, "always-base"),
+            RoutingPolicyKind::BestCoverage { min_coverage } => {
+                write!(f, "best-coverage:{min_coverage}")
+            }
+            RoutingPolicyKind::CostBased { min_coverage } => {
+                write!(f, "cost-based:{min_coverage}")
+            }
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq)]
+#[error("Invalid eviction policy: {0}")]
+pub struct EvictionPolicyParseError(String);
+
+/// Decides which opportunistic index a `Router` evicts first when it is over its memory budget.
+/// It is written as `lru`, `lfu` or `benefit-per-byte`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evicts the index that was searched least recently.
+    LeastRecentlyUsed,
+    /// Evicts the index that has been searched the fewest times, or the least recently used of
+    /// those that have been searched equally often.
+    LeastFrequentlyUsed,
+    /// Evicts the index that has saved the least search work for the memory it uses. Each search
+    /// of an index is taken to save searching the vectors of the base dataset that are not in it.
+    BenefitPerByte,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = EvictionPolicyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "lru" => Ok(EvictionPolicy::LeastRecentlyUsed),
+            "lfu" => Ok(EvictionPolicy::LeastFrequentlyUsed),
+            "benefit-per-byte" => Ok(EvictionPolicy::BenefitPerByte),
+            name => Err(EvictionPolicyParseError(format!(
+                "unknown policy {name:?}, expected one of lru, lfu or benefit-per-byte"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvictionPolicy::LeastRecentlyUsed => write!(f, "lru"),
+            EvictionPolicy::LeastFrequentlyUsed => write!(f, "lfu"),
+            EvictionPolicy::BenefitPerByte => write!(f, "benefit-per-byte"),
+        }
+    }
+}
+
+/// An opportunistic index, along with what is needed to decide whether to route queries to it
+/// and whether to evict it.
+struct OpportunisticIndex {
+    /// The vectors of the base dataset that are in the index.
+    mask: Bitmask,
+    index: Box<dyn SimilaritySearchable>,
+    /// The memory used by the index when it was registered.
+    memory_usage: MemoryUsage,
+    /// The value of the router's clock when the index was last searched, or registered if it has
+    /// not been searched since.
+    last_used: AtomicU64,
+    /// The number of times that the index has been searched.
+    uses: AtomicU64,
+}
+
+impl OpportunisticIndex {
+    fn new(mask: Bitmask, index: Box<dyn SimilaritySearchable>, now: u64) -> Self {
+        let memory_usage = index.memory_usage();
+        Self {
+            mask,
+            index,
+            memory_usage,
+            last_used: AtomicU64::new(now),
+            uses: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Routes queries between a base dataset and opportunistic indexes over subsets of it. The router
+/// owns its indexes and shares ownership of the base dataset, so it can be kept behind a lock and
+/// shared between the threads that serve queries and those that build new indexes.
+pub struct Router {
+    base: Arc<dyn SimilaritySearchable>,
+    opportunistic: Vec<OpportunisticIndex>,
+    policy: Box<dyn RoutingPolicy>,
+    workload: Option<Arc<Mutex<WorkloadTracker>>>,
+    /// The most memory, in bytes, that the opportunistic indexes may use between them, and how to
+    /// choose which to evict when they would use more.
+    memory_budget: Option<(usize, EvictionPolicy)>,
+    /// Counts the searches of opportunistic indexes, to order them by when they were last used.
+    clock: AtomicU64,
+}
+
+impl Router {
     pub fn new(
-        base: &'a dyn SimilaritySearchable,
-        opportunistic: Vec<(&'a Bitmask, &'a dyn SimilaritySearchable)>,
+        base: Arc<dyn SimilaritySearchable>,
+        opportunistic: Vec<(Bitmask, Box<dyn SimilaritySearchable>)>,
+        policy: Box<dyn RoutingPolicy>,
     ) -> Self {
         Router {
             base,
-            opportunistic,
+            opportunistic: opportunistic
+                .into_iter()
+                .map(|(mask, index)| OpportunisticIndex::new(mask, index, 0))
+                .collect(),
+            policy,
+            workload: None,
+            memory_budget: None,
+            clock: AtomicU64::new(0),
         }
     }
+
+    /// Adds an opportunistic index that queries can be routed to, which must be a partition of the
+    /// base dataset, so that it knows which of the base's vectors it holds. If the router has a
+    /// memory budget, other indexes are evicted to make room for it. Returns whether the index was
+    /// added, which it is not if it alone would use more memory than the budget.
+    pub fn register(&mut self, index: Box<dyn SimilaritySearchable>) -> bool {
+        let mask = index
+            .mask()
+            .expect("opportunistic indexes are partitions of the base dataset")
+            .clone();
+        let now = self.clock.load(Ordering::Relaxed);
+        let opportunistic = OpportunisticIndex::new(mask, index, now);
+        if let Some((budget, _)) = self.memory_budget {
+            if opportunistic.memory_usage.total() > budget {
+                debug!(
+                    "Not registering an index of {} bytes, which is over the memory budget.",
+                    opportunistic.memory_usage.total()
+                );
+                return false;
+            }
+        }
+
+        self.opportunistic.push(opportunistic);
+        self.evict(Some(self.opportunistic.len() - 1));
+        true
+    }
+
+    /// Limits the memory used by the opportunistic indexes to `budget` bytes, evicting them in the
+    /// order given by `policy` whenever they would use more. Indexes are evicted straight away if
+    /// they already do.
+    pub fn set_memory_budget(&mut self, budget: usize, policy: EvictionPolicy) {
+        self.memory_budget = Some((budget, policy));
+        self.evict(None);
+    }
+
+    /// The memory used by the opportunistic indexes, which does not include the base dataset.
+    pub fn opportunistic_memory_usage(&self) -> MemoryUsage {
+        self.opportunistic
+            .iter()
+            .map(|opportunistic| opportunistic.memory_usage)
+            .sum()
+    }
+
+    /// The predicates that the opportunistic indexes were built from, for those that have one.
+    pub fn predicates(&self) -> impl Iterator<Item = &PredicateQuery> {
+        self.opportunistic
+            .iter()
+            .filter_map(|opportunistic| opportunistic.index.get_predicate())
+    }
+
+    /// Records the predicate of every query that is searched from now on in `workload`, such as
+    /// for an `AutoPartitioner` to decide which partitions to build.
+    pub fn track_workload(&mut self, workload: Arc<Mutex<WorkloadTracker>>) {
+        self.workload = Some(workload);
+    }
+
+    /// Records that the opportunistic index at position `i` has been searched.
+    fn mark_used(&self, i: usize) {
+        let now = self.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        self.opportunistic[i]
+            .last_used
+            .store(now, Ordering::Relaxed);
+        self.opportunistic[i].uses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Evicts opportunistic indexes until they fit within the memory budget, if there is one. The
+    /// index at position `keep` is never evicted, so that one that has just been registered is not
+    /// evicted before it has had the chance to be used.
+    fn evict(&mut self, keep: Option<usize>) {
+        let Some((budget, policy)) = self.memory_budget else {
+            return;
+        };
+        let base_len = self.base.len();
+        let mut keep = keep;
+
+        while self.opportunistic_memory_usage().total() > budget {
+            let victim = self
+                .opportunistic
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| Some(i) != keep)
+                .min_by(|(_, a), (_, b)| {
+                    let last_used = |o: &OpportunisticIndex| o.last_used.load(Ordering::Relaxed);
+                    let uses = |o: &OpportunisticIndex| o.uses.load(Ordering::Relaxed);
+                    let benefit_per_byte = |o: &OpportunisticIndex| {
+                        let saved = base_len.saturating_sub(o.index.len()) as f64;
+                        uses(o) as f64 * saved / o.memory_usage.total().max(1) as f64
+                    };
+                    match policy {
+                        EvictionPolicy::LeastRecentlyUsed => last_used(a).cmp(&last_used(b)),
+                        EvictionPolicy::LeastFrequentlyUsed => uses(a)
+                            .cmp(&uses(b))
+                            .then_with(|| last_used(a).cmp(&last_used(b))),
+                        EvictionPolicy::BenefitPerByte => {
+                            benefit_per_byte(a).total_cmp(&benefit_per_byte(b))
+                        }
+                    }
+                })
+                .map(|(i, _)| i);
+            let Some(victim) = victim else {
+                return;
+            };
+
+            let evicted = self.opportunistic.remove(victim);
+            info!(
+                "Evicted an opportunistic index of {} vectors using {} bytes{}.",
+                evicted.index.len(),
+                evicted.memory_usage.total(),
+                evicted
+                    .index
+                    .get_predicate()
+                    .map_or(String::new(), |pq| format!(", built for {pq}"))
+            );
+            keep = keep.map(|keep| if keep > victim { keep - 1 } else { keep });
+        }
+    }
+
+    /// Routes a query using only the predicates that the opportunistic indexes were built from,
+    /// which avoids evaluating the query over the whole base dataset. This is only possible when
+    /// the query predicate can be shown to imply the predicate of at least one of the indexes, and
+    /// the policy can decide between them without the query's mask; otherwise `None` is returned,
+    /// and the query must be routed using its mask. The decision comes with the predicate that the
+    /// chosen index must be searched with, which is `None` if every vector in it matches the query.
+    fn route_by_containment(
+        &self,
+        pq: &PredicateQuery,
+    ) -> Option<(RoutingDecision, Option<PredicateQuery>)> {
+        let containing: Vec<(usize, &PredicateQuery)> = self
+            .opportunistic
+            .iter()
+            .enumerate()
+            .filter_map(|(i, opportunistic)| Some((i, opportunistic.index.get_predicate()?)))
+            .filter(|(_, predicate)| pq.implies(predicate))
+            .collect();
+        if containing.is_empty() {
+            return None;
+        }
+
+        let lens: Vec<usize> = containing
+            .iter()
+            .map(|&(i, _)| self.opportunistic[i].index.len())
+            .collect();
+        // An index built for an equivalent predicate holds exactly the vectors matching the query.
+        let matching = containing
+            .iter()
+            .zip(&lens)
+            .find(|((_, predicate), _)| predicate.implies(pq))
+            .map(|(_, &len)| len);
+        let chosen = match self
+            .policy
+            .route_contained(self.base.len(), &lens, matching)?
+        {
+            RoutingDecision::Opportunistic(chosen) => chosen,
+            RoutingDecision::Base => {
+                debug!("Routing decision by containment: base");
+                return Some((RoutingDecision::Base, Some(pq.clone())));
+            }
+            RoutingDecision::FanOut(_) => return None,
+        };
+        let (i, predicate) = containing[chosen];
+        debug!("Routing decision by containment: opportunistic index {i}");
+
+        // If the two predicates are equivalent, every vector in the index matches the query.
+        let filter = if predicate.implies(pq) {
+            None
+        } else {
+            Some(pq.clone())
+        };
+        Some((RoutingDecision::Opportunistic(i), filter))
+    }
+
+    /// Searches for a query that `route_by_containment` can route, or returns `None` if it can't.
+    fn search_by_containment(
+        &self,
+        query_vectors: &FlattenedVecs,
+        pq: &PredicateQuery,
+        topk: usize,
+        efsearch: i64,
+    ) -> Option<Result<Vec<TopKSearchResult>, SearchableError>> {
+        let (decision, filter) = self.route_by_containment(pq)?;
+        let index = match decision {
+            RoutingDecision::Opportunistic(i) => {
+                self.mark_used(i);
+                self.opportunistic[i].index.as_ref()
+            }
+            _ => self.base.as_ref(),
+        };
+        Some(index.search(query_vectors, &filter, topk, efsearch))
+    }
+
+    /// Routes a query by its mask over the base dataset, which the policy compares with the masks
+    /// of the opportunistic indexes.
+    fn route_by_mask(&self, query_bitmask: &Bitmask) -> RoutingDecision {
+        let candidates: Vec<RoutingCandidate> = self
+            .opportunistic
+            .iter()
+            .map(|opportunistic| RoutingCandidate {
+                mask: &opportunistic.mask,
+                len: opportunistic.index.len(),
+                coverage: query_bitmask.coverage_by(&opportunistic.mask),
+            })
+            .collect();
+
+        let decision = self
+            .policy
+            .route(query_bitmask, self.base.len(), &candidates);
+        debug!("Routing decision: {:?}", decision);
+        decision
+    }
 }
 
-impl SimilaritySearchable for Router<'_> {
+impl SimilaritySearchable for Router {
     fn len(&self) -> usize {
         self.base.len()
     }
@@ -40,6 +749,10 @@ impl SimilaritySearchable for Router<'_> {
         Ok(())
     }
 
+    fn memory_usage(&self) -> MemoryUsage {
+        self.base.memory_usage() + self.opportunistic_memory_usage()
+    }
+
     fn search(
         &self,
         query_vectors: &crate::fvecs::FlattenedVecs,
@@ -48,8 +761,20 @@ impl SimilaritySearchable for Router<'_> {
         efsearch: i64,
     ) -> anyhow::Result<Vec<crate::dataset::TopKSearchResult>, crate::dataset::SearchableError>
     {
-        self.base
-            .search(query_vectors, predicate_query, topk, efsearch)
+        // Without a predicate every vector matches, which no opportunistic index can cover.
+        let Some(pq) = predicate_query else {
+            return self
+                .base
+                .search(query_vectors, predicate_query, topk, efsearch);
+        };
+        if let Some(workload) = &self.workload {
+            workload.lock().unwrap().record(pq);
+        }
+        if let Some(results) = self.search_by_containment(query_vectors, pq, topk, efsearch) {
+            return results;
+        }
+        let query_bitmask = Bitmask::new(pq, self.base.as_ref())?;
+        self.search_with_bitmask(query_vectors, &query_bitmask, topk, efsearch)
     }
 
     fn search_with_bitmask(
@@ -60,39 +785,648 @@ impl SimilaritySearchable for Router<'_> {
         efsearch: i64,
     ) -> anyhow::Result<Vec<crate::dataset::TopKSearchResult>, crate::dataset::SearchableError>
     {
-        let base_meta = self.base.get_metadata();
-        let base_meta_len = base_meta.len() as f32;
+        let chosen = match self.route_by_mask(query_bitmask) {
+            RoutingDecision::Base => vec![],
+            RoutingDecision::Opportunistic(i) => vec![i],
+            RoutingDecision::FanOut(chosen) => chosen,
+        };
+        let indexes_to_search: Vec<&dyn SimilaritySearchable> = if chosen.is_empty() {
+            vec![self.base.as_ref()]
+        } else {
+            chosen
+                .into_iter()
+                .map(|i| {
+                    self.mark_used(i);
+                    self.opportunistic[i].index.as_ref()
+                })
+                .collect()
+        };
 
-        let (best_index, best_score) = self
-            .opportunistic
+        if let [index] = indexes_to_search[..] {
+            return index.search_with_bitmask(query_vectors, query_bitmask, topk, efsearch);
+        }
+        let results = indexes_to_search
+            .into_iter()
+            .map(|index| index.search_with_bitmask(query_vectors, query_bitmask, topk, efsearch))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(merge_results(results, topk))
+    }
+
+    fn search_batch(
+        &self,
+        query_vectors: &FlattenedVecs,
+        filters: &[QueryFilter],
+        topk: usize,
+        efsearch: i64,
+    ) -> Result<Vec<TopKSearchResult>, SearchableError> {
+        check_batch_size(query_vectors, filters)?;
+
+        // Each distinct predicate is routed once, by containment where it can be and otherwise by
+        // its mask, which are then evaluated together.
+        let mut routes: HashMap<String, (RoutingDecision, QueryFilter)> = HashMap::new();
+        let mut uncontained = vec![];
+        for filter in filters {
+            let QueryFilter::Predicate(pq) = filter else {
+                continue;
+            };
+            if let Some(workload) = &self.workload {
+                workload.lock().unwrap().record(pq);
+            }
+            let key = pq.to_string();
+            if routes.contains_key(&key) || uncontained.contains(filter) {
+                continue;
+            }
+            match self.route_by_containment(pq) {
+                Some((decision, filter)) => {
+                    routes.insert(key, (decision, filter.into()));
+                }
+                None => uncontained.push(filter.clone()),
+            }
+        }
+        for (key, mask) in evaluate_batch_predicates(self.base.as_ref(), &uncontained)? {
+            routes.insert(key, (self.route_by_mask(&mask), mask.into()));
+        }
+
+        // The queries are grouped by the index they are searched over, where `None` is the base, so
+        // that each index is searched once for the whole batch.
+        let mut groups: BTreeMap<Option<usize>, (Vec<usize>, Vec<QueryFilter>)> = BTreeMap::new();
+        for (query, filter) in filters.iter().enumerate() {
+            let (decision, filter) = match filter {
+                QueryFilter::Unfiltered => (RoutingDecision::Base, filter.clone()),
+                QueryFilter::Predicate(pq) => routes[&pq.to_string()].clone(),
+                QueryFilter::Bitmask(mask) => (self.route_by_mask(mask), filter.clone()),
+            };
+            let targets = match decision {
+                RoutingDecision::Opportunistic(i) => vec![Some(i)],
+                RoutingDecision::FanOut(chosen) if !chosen.is_empty() => {
+                    chosen.into_iter().map(Some).collect()
+                }
+                _ => vec![None],
+            };
+            for target in targets {
+                if let Some(i) = target {
+                    self.mark_used(i);
+                }
+                let (queries, target_filters) = groups.entry(target).or_default();
+                queries.push(query);
+                target_filters.push(filter.clone());
+            }
+        }
+
+        let dimensionality = query_vectors.dimensionality;
+        let mut results = vec![vec![]; query_vectors.len()];
+        for (target, (queries, target_filters)) in groups {
+            let index = match target {
+                Some(i) => self.opportunistic[i].index.as_ref(),
+                None => self.base.as_ref(),
+            };
+            let batch = FlattenedVecs {
+                dimensionality,
+                data: queries
+                    .iter()
+                    .flat_map(|&query| {
+                        &query_vectors.data[query * dimensionality..(query + 1) * dimensionality]
+                    })
+                    .copied()
+                    .collect(),
+            };
+            let found = index.search_batch(&batch, &target_filters, topk, efsearch)?;
+            for (query, found) in queries.into_iter().zip(found) {
+                results[query].push(found);
+            }
+        }
+        // A query that was fanned out has one result for each index it was searched over.
+        Ok(results
+            .into_iter()
+            .map(|mut found| {
+                if found.len() == 1 {
+                    return found.remove(0);
+                }
+                let batches = found.into_iter().map(|found| vec![found]).collect();
+                merge_results(batches, topk).remove(0)
+            })
+            .collect())
+    }
+}
+
+/// Merges the results of searching several indexes for the same batch of queries into one top-k
+/// per query, ordered by distance. A vector that is in more than one of the indexes is only
+/// returned once.
+fn merge_results(results: Vec<Vec<TopKSearchResult>>, topk: usize) -> Vec<TopKSearchResult> {
+    let number_of_queries = results.first().map_or(0, |batch| batch.len());
+    (0..number_of_queries)
+        .map(|i| {
+            let mut merged: TopKSearchResult = results
+                .iter()
+                .flat_map(|batch| batch[i].iter().copied())
+                .collect();
+            merged.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+            let mut seen = HashSet::new();
+            merged.retain(|&(id, _)| seen.insert(id));
+            merged.truncate(topk);
+            merged
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::OakIndexOptions;
+    use crate::fvecs::{AttributeCsv, FvecsDataset};
+    use crate::predicate::PredicateRhs;
+
+    fn mask(bits: &[i8]) -> Bitmask {
+        Bitmask::from(bits.to_vec())
+    }
+
+    fn candidates<'a>(query: &Bitmask, masks: &'a [Bitmask]) -> Vec<RoutingCandidate<'a>> {
+        masks
             .iter()
-            .map(|(opp_mask, opp_index)| {
-                let opp_meta = opp_index.get_metadata();
-                let perf_gain = base_meta_len / (opp_meta.len() as f32);
-                debug!("Performance gain: {}", perf_gain);
-                let recall_loss = query_bitmask.jaccard_similarity(opp_mask);
-                debug!("Recall loss: {}", recall_loss);
-                // Recall is a maximum of 1 (if masks perfectly overlap).
-                (perf_gain as f64) * recall_loss
+            .map(|mask| RoutingCandidate {
+                mask,
+                len: mask.bitcount(),
+                coverage: query.coverage_by(mask),
             })
-            // get the index of the max
-            .enumerate()
-            .max_by(|&(_, a), (_, b)| a.partial_cmp(b).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_policies() {
+        let query = mask(&[1, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let masks = [
+            // Covers half of the query.
+            mask(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            // Covers all of the query, but is larger than it.
+            mask(&[1, 1, 1, 1, 1, 0, 0, 0, 0, 0]),
+            // Covers all of the query exactly.
+            mask(&[1, 1, 0, 0, 0, 0, 0, 0, 0, 0]),
+        ];
+        let candidates = candidates(&query, &masks);
+
+        assert_eq!(
+            AlwaysBasePolicy.route(&query, 10, &candidates),
+            RoutingDecision::Base
+        );
+        assert_eq!(
+            BestCoveragePolicy::default().route(&query, 10, &candidates),
+            RoutingDecision::Opportunistic(2)
+        );
+        assert_eq!(
+            CostBasedPolicy::default().route(&query, 10, &candidates),
+            RoutingDecision::Opportunistic(2)
+        );
+        // Accepting a partial cover lets the smallest candidate be chosen, as it is no costlier.
+        assert_eq!(
+            CostBasedPolicy { min_coverage: 0.5 }.route(&query, 10, &candidates),
+            RoutingDecision::Opportunistic(0)
+        );
+
+        // The exact cover scores 10 / 2 * 1 = 5, which is only enough for a lower threshold.
+        assert_eq!(
+            HeuristicPolicy::default().route(&query, 10, &candidates),
+            RoutingDecision::Base
+        );
+        assert_eq!(
+            HeuristicPolicy {
+                score_threshold: 4.
+            }
+            .route(&query, 10, &candidates),
+            RoutingDecision::Opportunistic(2)
+        );
+
+        // Routing among indexes that contain the query gives the same decisions, as long as the
+        // heuristic is told how many vectors match the query.
+        let lens = [5, 2];
+        assert_eq!(
+            AlwaysBasePolicy.route_contained(10, &lens, None),
+            Some(RoutingDecision::Base)
+        );
+        assert_eq!(
+            BestCoveragePolicy::default().route_contained(10, &lens, None),
+            Some(RoutingDecision::Opportunistic(1))
+        );
+        assert_eq!(
+            CostBasedPolicy::default().route_contained(10, &lens, None),
+            Some(RoutingDecision::Opportunistic(1))
+        );
+        assert_eq!(
+            CostBasedPolicy::default().route_contained(10, &[10], None),
+            Some(RoutingDecision::Base)
+        );
+        assert_eq!(
+            HeuristicPolicy::default().route_contained(10, &lens, Some(2)),
+            Some(RoutingDecision::Base)
+        );
+        assert_eq!(
+            HeuristicPolicy {
+                score_threshold: 4.
+            }
+            .route_contained(10, &lens, Some(2)),
+            Some(RoutingDecision::Opportunistic(1))
+        );
+        assert_eq!(
+            HeuristicPolicy::default().route_contained(10, &lens, None),
+            None
+        );
+
+        for policy in [
+            RoutingPolicyKind::Heuristic {
+                score_threshold: 0.,
+            },
+            RoutingPolicyKind::BestCoverage { min_coverage: 0. },
+            RoutingPolicyKind::CostBased { min_coverage: 0. },
+        ] {
+            assert_eq!(
+                policy.into_policy().route(&query, 10, &[]),
+                RoutingDecision::Base
+            );
+        }
+    }
+
+    #[test]
+    fn test_fan_out() {
+        let query = mask(&[1, 1, 1, 1, 0, 0, 0, 0, 0, 0]);
+        let masks = [
+            mask(&[1, 1, 0, 0, 0, 0, 0, 0, 0, 0]),
+            mask(&[0, 0, 0, 0, 1, 1, 1, 1, 1, 1]),
+            mask(&[0, 1, 1, 1, 0, 0, 0, 0, 0, 0]),
+        ];
+        let candidates = candidates(&query, &masks);
+
+        assert_eq!(greedy_cover(&query, &candidates, 1.), Some(vec![2, 0]));
+        assert_eq!(greedy_cover(&query, &candidates[..2], 1.), None);
+        assert_eq!(
+            BestCoveragePolicy::default().route(&query, 10, &candidates),
+            RoutingDecision::FanOut(vec![2, 0])
+        );
+        assert_eq!(
+            CostBasedPolicy::default().route(&query, 10, &candidates),
+            RoutingDecision::FanOut(vec![2, 0])
+        );
+        // A single candidate that covers enough is preferred to fanning out.
+        assert_eq!(
+            BestCoveragePolicy { min_coverage: 0.75 }.route(&query, 10, &candidates),
+            RoutingDecision::Opportunistic(2)
+        );
+    }
+
+    #[test]
+    fn test_merge_results() {
+        let results = vec![
+            vec![vec![(1, 0.5), (3, 2.0)], vec![(7, 1.0)]],
+            vec![vec![(2, 1.0), (3, 2.0)], vec![]],
+        ];
+        assert_eq!(
+            merge_results(results, 3),
+            vec![vec![(1, 0.5), (2, 1.0), (3, 2.0)], vec![(7, 1.0)]]
+        );
+    }
+
+    #[test]
+    fn test_router_search() {
+        let mut dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let dataset = Arc::new(dataset);
+        let pq = PredicateQuery::new(1);
+        let mut partition = dataset.view(&pq).unwrap();
+        partition.initialize(&OakIndexOptions::default()).unwrap();
+        let partition_mask = Bitmask::new(&pq, dataset.as_ref()).unwrap();
+
+        let dimensionality = dataset.get_dimensionality();
+        let id = partition.global_id(0);
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.vectors().get(id).to_vec(),
+        };
+
+        // With no opportunistic indexes, everything falls back to the base.
+        let router = Router::new(
+            dataset.clone(),
+            vec![],
+            Box::new(HeuristicPolicy::default()),
+        );
+        let result = router
+            .search_with_bitmask(&query_vector, &partition_mask, 1, 16)
             .unwrap();
+        assert_eq!(result[0][0].0, id);
 
-        debug!(
-            "The best opportunistic index is at position {} with a score of {}",
-            best_index, best_score
+        // A predicate query is routed to the partition that was built for it.
+        let router = Router::new(
+            dataset.clone(),
+            vec![(partition_mask, Box::new(partition))],
+            Box::new(BestCoveragePolicy::default()),
         );
+        let result = router.search(&query_vector, &Some(pq), 1, 16).unwrap();
+        assert_eq!(result[0][0].0, id);
 
-        let score_threshold = 10.;
-        let index_to_search = if best_score > score_threshold {
-            let (_, opp_index) = self.opportunistic[best_index];
-            opp_index
-        } else {
-            self.base
+        assert_eq!(
+            router.search(
+                &query_vector,
+                &Some(PredicateQuery::equals(
+                    "no_such_attribute",
+                    PredicateRhs::Number(1)
+                )),
+                1,
+                16
+            ),
+            Err(SearchableError::UnknownAttribute(
+                "no_such_attribute".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_router_search_by_containment() {
+        let mut dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let dataset = Arc::new(dataset);
+        let partition_pq: PredicateQuery = "attr0 IN (1, 2)".parse().unwrap();
+        let mut partition = dataset.view(&partition_pq).unwrap();
+        partition.initialize(&OakIndexOptions::default()).unwrap();
+        let partition_mask = Bitmask::new(&partition_pq, dataset.as_ref()).unwrap();
+        let router = Router::new(
+            dataset.clone(),
+            vec![(partition_mask, Box::new(partition))],
+            Box::new(BestCoveragePolicy::default()),
+        );
+
+        // The query is contained in the partition, so is still filtered within it.
+        let pq: PredicateQuery = "attr0 = 2".parse().unwrap();
+        let query_mask = Bitmask::new(&pq, dataset.as_ref()).unwrap();
+        let id = query_mask.iter_ones().next().unwrap();
+        let dimensionality = dataset.get_dimensionality();
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.vectors().get(id).to_vec(),
+        };
+
+        let result = router
+            .search_by_containment(&query_vector, &pq, 10, 16)
+            .unwrap()
+            .unwrap();
+        assert_eq!(result[0][0].0, id);
+        assert!(result[0].iter().all(|&(id, _)| query_mask.get(id)));
+
+        // A query that is not contained in any partition is left to be routed by its mask.
+        let contained = pq;
+        let pq: PredicateQuery = "attr0 IN (2, 3)".parse().unwrap();
+        assert!(router
+            .search_by_containment(&query_vector, &pq, 10, 16)
+            .is_none());
+
+        // So is a contained query when the policy needs to know how much of the partition matches.
+        let router = Router {
+            policy: Box::new(HeuristicPolicy::default()),
+            ..router
         };
+        assert!(router
+            .search_by_containment(&query_vector, &contained, 10, 16)
+            .is_none());
+        assert!(router
+            .search_by_containment(&query_vector, &partition_pq, 10, 16)
+            .is_some());
+    }
+
+    #[test]
+    fn test_router_search_batch() {
+        let mut dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let dataset = Arc::new(dataset);
+        let opportunistic: Vec<(Bitmask, Box<dyn SimilaritySearchable>)> =
+            ["attr0 IN (1, 2)", "attr0 IN (3, 4)"]
+                .iter()
+                .map(|pq| {
+                    let pq: PredicateQuery = pq.parse().unwrap();
+                    let mut partition = dataset.view(&pq).unwrap();
+                    partition.initialize(&OakIndexOptions::default()).unwrap();
+                    let mask = Bitmask::new(&pq, dataset.as_ref()).unwrap();
+                    (mask, Box::new(partition) as Box<dyn SimilaritySearchable>)
+                })
+                .collect();
+        let router = Router::new(
+            dataset.clone(),
+            opportunistic,
+            Box::new(BestCoveragePolicy::default()),
+        );
+
+        // The batch is routed by containment, by mask with a fan out, and to the base.
+        let contained: PredicateQuery = "attr0 = 2".parse().unwrap();
+        let spanning: PredicateQuery = "attr0 IN (2, 3)".parse().unwrap();
+        let filters = vec![
+            QueryFilter::Predicate(contained.clone()),
+            QueryFilter::Unfiltered,
+            QueryFilter::Predicate(spanning.clone()),
+            QueryFilter::Predicate(contained),
+            QueryFilter::Bitmask(Bitmask::new(&spanning, dataset.as_ref()).unwrap()),
+        ];
+        let QueryFilter::Bitmask(spanning_mask) = &filters[4] else {
+            unreachable!()
+        };
+        assert!(matches!(
+            router.route_by_mask(spanning_mask),
+            RoutingDecision::FanOut(_)
+        ));
+        let dimensionality = dataset.get_dimensionality();
+        let query_vectors = FlattenedVecs {
+            dimensionality,
+            data: (0..filters.len())
+                .flat_map(|id| dataset.vectors().get(id * 7).to_vec())
+                .collect(),
+        };
+
+        let results = router
+            .search_batch(&query_vectors, &filters, 10, 16)
+            .unwrap();
+        assert_eq!(results.len(), filters.len());
+        for (i, filter) in filters.iter().enumerate() {
+            let query_vector = FlattenedVecs {
+                dimensionality,
+                data: query_vectors.data[i * dimensionality..(i + 1) * dimensionality].to_vec(),
+            };
+            let expected = match filter {
+                QueryFilter::Unfiltered => router.search(&query_vector, &None, 10, 16),
+                QueryFilter::Predicate(pq) => {
+                    router.search(&query_vector, &Some(pq.clone()), 10, 16)
+                }
+                QueryFilter::Bitmask(mask) => {
+                    router.search_with_bitmask(&query_vector, mask, 10, 16)
+                }
+            };
+            assert_eq!(results[i], expected.unwrap()[0]);
+        }
+
+        assert_eq!(
+            router.search_batch(&query_vectors, &filters[1..], 10, 16),
+            Err(SearchableError::BatchSizeMismatch {
+                queries: filters.len(),
+                filters: filters.len() - 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let dataset = Arc::new(dataset);
+        let dimensionality = dataset.get_dimensionality();
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.vectors().get(0).to_vec(),
+        };
+        let predicates: Vec<PredicateQuery> = (1..=3)
+            .map(|i| format!("attr0 = {i}").parse().unwrap())
+            .collect();
+        let partition = |pq: &PredicateQuery| {
+            let mut partition = dataset.view(pq).unwrap();
+            partition.initialize(&OakIndexOptions::default()).unwrap();
+            let mask = Bitmask::new(pq, dataset.as_ref()).unwrap();
+            (mask, Box::new(partition) as Box<dyn SimilaritySearchable>)
+        };
+
+        // The first partition is searched twice but before the second, which is searched once.
+        // The budget leaves room for any two of the three partitions.
+        let evicted = |policy: EvictionPolicy| {
+            let mut router = Router::new(
+                dataset.clone(),
+                vec![partition(&predicates[0]), partition(&predicates[1])],
+                Box::new(BestCoveragePolicy::default()),
+            );
+            for pq in [&predicates[0], &predicates[0], &predicates[1]] {
+                router
+                    .search(&query_vector, &Some(pq.clone()), 1, 16)
+                    .unwrap();
+            }
+
+            let (_, index) = partition(&predicates[2]);
+            let memory_usage = router.opportunistic_memory_usage() + index.memory_usage();
+            assert!(memory_usage.vectors > 0 && memory_usage.graph > 0);
+            let budget = memory_usage.total() - 1;
+            router.set_memory_budget(budget, policy);
+            assert!(router.register(index));
+            assert!(router.opportunistic_memory_usage().total() <= budget);
+
+            let remaining: Vec<&PredicateQuery> = router.predicates().collect();
+            assert_eq!(remaining.len(), 2);
+            assert!(remaining.contains(&&predicates[2]));
+            predicates
+                .iter()
+                .find(|pq| !remaining.contains(pq))
+                .unwrap()
+                .clone()
+        };
+
+        assert_eq!(evicted(EvictionPolicy::LeastRecentlyUsed), predicates[0]);
+        assert_eq!(evicted(EvictionPolicy::LeastFrequentlyUsed), predicates[1]);
+        assert_eq!(evicted(EvictionPolicy::BenefitPerByte), predicates[1]);
+
+        // An index that would not fit within the budget even on its own is not registered.
+        let mut router = Router::new(
+            dataset.clone(),
+            vec![],
+            Box::new(HeuristicPolicy::default()),
+        );
+        router.set_memory_budget(1, EvictionPolicy::LeastRecentlyUsed);
+        let (_, index) = partition(&predicates[0]);
+        assert!(!router.register(index));
+        assert_eq!(router.predicates().count(), 0);
+    }
+
+    #[test]
+    fn test_router_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Router>();
+        assert_send_sync::<FvecsDataset>();
+        assert_send_sync::<crate::fvecs::FvecsDatasetPartition>();
+
+        let mut dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let dataset = Arc::new(dataset);
+        let pq: PredicateQuery = "attr0 = 1".parse().unwrap();
+        let query_mask = Bitmask::new(&pq, dataset.as_ref()).unwrap();
+        let dimensionality = dataset.get_dimensionality();
+        let router = Arc::new(std::sync::RwLock::new(Router::new(
+            dataset.clone(),
+            vec![],
+            Box::new(BestCoveragePolicy::default()),
+        )));
+
+        // Queries keep being served while a partition is built and registered.
+        let searchers: Vec<_> = (0..4)
+            .map(|i| {
+                let router = Arc::clone(&router);
+                let pq = pq.clone();
+                let query_mask = query_mask.clone();
+                let query_vector = FlattenedVecs {
+                    dimensionality,
+                    data: dataset.vectors().get(i).to_vec(),
+                };
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        let result = router
+                            .read()
+                            .unwrap()
+                            .search(&query_vector, &Some(pq.clone()), 1, 16)
+                            .unwrap();
+                        assert!(result[0].iter().all(|&(id, _)| query_mask.get(id)));
+                    }
+                })
+            })
+            .collect();
+
+        let mut partition = dataset.view(&pq).unwrap();
+        partition.initialize(&OakIndexOptions::default()).unwrap();
+        router.write().unwrap().register(Box::new(partition));
+        for searcher in searchers {
+            searcher.join().unwrap();
+        }
+        assert_eq!(router.read().unwrap().predicates().count(), 1);
+    }
+
+    #[test]
+    fn test_parse_eviction_policy() {
+        for policy in [
+            EvictionPolicy::LeastRecentlyUsed,
+            EvictionPolicy::LeastFrequentlyUsed,
+            EvictionPolicy::BenefitPerByte,
+        ] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+        assert!("fifo".parse::<EvictionPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_parse_routing_policy() {
+        assert_eq!(
+            "heuristic".parse(),
+            Ok(RoutingPolicyKind::Heuristic {
+                score_threshold: 10.
+            })
+        );
+        assert_eq!(
+            "best-coverage:0.9".parse(),
+            Ok(RoutingPolicyKind::BestCoverage { min_coverage: 0.9 })
+        );
+        assert_eq!("always-base".parse(), Ok(RoutingPolicyKind::AlwaysBase));
+
+        for policy in [
+            RoutingPolicyKind::Heuristic {
+                score_threshold: 2.5,
+            },
+            RoutingPolicyKind::AlwaysBase,
+            RoutingPolicyKind::CostBased { min_coverage: 1. },
+        ] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
 
-        index_to_search.search_with_bitmask(query_vectors, query_bitmask, topk, efsearch)
+        assert!("fastest".parse::<RoutingPolicyKind>().is_err());
+        assert!("heuristic:high".parse::<RoutingPolicyKind>().is_err());
+        assert!("always-base:1".parse::<RoutingPolicyKind>().is_err());
     }
 }
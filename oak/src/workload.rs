@@ -0,0 +1,444 @@
+use crate::bitmask::Bitmask;
+use crate::dataset::{ConstructionError, OakIndexOptions, SimilaritySearchable};
+use crate::fvecs::{FvecsDataset, FvecsDatasetPartition, PendingIndex};
+use crate::predicate::PredicateQuery;
+use crate::router::Router;
+use slog_scope::{debug, info};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Counts how often each predicate is queried. Predicates are told apart by how they are written,
+/// so equivalent predicates that are written differently are counted separately.
+#[derive(Default)]
+pub struct WorkloadTracker {
+    counts: HashMap<String, (PredicateQuery, usize)>,
+}
+
+impl WorkloadTracker {
+    pub fn record(&mut self, pq: &PredicateQuery) {
+        self.counts
+            .entry(pq.to_string())
+            .or_insert_with(|| (pq.clone(), 0))
+            .1 += 1;
+    }
+
+    /// The number of times that `pq` has been queried.
+    pub fn frequency(&self, pq: &PredicateQuery) -> usize {
+        self.counts
+            .get(&pq.to_string())
+            .map_or(0, |(_, count)| *count)
+    }
+
+    /// The predicates that have been queried at least `min_frequency` times, most frequent first.
+    pub fn frequent(&self, min_frequency: usize) -> Vec<(PredicateQuery, usize)> {
+        let mut frequent: Vec<(&String, &(PredicateQuery, usize))> = self
+            .counts
+            .iter()
+            .filter(|(_, (_, count))| *count >= min_frequency)
+            .collect();
+        frequent.sort_by(|(a, (_, a_count)), (b, (_, b_count))| {
+            b_count.cmp(a_count).then_with(|| a.cmp(b))
+        });
+        frequent
+            .into_iter()
+            .map(|(_, (pq, count))| (pq.clone(), *count))
+            .collect()
+    }
+}
+
+/// Configures when an `AutoPartitioner` builds a partition for a predicate.
+pub struct AutoPartitionOptions {
+    /// How many times a predicate must have been queried before a partition is built for it.
+    pub min_frequency: usize,
+    /// Partitions are only built for predicates that match at most this fraction of the base
+    /// dataset, as searching a partition that is nearly as large as the base saves little.
+    pub max_selectivity: f64,
+    /// The most memory, in bytes, that the opportunistic indexes of the router may use between
+    /// them, counting the partitions that are still being built. No partitions are built while
+    /// they would use more. If the router also has a memory budget, set with
+    /// `Router::set_memory_budget`, this should be larger than it, so that partitions for new
+    /// predicates can be built and then evict those for predicates that are no longer used.
+    pub memory_budget: usize,
+    /// The options that the index of each partition is built with.
+    pub index_options: OakIndexOptions,
+}
+
+impl Default for AutoPartitionOptions {
+    fn default() -> Self {
+        Self {
+            min_frequency: 10,
+            max_selectivity: 0.1,
+            memory_budget: 1 << 30,
+            index_options: OakIndexOptions::default(),
+        }
+    }
+}
+
+/// An estimate of the memory used by a partition of `len` vectors: its copy of the vectors, and
+/// the neighbour lists of its graph, which ACORN sizes by `m * gamma` on the bottom level. The
+/// upper levels of the graph add comparatively little.
+fn estimated_partition_bytes(len: usize, dimensionality: usize, opts: &OakIndexOptions) -> usize {
+    let vector_bytes = dimensionality * std::mem::size_of::<f32>();
+    let neighbour_bytes = 2 * (opts.m * opts.gamma) as usize * std::mem::size_of::<i32>();
+    len * (vector_bytes + neighbour_bytes)
+}
+
+/// Builds opportunistic partitions of a dataset automatically, based on the predicates that a
+/// `Router` over it is queried with. Once a predicate has been queried often enough, matches few
+/// enough vectors and fits within the memory budget, a partition for it is built in the
+/// background and then registered with the router.
+///
+/// If the router evicts a partition to stay within its own memory budget, the partition is only
+/// built again once its predicate has been queried `min_frequency` more times. Otherwise,
+/// partitions that do not fit in the router together would be built and evicted over and over.
+pub struct AutoPartitioner {
+    base: Arc<FvecsDataset>,
+    options: AutoPartitionOptions,
+    workload: Arc<Mutex<WorkloadTracker>>,
+    /// Partitions whose indexes are being built.
+    building: Vec<(FvecsDatasetPartition, PendingIndex)>,
+    /// The number of vectors matching each predicate that has been considered, or `None` if it
+    /// will never be worth building a partition for.
+    considered: HashMap<String, Option<usize>>,
+    /// The predicates of the partitions that are registered with the router, as far as the
+    /// partitioner knows, so that it can tell when the router has evicted one.
+    registered: Vec<PredicateQuery>,
+    /// The number of times that each predicate whose partition was evicted had been queried when
+    /// it was.
+    evicted: HashMap<String, usize>,
+}
+
+impl AutoPartitioner {
+    pub fn new(base: Arc<FvecsDataset>, options: AutoPartitionOptions) -> Self {
+        Self {
+            base,
+            options,
+            workload: Arc::new(Mutex::new(WorkloadTracker::default())),
+            building: vec![],
+            considered: HashMap::new(),
+            registered: vec![],
+            evicted: HashMap::new(),
+        }
+    }
+
+    /// The tracker that the router should record the predicates it is queried with in. See
+    /// `Router::track_workload`.
+    pub fn workload(&self) -> Arc<Mutex<WorkloadTracker>> {
+        Arc::clone(&self.workload)
+    }
+
+    /// The estimated memory that will be used by the partitions that are being built.
+    pub fn building_bytes(&self) -> usize {
+        self.building
+            .iter()
+            .map(|(partition, _)| self.estimate(partition.len()))
+            .sum()
+    }
+
+    /// Whether any partitions are still being built.
+    pub fn is_building(&self) -> bool {
+        !self.building.is_empty()
+    }
+
+    /// Registers the partitions that have finished building with `router`, and then starts
+    /// building partitions for any predicates that have become worth one. Partitions are built on
+    /// background threads, so this is cheap enough to call in between serving queries.
+    ///
+    /// The predicates are evaluated over the base dataset without holding the lock on `router`,
+    /// which is only locked for writing to register the finished partitions, so that queries are
+    /// not held up while the partitioner decides what to build.
+    pub fn poll(&mut self, router: &RwLock<Router>) -> Result<(), ConstructionError> {
+        let result = self.register_finished(router);
+
+        // What is already built is read up front, so that the lock is not held while deciding.
+        let (built, used_bytes) = {
+            let router = router.read().unwrap();
+            let built: Vec<PredicateQuery> = router.predicates().cloned().collect();
+            (built, router.opportunistic_memory_usage().total())
+        };
+        self.record_evictions(&built);
+
+        let frequent = self
+            .workload
+            .lock()
+            .unwrap()
+            .frequent(self.options.min_frequency);
+        for (pq, frequency) in frequent {
+            let is_built = built.contains(&pq);
+            let is_building = self
+                .building
+                .iter()
+                .any(|(partition, _)| partition.get_predicate() == Some(&pq));
+            if is_built || is_building {
+                continue;
+            }
+            if let Some(&evicted_at) = self.evicted.get(&pq.to_string()) {
+                if frequency < evicted_at + self.options.min_frequency {
+                    continue;
+                }
+            }
+
+            let Some(len) = self.matching(&pq) else {
+                continue;
+            };
+            if used_bytes + self.building_bytes() + self.estimate(len) > self.options.memory_budget
+            {
+                debug!("Not building a partition for {pq}, as it would exceed the memory budget.");
+                continue;
+            }
+
+            let Ok(partition) = self.base.view(&pq) else {
+                continue;
+            };
+            debug!("Building a partition for {pq}, which has been queried {frequency} times.");
+            self.evicted.remove(&pq.to_string());
+            let pending = partition.initialize_in_background(&self.options.index_options);
+            self.building.push((partition, pending));
+        }
+
+        result
+    }
+
+    /// Registers the partitions that have finished building with `router`, which is only locked
+    /// if there are any, and then only to register them.
+    fn register_finished(&mut self, router: &RwLock<Router>) -> Result<(), ConstructionError> {
+        let mut result = Ok(());
+        let (finished, building): (Vec<_>, Vec<_>) = std::mem::take(&mut self.building)
+            .into_iter()
+            .partition(|(_, pending)| pending.is_finished());
+        self.building = building;
+
+        let mut ready = vec![];
+        for (mut partition, pending) in finished {
+            match partition.finish_initialize(pending) {
+                Ok(()) => ready.push(partition),
+                Err(e) => result = Err(e),
+            }
+        }
+        if ready.is_empty() {
+            return result;
+        }
+
+        let mut router = router.write().unwrap();
+        for partition in ready {
+            let pq = partition.get_predicate().unwrap().clone();
+            info!(
+                "Registering partition of {} vectors for {pq}.",
+                partition.len()
+            );
+            if router.register(Box::new(partition)) {
+                self.registered.push(pq);
+            } else {
+                // The partition is too large for the router to ever keep, so is not built again.
+                self.considered.insert(pq.to_string(), None);
+            }
+        }
+
+        result
+    }
+
+    /// Notes which of the partitions that were registered with the router it has since evicted,
+    /// given the predicates of those that it still has.
+    fn record_evictions(&mut self, built: &[PredicateQuery]) {
+        let (kept, evicted): (Vec<_>, Vec<_>) = std::mem::take(&mut self.registered)
+            .into_iter()
+            .partition(|pq| built.contains(pq));
+        self.registered = kept;
+
+        let workload = self.workload.lock().unwrap();
+        for pq in evicted {
+            let frequency = workload.frequency(&pq);
+            debug!("The partition for {pq} was evicted after being queried {frequency} times.");
+            self.evicted.insert(pq.to_string(), frequency);
+        }
+    }
+
+    /// The number of vectors that `pq` matches, if a partition for it would be selective enough to
+    /// be worth building.
+    fn matching(&mut self, pq: &PredicateQuery) -> Option<usize> {
+        let base = self.base.as_ref();
+        let max_selectivity = self.options.max_selectivity;
+        *self.considered.entry(pq.to_string()).or_insert_with(|| {
+            let len = Bitmask::new(pq, base).ok()?.bitcount();
+            let selectivity = len as f64 / base.len() as f64;
+            if len == 0 || selectivity > max_selectivity {
+                debug!("Not building a partition for {pq}, with selectivity {selectivity}.");
+                return None;
+            }
+            Some(len)
+        })
+    }
+
+    fn estimate(&self, len: usize) -> usize {
+        estimated_partition_bytes(
+            len,
+            self.base.get_dimensionality(),
+            &self.options.index_options,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fvecs::{AttributeCsv, FlattenedVecs};
+    use crate::router::{AlwaysBasePolicy, EvictionPolicy};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_workload_tracker() {
+        let mut tracker = WorkloadTracker::default();
+        let a: PredicateQuery = "attr0 = 1".parse().unwrap();
+        let b: PredicateQuery = "attr0 IN (2, 3)".parse().unwrap();
+        let c: PredicateQuery = "attr1 < 5".parse().unwrap();
+        for pq in [&a, &b, &b, &c, &b, &a] {
+            tracker.record(pq);
+        }
+
+        assert_eq!(tracker.frequency(&b), 3);
+        assert_eq!(tracker.frequency(&"attr0 = 7".parse().unwrap()), 0);
+        assert_eq!(tracker.frequent(2), vec![(b, 3), (a, 2)]);
+    }
+
+    #[test]
+    fn test_auto_partitioner() {
+        let mut dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let dataset = Arc::new(dataset);
+        let dimensionality = dataset.get_dimensionality();
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.vectors().get(0).to_vec(),
+        };
+
+        let mut partitioner = AutoPartitioner::new(
+            dataset.clone(),
+            AutoPartitionOptions {
+                min_frequency: 2,
+                max_selectivity: 0.5,
+                ..Default::default()
+            },
+        );
+        let mut router = Router::new(dataset.clone(), vec![], Box::new(AlwaysBasePolicy));
+        router.track_workload(partitioner.workload());
+        let router = RwLock::new(router);
+
+        let frequent: PredicateQuery = "attr0 = 1".parse().unwrap();
+        let infrequent: PredicateQuery = "attr0 = 2".parse().unwrap();
+        let unselective: PredicateQuery = "attr0 > 0".parse().unwrap();
+        for pq in [
+            &frequent,
+            &infrequent,
+            &unselective,
+            &frequent,
+            &unselective,
+        ] {
+            router
+                .read()
+                .unwrap()
+                .search(&query_vector, &Some(pq.clone()), 1, 16)
+                .unwrap();
+            partitioner.poll(&router).unwrap();
+        }
+
+        let start = Instant::now();
+        while partitioner.is_building() {
+            assert!(start.elapsed() < Duration::from_secs(60));
+            std::thread::sleep(Duration::from_millis(10));
+            partitioner.poll(&router).unwrap();
+        }
+
+        let router = router.into_inner().unwrap();
+        assert_eq!(router.predicates().collect::<Vec<_>>(), vec![&frequent]);
+        assert!(router.opportunistic_memory_usage().total() > 0);
+        assert_eq!(partitioner.building_bytes(), 0);
+    }
+
+    #[test]
+    fn test_auto_partitioner_under_router_budget() {
+        let mut dataset =
+            FvecsDataset::new("data/sift_query".to_string(), AttributeCsv::NoHeader).unwrap();
+        dataset.initialize(&OakIndexOptions::default()).unwrap();
+        let dataset = Arc::new(dataset);
+        let dimensionality = dataset.get_dimensionality();
+        let query_vector = FlattenedVecs {
+            dimensionality,
+            data: dataset.vectors().get(0).to_vec(),
+        };
+        let predicates: Vec<PredicateQuery> = ["attr0 = 1", "attr0 = 2"]
+            .iter()
+            .map(|pq| pq.parse().unwrap())
+            .collect();
+
+        // The router only has room for one of the two partitions at a time.
+        let sizes: Vec<usize> = predicates
+            .iter()
+            .map(|pq| {
+                let mut partition = dataset.view(pq).unwrap();
+                partition.initialize(&OakIndexOptions::default()).unwrap();
+                partition.memory_usage().total()
+            })
+            .collect();
+        let budget = *sizes.iter().max().unwrap();
+        assert!(budget < sizes.iter().sum());
+
+        let mut partitioner = AutoPartitioner::new(
+            dataset.clone(),
+            AutoPartitionOptions {
+                min_frequency: 2,
+                max_selectivity: 0.5,
+                ..Default::default()
+            },
+        );
+        let mut router = Router::new(dataset.clone(), vec![], Box::new(AlwaysBasePolicy));
+        router.set_memory_budget(budget, EvictionPolicy::LeastRecentlyUsed);
+        router.track_workload(partitioner.workload());
+        let router = RwLock::new(router);
+
+        let search = |pq: &PredicateQuery| {
+            router
+                .read()
+                .unwrap()
+                .search(&query_vector, &Some(pq.clone()), 1, 16)
+                .unwrap();
+        };
+        let settle = |partitioner: &mut AutoPartitioner| {
+            let start = Instant::now();
+            partitioner.poll(&router).unwrap();
+            while partitioner.is_building() {
+                assert!(start.elapsed() < Duration::from_secs(60));
+                std::thread::sleep(Duration::from_millis(10));
+                partitioner.poll(&router).unwrap();
+            }
+        };
+
+        for pq in [
+            &predicates[0],
+            &predicates[1],
+            &predicates[0],
+            &predicates[1],
+        ] {
+            search(pq);
+        }
+        settle(&mut partitioner);
+
+        // Whichever partition was evicted is not built again until it is queried more.
+        let built: Vec<PredicateQuery> = router.read().unwrap().predicates().cloned().collect();
+        assert_eq!(built.len(), 1);
+        for _ in 0..3 {
+            partitioner.poll(&router).unwrap();
+            assert!(!partitioner.is_building());
+        }
+        let evicted = predicates.iter().find(|pq| !built.contains(pq)).unwrap();
+        search(evicted);
+        partitioner.poll(&router).unwrap();
+        assert!(!partitioner.is_building());
+
+        search(evicted);
+        settle(&mut partitioner);
+        let router = router.into_inner().unwrap();
+        assert_eq!(router.predicates().collect::<Vec<_>>(), vec![evicted]);
+        assert!(router.opportunistic_memory_usage().total() <= budget);
+    }
+}
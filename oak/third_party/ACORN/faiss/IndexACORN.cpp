@@ -17,6 +17,7 @@
 #include <cstdlib>
 #include <cstring>
 
+#include <mutex>
 #include <queue>
 #include <unordered_set>
 
@@ -27,6 +28,7 @@
 #include "oak/third_party/ACORN/faiss/Index2Layer.h"
 #include "oak/third_party/ACORN/faiss/IndexFlat.h"
 #include "oak/third_party/ACORN/faiss/IndexIVFPQ.h"
+#include "oak/third_party/ACORN/faiss/index_io.h"
 #include "oak/third_party/ACORN/faiss/impl/AuxIndexStructures.h"
 #include "oak/third_party/ACORN/faiss/impl/FaissAssert.h"
 #include "oak/third_party/ACORN/faiss/utils/Heap.h"
@@ -70,6 +72,8 @@ using storage_idx_t = ACORN::storage_idx_t;
 using NodeDistFarther = ACORN::NodeDistFarther;
 
 ACORNStats acorn_stats;
+// OAK: searches may run concurrently, so updates to the global statistics are serialised.
+static std::mutex acorn_stats_mutex;
 
 /**************************************************************
  * add / search blocks of descriptors
@@ -368,6 +372,7 @@ void IndexACORN::search(
         }
     }
 
+    std::lock_guard<std::mutex> lock(acorn_stats_mutex);
     acorn_stats.combine({n1, n2, n3, ndis, nreorder, candidates_loop, neighbors_loop, tuple_unwrap, skips, visits}); //added for profiling
 }
 
@@ -434,6 +439,7 @@ void IndexACORN::search(
         }
     }
 
+    std::lock_guard<std::mutex> lock(acorn_stats_mutex);
     acorn_stats.combine({n1, n2, n3, ndis, nreorder});
 }
 
@@ -495,6 +501,9 @@ std::unique_ptr<IndexACORNFlat> new_index_acorn(
   int M_beta,
   const rust::Vec<int>& metadata
 ) {
+  FAISS_THROW_IF_NOT_MSG(d > 0, "dimensionality must be positive");
+  FAISS_THROW_IF_NOT_MSG(M > 0 && gamma > 0 && M_beta > 0, "M, gamma and M_beta must be positive");
+
   // Copy the elements to a C++ std::vector using STL algorithm.
   std::vector<int> metadata_cpp;
   std::copy(metadata.begin(), metadata.end(), std::back_inserter(metadata_cpp));
@@ -503,6 +512,11 @@ std::unique_ptr<IndexACORNFlat> new_index_acorn(
     new faiss::IndexACORNFlat(d, M, gamma, metadata_cpp, M_beta, METRIC_L2)
   );
 
+  // ACORN points at the metadata it was constructed with, so the index takes ownership of it
+  // rather than leaving ACORN pointing at this function's copy once it returns.
+  base_index->owned_metadata = std::move(metadata_cpp);
+  base_index->acorn.metadata = base_index->owned_metadata.data();
+
   base_index.get()->acorn.efSearch = 16; 
   return base_index;
 }
@@ -513,6 +527,13 @@ void add_to_index(
   idx_t n, 
   const float* x
 ) {
+  FAISS_THROW_IF_NOT(x != nullptr);
+  FAISS_THROW_IF_NOT(n >= 0);
+  // ACORN reads the metadata of each vector it adds, so it would read past the end otherwise.
+  FAISS_THROW_IF_NOT_FMT(
+    idx->ntotal + n <= (idx_t) idx->owned_metadata.size(),
+    "cannot add %" PRId64 " vectors to an index with %" PRId64 " vectors and metadata for %zu",
+    n, idx->ntotal, idx->owned_metadata.size());
   idx->add(n, x);
 }
 
@@ -524,8 +545,8 @@ void search_index(
   idx_t k,            // number of vectors to return for each query vector
   float* distances,   // pointer to an array of (k*n) floats, each representing a distance of the result from the query vector 
   idx_t* labels,      // pointer to an array of (k*n) indices, each representing the ID of the query vector in idx 
-  char* filter_id_map,// a bitmap of the IDs in the filter, an array of (n * N) bools, where N is the total number of vectors in the index, and a '1' represents that the vector at that index passes the predicate for that query.
-  idx_t efsearch      // an integer that can be tweaked to affect ACORN's recall/performance tradeoff dynamically
+  const char* filter_id_map,
+  idx_t efsearch
 ) {
   FAISS_THROW_IF_NOT(x != nullptr);
   FAISS_THROW_IF_NOT(distances != nullptr);
@@ -533,9 +554,55 @@ void search_index(
   FAISS_THROW_IF_NOT(filter_id_map != nullptr);
   FAISS_THROW_IF_NOT(n > 0 && k > 0);
 
-  idx.get()->acorn.efSearch = efsearch;
+  // efSearch is passed with the search rather than set on the index, so that concurrent searches
+  // with different values do not race.
+  SearchParametersACORN params;
+  params.efSearch = efsearch;
+
+  // ACORN only reads the filter map, but its signatures predate const-correctness.
+  idx->search(n, x, k, distances, labels, const_cast<char*>(filter_id_map), &params);
+}
+
+// OAK: standalone function to write an index to disk from Rust over FFI.
+void write_index_to_file(
+  const std::unique_ptr<IndexACORNFlat>& idx,
+  rust::Str path
+) {
+  std::string fname(path);
+  write_index(idx.get(), fname.c_str());
+}
+
+// OAK: standalone function to read an index written by `write_index_to_file` from Rust over FFI.
+// NOTE: FAISS does not persist the metadata that ACORN is constructed with, so vectors must not be
+// added to an index that has been read back; it can only be searched.
+std::unique_ptr<IndexACORNFlat> read_index_from_file(rust::Str path) {
+  std::string fname(path);
+  Index* idx = read_index(fname.c_str());
+  IndexACORNFlat* acorn = dynamic_cast<IndexACORNFlat*>(idx);
+  if (acorn == nullptr) {
+    delete idx;
+    FAISS_THROW_FMT("%s does not contain an ACORN index", fname.c_str());
+  }
+  return std::unique_ptr<IndexACORNFlat>(acorn);
+}
+
+// OAK: standalone function to get the dimensionality of the vectors in an index from Rust over FFI.
+int index_dimensionality(const std::unique_ptr<IndexACORNFlat>& idx) {
+  return idx->d;
+}
+
+// OAK: standalone function to get the number of vectors in an index from Rust over FFI.
+idx_t index_count(const std::unique_ptr<IndexACORNFlat>& idx) {
+  return idx->ntotal;
+}
 
-  idx->search(n, x, k, distances, labels, filter_id_map);
+// OAK: standalone function to get the number of bytes used by the graph of an index from Rust over
+// FFI, which does not include the vectors themselves.
+size_t index_graph_bytes(const std::unique_ptr<IndexACORNFlat>& idx) {
+  const ACORN& acorn = idx->acorn;
+  return acorn.neighbors.capacity() * sizeof(ACORN::storage_idx_t) +
+         acorn.offsets.capacity() * sizeof(size_t) +
+         acorn.levels.capacity() * sizeof(int);
 }
 
 
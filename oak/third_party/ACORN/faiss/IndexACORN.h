@@ -131,6 +131,11 @@ struct IndexACORN : Index {
  */
 
 struct IndexACORNFlat : IndexACORN {
+    // OAK: ACORN only keeps a pointer to the metadata it is constructed with, so indexes built
+    // over FFI keep their own copy here for as long as they live. It is empty for indexes read
+    // from disk, which cannot be added to.
+    std::vector<int> owned_metadata;
+
     IndexACORNFlat();
     IndexACORNFlat(int d, int M, int gamma, std::vector<int>& metadata, int M_beta, MetricType metric = METRIC_L2);
 
@@ -145,14 +150,16 @@ std::unique_ptr<IndexACORNFlat> new_index_acorn(
   const rust::Vec<int>& metadata
 ); 
 
-// OAK: standalone function to add vectors to an index from Rust over FFI.
+// OAK: standalone function to add vectors to an index from Rust over FFI. Throws if the index has
+// no metadata for some of the vectors.
 void add_to_index(
   std::unique_ptr<IndexACORNFlat>& idx,
   idx_t n,        // number of vectors to add
   const float* x  // pointer to a contiguous array of the vectors to add
 );
 
-// OAK: standalone function to search vectors from an index from Rust over FFI.
+// OAK: standalone function to search vectors from an index from Rust over FFI. It does not modify
+// the index, so may be called from several threads at once.
 void search_index(
   const std::unique_ptr<IndexACORNFlat>& idx,
   idx_t n,            // number of query vectors
@@ -160,9 +167,30 @@ void search_index(
   idx_t k,            // number of vectors to return for each query vector
   float* distances,   // pointer to an array of (k*n) floats, each representing a distance of the result from the query vector 
   idx_t* labels,      // pointer to an array of (k*n) indices, each representing the ID of the query vector in idx 
-  char* filter_id_map,// a bitmap of the IDs in the filter, an array of (n * N) bools, where N is the total number of vectors in the index, and a '1' represents that the vector at that index passes the predicate for that query.
-  idx_t efsearch      // an integer that can be tweaked to affect ACORN's recall/performance tradeoff dynamically
+  const char* filter_id_map,// a bitmap of the IDs in the filter, an array of (n * N) bools, where N is the total number of vectors in the index, and a '1' represents that the vector at that index passes the predicate for that query. It is only read.
+  idx_t efsearch      // an integer that can be tweaked to affect ACORN's recall/performance tradeoff dynamically, which only applies to this search
 );
 
+// OAK: standalone function to write an index to disk from Rust over FFI.
+void write_index_to_file(
+  const std::unique_ptr<IndexACORNFlat>& idx,
+  rust::Str path      // the file to write the index to, which is overwritten if it exists
+);
+
+// OAK: standalone function to read an index written by `write_index_to_file` from Rust over FFI.
+std::unique_ptr<IndexACORNFlat> read_index_from_file(
+  rust::Str path      // the file to read the index from
+);
+
+// OAK: standalone function to get the dimensionality of the vectors in an index from Rust over FFI.
+int index_dimensionality(const std::unique_ptr<IndexACORNFlat>& idx);
+
+// OAK: standalone function to get the number of vectors in an index from Rust over FFI.
+idx_t index_count(const std::unique_ptr<IndexACORNFlat>& idx);
+
+// OAK: standalone function to get the number of bytes used by the graph of an index from Rust over
+// FFI, which does not include the vectors themselves.
+size_t index_graph_bytes(const std::unique_ptr<IndexACORNFlat>& idx);
+
 
 } // namespace faiss
@@ -1441,7 +1441,8 @@ ACORNStats ACORN::search(
         }
 
         
-        int ef = std::max(efSearch, k);
+        // OAK: honour a per-search efSearch, so that searches do not have to set it on the index.
+        int ef = std::max(params ? params->efSearch : efSearch, k);
         if (search_bounded_queue) { // this is the most common branch
             debug("%s\n", "reached search bounded queue");
 
@@ -1544,7 +1545,8 @@ ACORNStats ACORN::hybrid_search(
         }
         stats.n3 += ndis_upper;
 
-        int ef = std::max(efSearch, k);
+        // OAK: honour a per-search efSearch, so that searches do not have to set it on the index.
+        int ef = std::max(params ? params->efSearch : efSearch, k);
         if (search_bounded_queue) { // this is the most common branch
             debug("%s\n", "reached search bounded queue");
 
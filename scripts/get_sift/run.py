@@ -8,12 +8,8 @@ import shutil
 data_dir = "siftsmall"
 base_fname = "siftsmall_base"
 query_fname = "siftsmall_query"
-gt_name = "siftsmall_groundtruth"
 base_file = os.path.join(data_dir, f"{base_fname}.fvecs")
 query_file = os.path.join(data_dir, f"{query_fname}.fvecs")
-groundtruth_file = os.path.join(data_dir, f"{gt_name}.ivecs")
-
-single_predicate = 1
 
 # Parameters
 attribute_domain = range(1, 13)  # Domain of attribute values (1-12)
@@ -38,30 +34,6 @@ def assign_attributes_and_predicates(base_vectors, query_vectors):
     
     return base_attributes, query_predicates
 
-def generate_groundtruth(gt, base_predicates):
-    predicate_gt = []
-    no_such_index = [] #query vectors where a nearest neighbor wasn't found
-    for i in range(len(gt)):
-        # for each query vector, checking for the 
-        # nearest neighbor matching our predicate
-        nearest_neighbors = gt[i]
-        satisfying_nearest_neighbor = -1
-        # print("Query number", i)
-        for j in range(100):
-            pred_index = nearest_neighbors[j]
-            # print("NN number", j, base_predicates[pred_index])
-            if base_predicates[pred_index] == single_predicate:
-                
-                satisfying_nearest_neighbor = pred_index
-                break
-            
-        if satisfying_nearest_neighbor == -1:
-            no_such_index.append(i)
-        else:
-            predicate_gt.append(satisfying_nearest_neighbor)
-        # if no such found, raise error
-    return predicate_gt, no_such_index
-
 def main():
     # Read base and query vectors
     print("Reading base vectors...")
@@ -90,18 +62,7 @@ def main():
         for pred in query_predicates:
             f.write(f"{pred}\n")
 
-    # Generate new groundtruth
-    print("generating groundtruth...")
-    groundtruth = ivecs_read(groundtruth_file)
-    predicate_gt, no_NN = generate_groundtruth(groundtruth, base_attributes)
-    print("No index found for", len(no_NN), "queries")
-    print(no_NN[:10])
-
-    print("saving gt to outdir/"+ gt_name)
-    with open(f"outdir/{gt_name}.csv", "w") as f:
-        for pred in predicate_gt:
-            f.write(f"{pred}\n")
-
+    # The groundtruth for the predicates is computed exactly by the `groundtruth` binary in oak.
 
     # Write gitignore 
     # with open("outdir/.gitignore", "w") as f: